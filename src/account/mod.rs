@@ -1,25 +1,39 @@
 use curve25519_dalek::scalar::Scalar;
 use curve25519_dalek::ristretto::{RistrettoPoint, CompressedRistretto};
-use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
-use rayon::iter::{IntoParallelIterator, ParallelIterator, IntoParallelRefIterator};
+use curve25519_dalek::constants::{RISTRETTO_BASEPOINT_POINT, RISTRETTO_BASEPOINT_TABLE};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use std::hash::{Hash, Hasher};
+use std::collections::{HashMap, VecDeque};
 use sha3::{Digest, Sha3_512};
 use rand::{thread_rng, Rng};
 use serde::{Serialize, Deserialize};
+use zeroize::Zeroize;
+use merlin::Transcript;
 
 use crate::commitment::{Commitment};
 use crate::lpke::Ciphertext;
-use crate::constants::PEDERSEN_H;
+use crate::reserve::{ReserveProof, ReserveError};
+use crate::constants::PEDERSEN_H_TABLE;
+use crate::external::transcript::TranscriptProtocol;
 
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 #[cfg_attr(feature = "std", derive(Error))]
 pub enum AccountError{
     NotOurAccount,
-    NotPrivateAccount
+    NotPrivateAccount,
+    MemoTooLong
 }
 
-#[derive(Debug, Copy, Clone, Eq, Serialize, Deserialize)]
+/// the most bytes a memo can hold. every output has room for one so the ones with memos look like the rest
+pub const MEMO_SIZE: usize = 256;
+/// the size of the view key's ciphertext of an output made before outputs had room for memos (the amount and randomness and the aead tag)
+const PLAIN_ECK_SIZE: usize = 64 + 16;
+/// the name of a subaddress starts with this (the keys themselves are written with a to p)
+pub const SUBADDRESS_MARK: char = 's';
+
+/// an account's keys. the secret keys are wiped when it's dropped so it isn't Copy (clone it to keep a copy)
+#[derive(Debug, Clone, Eq, Serialize, Deserialize)]
 pub struct Account{
     pub sk: Scalar, // spend
     pub pk: RistrettoPoint,
@@ -27,6 +41,9 @@ pub struct Account{
     pub apk: RistrettoPoint,
     pub vsk: Scalar, // view
     pub vpk: RistrettoPoint,
+    /// a subaddress has its tracking and view keys over pk instead of the basepoint. it's part of the name, not the saved keys
+    #[serde(skip)]
+    pub subaddress: bool,
 }
 
 impl PartialEq for Account{
@@ -35,6 +52,21 @@ impl PartialEq for Account{
     }
 }
 
+/// wipes the secret keys (the public keys stay)
+impl Zeroize for Account{
+    fn zeroize(&mut self) {
+        self.sk.zeroize();
+        self.ask.zeroize();
+        self.vsk.zeroize();
+    }
+}
+
+impl Drop for Account{
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
 pub type Tag = CompressedRistretto;
 
 #[derive(Debug, Clone, Eq, Serialize, Deserialize)]
@@ -48,6 +80,13 @@ pub struct OTAccount{
     pub s: Option<Scalar>,
     pub sk: Option<Scalar>,
     pub tag: Option<Tag>,
+    /// the decrypted memo. it isn't saved because eck holds it
+    #[serde(skip)]
+    pub memo: Option<Vec<u8>>,
+    /// one byte of the tracking key's shared secret so scanning skips most outputs that aren't ours (older outputs
+    /// don't have one). blocks and transactions serialize it with their outputs (see with_view_tags)
+    #[serde(skip)]
+    pub view_tag: Option<u8>,
 }
 
 impl PartialEq for OTAccount{
@@ -56,21 +95,24 @@ impl PartialEq for OTAccount{
     }
 }
 
+/// wipes the one time key and what it's made from
+impl Zeroize for OTAccount{
+    fn zeroize(&mut self) {
+        self.sk.zeroize();
+        self.s.zeroize();
+        self.ek.zeroize();
+    }
+}
+
+impl Drop for OTAccount{
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
 impl Default for OTAccount{
     fn default() -> Self {
-        let mut csrng = thread_rng();
-
-        OTAccount {
-            pk: RistrettoPoint::random(&mut csrng),
-            com: Commitment::default(),
-            account: None,
-            eek: None,
-            eck: None,
-            ek: None,
-            s: None,
-            sk: None,
-            tag: None,
-        }
+        OTAccount::new(RistrettoPoint::random(&mut thread_rng()), Commitment::default())
     }
 }
 
@@ -83,27 +125,157 @@ pub fn stakereader_acc() -> Account {
         apk: RISTRETTO_BASEPOINT_POINT,
         vsk: Scalar::one(),
         vpk: RISTRETTO_BASEPOINT_POINT,
+        subaddress: false,
     }
 }
 /// makes the fee output account
 pub fn fee_ota(amount: &Scalar) -> OTAccount {
     let com = Commitment::commit(amount, &Scalar::from(0u8));
 
-    OTAccount{
-        pk: RISTRETTO_BASEPOINT_POINT,
-        com,
-        ..Default::default()
+    OTAccount::new(RISTRETTO_BASEPOINT_POINT, com)
+}
+
+/// the secret that makes the one time account of pk from the one time key ek
+pub(crate) fn one_time_s(pk: &CompressedRistretto, ek: &[u8]) -> Scalar {
+    let mut hasher = Sha3_512::new();
+    hasher.update(pk.as_bytes());
+    hasher.update(ek);
+    Scalar::from_hash(hasher)
+}
+
+/// the randomness the tracking key ciphertext of an output with one time key ek is made with. it's derived from ek so
+/// the sender can prove what the ciphertext holds with only ek (see PaymentProof)
+pub(crate) fn eek_randomness(ek: &[u8]) -> Scalar {
+    let mut hasher = Sha3_512::new();
+    hasher.update(b"eek randomness");
+    hasher.update(ek);
+    Scalar::from_hash(hasher)
+}
+
+/// the spend key of the (sub)address an output was sent to. one time keys are 32 random bytes then that key (older ones are just
+/// the random bytes)
+fn recipient_of(ek: &[u8]) -> Option<CompressedRistretto> {
+    ek.get(32..64).map(CompressedRistretto::from_slice)
+}
+
+/// writes 32 bytes as 64 letters from a to p (the low half of every byte then the high half)
+fn to_letters(key: &[u8; 32]) -> Vec<u8> {
+    key.iter().map(|x| (x%16) + 97).chain(key.iter().map(|x| (x/16) + 97)).collect()
+}
+
+/// reads the 32 bytes written with to_letters
+fn from_letters(letters: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    key.iter_mut().zip(letters[..32].iter().zip(&letters[32..])).for_each(|(x, (l, h))| *x = (l - 97) + (h - 97)*16);
+    key
+}
+
+/// the one time key, the commitment and the memo of an output the tracking and view keys opened
+type Opened = (Vec<u8>, Commitment, Option<Vec<u8>>);
+
+/// reads the amount, randomness and memo (if there is one) the view key decrypted. an empty memo is no memo
+fn read_ck(ck: &[u8]) -> Result<(Scalar, Scalar, Option<Vec<u8>>), AccountError> {
+    let (amount, randomness): (Scalar, Scalar) = bincode::deserialize(&ck[..ck.len().min(64)]).map_err(|_| AccountError::NotOurAccount)?;
+    let memo = match ck.len() {
+        64 => None,
+        l if l == 64 + 2 + MEMO_SIZE => {
+            let len = u16::from_le_bytes([ck[64], ck[65]]) as usize;
+            if len > MEMO_SIZE {
+                return Err(AccountError::NotOurAccount)
+            }
+            Some(ck[66..66 + len].to_vec()).filter(|x| !x.is_empty())
+        }
+        _ => return Err(AccountError::NotOurAccount),
+    };
+    Ok((amount, randomness, memo))
+}
+
+/// the keys of an account's first subaddresses worked out once so outputs to any of them can be recognized
+#[derive(Debug, Clone)]
+pub struct Subaddresses {
+    account: Account,
+    table: HashMap<CompressedRistretto, u32>,
+}
+
+impl Subaddresses {
+    /// the account these are subaddresses of
+    pub fn account(&self) -> &Account {
+        &self.account
+    }
+
+    /// the number of subaddresses scanned for
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    /// tells you if no subaddresses are scanned for
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    /// decripts an OTA sent to any of the subaddresses and tells you which one received it.
+    /// the account's tracking and view keys open outputs to every subaddress and the one time key says which it went to
+    pub fn receive_ot(&self, acc: &OTAccount) -> Result<(u32, OTAccount), AccountError> {
+        let (ek, com, memo) = self.account.open_ot(acc)?;
+        let pk = recipient_of(&ek).unwrap_or_else(|| self.account.pk.compress());
+        let index = *self.table.get(&pk).ok_or(AccountError::NotOurAccount)?;
+        let sub = self.account.subaddress(index);
+        let s = one_time_s(&pk, &ek);
+        if sub.pk + Account::tag_k_gen(s) != acc.pk {
+            return Err(AccountError::NotOurAccount)
+        }
+        Ok((index, sub.claim_ot(acc, ek, com, memo, s)))
+    }
+}
+
+/// the accounts a restore follows through panics: the ones it already moved on from and the ones it's still looking for
+#[derive(Debug, Clone, Default)]
+pub struct Generations {
+    passed: Vec<Account>,
+    later: VecDeque<Account>,
+}
+
+impl Generations {
+    /// looks for the count generations after generation
+    pub fn after<T: std::convert::AsRef<[u8]>>(seed: &T, generation: u32, count: u32) -> Generations {
+        Generations {
+            passed: vec![],
+            later: (generation + 1..=generation + count).map(|g| Account::from_seed_generation(seed, g)).collect(),
+        }
+    }
+
+    /// the generation a restore moves to next if it gets money
+    pub fn next(&self) -> Option<&Account> {
+        self.later.front()
+    }
+
+    /// moves on from me to the next generation
+    pub fn advance(&mut self, me: &Account) -> Option<Account> {
+        let next = self.later.pop_front()?;
+        self.passed.push(me.clone());
+        Some(next)
+    }
+
+    /// goes back from me to the earlier generation to (when the blocks that moved the restore on are undone)
+    pub fn rewind(&mut self, me: &Account, to: &Account) {
+        let mut me = me.clone();
+        while &me != to {
+            match self.passed.pop() {
+                Some(prev) => self.later.push_front(std::mem::replace(&mut me, prev)),
+                None => return,
+            }
+        }
     }
 }
 
 impl Account {
 
     pub fn tag_k_gen(x: Scalar) -> RistrettoPoint {
-        x*PEDERSEN_H()
+        &x*PEDERSEN_H_TABLE()
     }
 
     fn tag_eval(x: Scalar) -> RistrettoPoint {
-        x.invert() * RISTRETTO_BASEPOINT_POINT
+        &x.invert() * &RISTRETTO_BASEPOINT_TABLE
     }
 
     /// makes an account from a password that can be expressed as a lot of data types
@@ -122,16 +294,36 @@ impl Account {
             apk: ask*RISTRETTO_BASEPOINT_POINT,
             vsk, // appendix B in omniring has info on ask (tsk in the paper) and vsk
             vpk: vsk*RISTRETTO_BASEPOINT_POINT,
+            subaddress: false,
         }
     }
 
-    /// name of an account used to copy and paste in the gui
+    /// name of an account used to copy and paste in the gui (subaddresses start with SUBADDRESS_MARK)
     pub fn name(&self) -> String {
-        std::str::from_utf8(&[self.pk,self.apk,self.vpk].into_par_iter().map(|key| {
-            let key = key.compress();
-            key.as_bytes().par_iter().map(|x| (x%16) + 97)
-            .chain(key.as_bytes().par_iter().map(|x| (x/16) + 97).collect::<Vec<u8>>()).collect::<Vec<u8>>()
-        }).flatten().collect::<Vec<u8>>()).unwrap().to_string()
+        let keys = String::from_utf8([self.pk,self.apk,self.vpk].iter().flat_map(|key| to_letters(key.compress().as_bytes())).collect()).unwrap();
+        if self.subaddress {
+            format!("{}{}", SUBADDRESS_MARK, keys)
+        } else {
+            keys
+        }
+    }
+
+    /// the account to send to with the name (None if it isn't one)
+    pub fn from_name(name: &str) -> Option<Account> {
+        let (subaddress, keys) = match name.strip_prefix(SUBADDRESS_MARK) {
+            Some(keys) => (true, keys.as_bytes()),
+            None => (false, name.as_bytes()),
+        };
+        if keys.len() != 192 || keys.iter().any(|x| !(97..113).contains(x)) {
+            return None
+        }
+        let pks = keys.chunks(64).map(|key| CompressedRistretto(from_letters(key))).collect::<Vec<_>>();
+        if pks.iter().any(|x| x.decompress().is_none()) {
+            return None
+        }
+        let mut account = Account::from_pks(&pks[0], &pks[1], &pks[2]);
+        account.subaddress = subaddress;
+        Some(account)
     }
 
     /// makes a fake account with specified pks to send transactions to
@@ -143,42 +335,125 @@ impl Account {
             apk: apk.decompress().unwrap(),
             vsk: Scalar::zero(),
             vpk: vpk.decompress().unwrap(),
-        }        
+            subaddress: false,
+        }
+    }
+
+    /// makes a watch-only account from the public key and the tracking and view keys. it finds and reads the account's
+    /// outputs but can't spend them or work out their tags
+    pub fn watch_only(pk: &CompressedRistretto, ask: &Scalar, vsk: &Scalar) -> Self {
+        Account{
+            sk: Scalar::zero(),
+            pk: pk.decompress().unwrap(),
+            ask: *ask,
+            apk: ask*RISTRETTO_BASEPOINT_POINT,
+            vsk: *vsk,
+            vpk: vsk*RISTRETTO_BASEPOINT_POINT,
+            subaddress: false,
+        }
+    }
+
+    /// the public key and the tracking and view keys written like name. anyone with it can make the watch-only account
+    pub fn watch_key(&self) -> String {
+        String::from_utf8([self.pk.compress().to_bytes(), self.ask.to_bytes(), self.vsk.to_bytes()].iter().flat_map(to_letters).collect()).unwrap()
+    }
+
+    /// the watch-only account with the watch key (None if it isn't one)
+    pub fn from_watch_key(key: &str) -> Option<Account> {
+        let key = key.as_bytes();
+        if key.len() != 192 || key.iter().any(|x| !(97..113).contains(x)) {
+            return None
+        }
+        let pk = CompressedRistretto(from_letters(&key[..64]));
+        let ask = Scalar::from_canonical_bytes(from_letters(&key[64..128]))?;
+        let vsk = Scalar::from_canonical_bytes(from_letters(&key[128..]))?;
+        if pk.decompress().is_none() || ask == Scalar::zero() {
+            return None
+        }
+        Some(Account::watch_only(&pk, &ask, &vsk))
+    }
+
+    /// tells you if the account has the tracking and view keys but not the spend key
+    pub fn is_watch_only(&self) -> bool {
+        self.sk == Scalar::zero() && self.ask != Scalar::zero()
+    }
+
+    /// proves the outputs you received (with their locations) are unspent and hold at least claimed between them.
+    /// the proof reveals the outputs' tags so anyone holding it sees when they're spent
+    pub fn prove_reserve_revealing_tags(&self, outputs: &[(u64, OTAccount)], claimed: u64) -> Result<ReserveProof, ReserveError> {
+        if self.is_watch_only() {
+            return Err(ReserveError::WatchOnly)
+        }
+        ReserveProof::prove(outputs, claimed)
     }
 
     /// derives a one time account from an account and amount
     pub fn derive_ot(&self, amount: &Scalar) -> OTAccount{
+        self.derive_memo_ot(amount, None)
+    }
+
+    /// derives a one time account with a memo only the recipient (or whoever has their view key) can read
+    pub fn derive_ot_with_memo(&self, amount: &Scalar, memo: &[u8]) -> Result<OTAccount, AccountError> {
+        if memo.len() > MEMO_SIZE {
+            return Err(AccountError::MemoTooLong)
+        }
+        Ok(self.derive_memo_ot(amount, Some(memo)))
+    }
+
+    /// the memo is padded and encrypted to the view key after the amount and randomness. outputs without one get an
+    /// empty memo padded the same way
+    fn derive_memo_ot(&self, amount: &Scalar, memo: Option<&[u8]>) -> OTAccount{
         let mut csprng = thread_rng();
         let randomness = Scalar::random(&mut csprng);
         let com = Commitment::commit(amount, &randomness);
         let contains = ( *amount, randomness);
-        let serialized = bincode::serialize(&contains).unwrap();
-        let ek = thread_rng().gen::<[u8; 32]>();
-        // println!("{:?}",ek);
-        let mut hasher = Sha3_512::new();
-        hasher.update(&self.pk.compress().as_bytes());
-        hasher.update(&ek);
-        let s = Scalar::from_hash(hasher);
+        let mut serialized = bincode::serialize(&contains).unwrap();
+        let memo_bytes = memo.unwrap_or_default();
+        serialized.extend((memo_bytes.len() as u16).to_le_bytes());
+        serialized.extend(memo_bytes);
+        serialized.resize(64 + 2 + MEMO_SIZE, 0);
+        let mut ek = thread_rng().gen::<[u8; 32]>().to_vec();
+        ek.extend(self.pk.compress().as_bytes()); // see recipient_of
+        let s = one_time_s(&self.pk.compress(), &ek);
         let pk = self.pk + Account::tag_k_gen(s);
 
         let mut label = pk.compress().as_bytes().to_vec();
         label.extend( com.com.compress().as_bytes().to_vec());
-        let eek = Ciphertext::encrypt(&self.apk, &label, &ek.to_vec());
-        let eck = Ciphertext::encrypt(&self.vpk, &label, &serialized);
+        let base = self.encryption_base();
+        let (eek, view_tag) = Ciphertext::encrypt_with_view_tag_from(&eek_randomness(&ek), &base, &self.apk, &label, &ek);
+        let eck = Ciphertext::encrypt_over(&base, &self.vpk, &label, &serialized);
 
         OTAccount{
             pk,
             com,
-            account: Some(*self),
-            ek: Some(ek.to_vec()),
+            account: Some(self.clone()),
+            ek: Some(ek),
             eek: Some(eek),
             eck: Some(eck),
-            ..Default::default()
+            s: None,
+            sk: None,
+            tag: None,
+            memo: memo.map(|x| x.to_vec()),
+            view_tag: Some(view_tag),
         }
     }
 
     /// decripts an OTA to fill in all unknowns
     pub fn receive_ot(&self, acc: &OTAccount) -> Result<OTAccount, AccountError> {
+        let (ek, com, memo) = self.open_ot(acc)?;
+        let s = one_time_s(&self.pk.compress(), &ek);
+
+        if self.pk + Account::tag_k_gen(s) != acc.pk {
+            return Err(AccountError::NotOurAccount)
+        }
+        Ok(self.claim_ot(acc, ek, com, memo, s))
+    }
+
+    /// decrypts the one time key, the commitment's opening and the memo with the tracking and view keys
+    fn open_ot(&self, acc: &OTAccount) -> Result<Opened, AccountError> {
+        if acc.view_tag.is_some_and(|tag| !acc.eek.as_ref().unwrap().may_be_for(&self.ask, tag)) {
+            return Err(AccountError::NotOurAccount)
+        }
         let mut label = acc.pk.compress().as_bytes().to_vec();
         label.extend( acc.com.com.compress().as_bytes().to_vec());
         let ek = match acc.eek.as_ref().unwrap().decrypt(&self.ask, &label) {
@@ -189,33 +464,97 @@ impl Account {
             Ok(ck) => ck,  // viewing secret key (can also view amount)
             Err(_) => return Err(AccountError::NotOurAccount)
         };
-        let (amount, randomness): (Scalar, Scalar) = bincode::deserialize(&ck).unwrap();
-
-        let mut hasher = Sha3_512::new();
-        hasher.update(&self.pk.compress().as_bytes());
-        hasher.update(&ek);
-        let s = Scalar::from_hash(hasher);
-        let sk = self.sk + s;
+        let (amount, randomness, memo) = read_ck(&ck)?;
 
-        if Account::tag_k_gen(sk) != acc.pk {
-            return Err(AccountError::NotOurAccount)
-        }
         let trcom = Commitment::commit(&amount, &randomness);
         if trcom != acc.com {
             return Err(AccountError::NotOurAccount)
         }
-        
-        Ok(OTAccount{
+        Ok((ek, trcom, memo))
+    }
+
+    /// fills in the OTA once we know it's ours (without the key and tag if we can't spend it)
+    fn claim_ot(&self, acc: &OTAccount, ek: Vec<u8>, com: Commitment, memo: Option<Vec<u8>>, s: Scalar) -> OTAccount {
+        let sk = Some(self.sk + s).filter(|_| !self.is_watch_only());
+        OTAccount{
             pk: acc.pk,
-            com: trcom,
-            account: Some(*self),
+            com,
+            account: Some(self.clone()),
             ek: Some(ek),
             eek: acc.eek.clone(),
             eck: acc.eck.clone(),
             s: Some(s),
-            sk: Some(sk),
-            tag: Some(Account::tag_eval(sk).compress()),
-        })
+            sk,
+            tag: sk.map(|sk| Account::tag_eval(sk).compress()),
+            memo,
+            view_tag: acc.view_tag,
+        }
+    }
+
+    /// the spend key offset of subaddress index (0 for the account itself). it's derived from the view key so anyone who can view the account can find its subaddresses
+    fn subaddress_offset(&self, index: u32) -> Scalar {
+        if index == 0 {
+            return Scalar::zero()
+        }
+        let mut hasher = Sha3_512::new();
+        hasher.update(b"subaddress");
+        hasher.update(self.vsk.as_bytes());
+        hasher.update(&index.to_le_bytes());
+        Scalar::from_hash(hasher)
+    }
+
+    /// subaddress index of this account (index 0 is the account itself). send to it like any other account with derive_ot.
+    /// its tracking and view keys are the account's secret keys times its own spend key so subaddresses can't be linked
+    /// to each other or to the account, and outputs to it are encrypted over its spend key so the account's keys open them
+    pub fn subaddress(&self, index: u32) -> Account {
+        if index == 0 {
+            return self.clone()
+        }
+        let m = self.subaddress_offset(index);
+        let pk = self.pk + Account::tag_k_gen(m);
+        Account{
+            sk: if self.sk == Scalar::zero() {Scalar::zero()} else {self.sk + m},
+            pk,
+            ask: self.ask,
+            apk: self.ask*pk,
+            vsk: self.vsk,
+            vpk: self.vsk*pk,
+            subaddress: true,
+        }
+    }
+
+    /// the first n subaddresses of this account (including the account itself) to scan for
+    pub fn subaddresses(&self, n: u32) -> Subaddresses {
+        let table = (0..n).into_par_iter().map(|i| {
+            ((self.pk + Account::tag_k_gen(self.subaddress_offset(i))).compress(), i)
+        }).collect();
+        Subaddresses{account: self.clone(), table}
+    }
+
+    /// what the tracking and view keys are multiples of (and so what outputs to the account are encrypted over)
+    pub(crate) fn encryption_base(&self) -> RistrettoPoint {
+        if self.subaddress {
+            self.pk
+        } else {
+            RISTRETTO_BASEPOINT_POINT
+        }
+    }
+
+    /// the account generation panics after the one made from seed (with its staking account). every generation comes
+    /// from the seed itself and not the account before it, so whoever stole an account's keys (the reason to panic)
+    /// can't work out the account the money moves to
+    pub fn from_seed_generation<T: std::convert::AsRef<[u8]>>(seed: &T, generation: u32) -> Account {
+        if generation == 0 {
+            return Account::new(seed)
+        }
+        let mut hasher = Sha3_512::new();
+        hasher.update(seed);
+        hasher.update(b"generation");
+        hasher.update(generation.to_le_bytes());
+        let mut x = hasher.finalize().to_vec();
+        let acc = Account::new(&x);
+        x.zeroize();
+        acc
     }
 
     /// reads the coin key and unwraps the comittment. This Account you run this on should have the vsk but may not have the sk
@@ -227,7 +566,7 @@ impl Account {
             Err(_) => return Err(AccountError::NotOurAccount)
         };
         
-        let (amount, randomness): (Scalar, Scalar) = bincode::deserialize(&ck).unwrap();
+        let (amount, randomness, memo) = read_ck(&ck)?;
         let trcom = Commitment::commit(&amount, &randomness);
 
         Ok(OTAccount{
@@ -240,6 +579,8 @@ impl Account {
             s: acc.s.to_owned(),
             sk: acc.sk.to_owned(),
             tag: acc.tag.to_owned(),
+            memo,
+            view_tag: acc.view_tag,
         })
     }
 
@@ -259,17 +600,21 @@ impl Account {
 
         let mut label = pk.compress().as_bytes().to_vec();
         label.extend( com.com.compress().as_bytes().to_vec());
-        let eek = Ciphertext::encrypt(&self.apk, &label, &ek.to_vec());
+        let (eek, view_tag) = Ciphertext::encrypt_with_view_tag(&self.apk, &label, &ek.to_vec());
         let eck = Ciphertext::encrypt(&self.vpk, &label, &serialized);
 
         OTAccount{
             pk,
             com,
-            account: Some(*self),
+            account: Some(self.clone()),
             ek: Some(ek.to_vec()),
             eek: Some(eek),
             eck: Some(eck),
-            ..Default::default()
+            s: None,
+            sk: None,
+            tag: None,
+            memo: None,
+            view_tag: Some(view_tag),
         }
     }
 
@@ -282,6 +627,7 @@ impl Account {
             apk: RISTRETTO_BASEPOINT_POINT,
             vsk: Scalar::one(), // appendix B has info on ask (tsk in the paper) and vsk
             vpk: RISTRETTO_BASEPOINT_POINT,
+            subaddress: false,
         }
     }
 
@@ -290,6 +636,22 @@ impl Account {
 
 
 impl OTAccount {
+    /// a one time account we know nothing about but its key and commitment
+    pub fn new(pk: RistrettoPoint, com: Commitment) -> OTAccount {
+        OTAccount {
+            pk,
+            com,
+            account: None,
+            eek: None,
+            eck: None,
+            ek: None,
+            s: None,
+            sk: None,
+            tag: None,
+            memo: None,
+            view_tag: None,
+        }
+    }
 
     pub fn get_s(&self) -> Result<Scalar, AccountError> {
         match &self.ek {
@@ -305,16 +667,16 @@ impl OTAccount {
     }
 
     pub fn summon_ota(x: &[CompressedRistretto;2]) -> OTAccount {
-        OTAccount{
-            pk: x[0].decompress().unwrap(),
-            com: Commitment{com: x[1].decompress().unwrap(),..Default::default()},
-            eek: Some(Ciphertext::default()),
-            eck: Some(Ciphertext::default()),
-            ..Default::default()
-        }
+        let mut ota = OTAccount::new(x[0].decompress().unwrap(), Commitment{com: x[1].decompress().unwrap(),..Default::default()});
+        ota.eek = Some(Ciphertext::default());
+        ota.eck = Some(Ciphertext::default());
+        ota
     }
 
     pub fn track_ot(&self, ask: &Scalar) -> bool { // just says weather the person who sent me their tracking key owns the account
+        if self.view_tag.is_some_and(|tag| !self.eek.as_ref().unwrap().may_be_for(ask, tag)) {
+            return false
+        }
         let mut label = self.pk.compress().as_bytes().to_vec();
         label.extend( self.com.com.compress().as_bytes().to_vec());
         match self.eek.as_ref().unwrap().decrypt(ask, &label) {
@@ -326,6 +688,7 @@ impl OTAccount {
     pub fn get_sk(&self) -> Result<Scalar, AccountError> {
         match self.sk {
             Some(sk) => Ok(sk),
+            None if self.account.as_ref().is_none_or(|x| x.is_watch_only()) => Err(AccountError::NotPrivateAccount),
             None => match self.s {
                 Some(s) => Ok(self.account.as_ref().unwrap().sk + s),
                 None => match self.get_s() {
@@ -348,18 +711,37 @@ impl OTAccount {
             }
         }
     }
+    /// tells you if the output's view key ciphertext has room for a memo. every output made now does, so this doesn't
+    /// say which of them carry one
+    pub fn has_memo_room(&self) -> bool {
+        self.eck.as_ref().is_some_and(|x| x.e.len() > PLAIN_ECK_SIZE)
+    }
+
     pub fn get_pk(&self) -> RistrettoPoint {
         self.pk
     }
 
     pub fn publish_offer(&self) -> OTAccount {
-        OTAccount{
-            pk: self.pk,
-            com: self.com.publish(),
-            eek: self.eek.clone(),
-            eck: self.eck.clone(),
-            ..Default::default()
-        }
+        let mut ota = OTAccount::new(self.pk, self.com.publish());
+        ota.eek = self.eek.clone();
+        ota.eck = self.eck.clone();
+        ota.view_tag = self.view_tag;
+        ota
+    }
+}
+
+/// serializes outputs with their view tags. use it with #[serde(with = "crate::account::with_view_tags")]
+pub mod with_view_tags {
+    use super::OTAccount;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(outputs: &[OTAccount], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(outputs.iter().map(|x| (x, x.view_tag)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<OTAccount>, D::Error> {
+        let tagged = Vec::<(OTAccount, Option<u8>)>::deserialize(deserializer)?;
+        Ok(tagged.into_iter().map(|(mut x, view_tag)| {x.view_tag = view_tag; x}).collect())
     }
 }
 
@@ -369,6 +751,32 @@ impl Hash for OTAccount {
     }
 }
 
+/// knowledge of the key sk of an output with pk = sk*H and B = sk*tag
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TagProof {
+    pub r1: RistrettoPoint,
+    pub r2: RistrettoPoint,
+    pub z: Scalar,
+}
+
+impl TagProof {
+    pub(crate) fn prove(transcript: &mut Transcript, sk: &Scalar, tag: &RistrettoPoint) -> TagProof {
+        let a = Scalar::random(&mut thread_rng());
+        let (r1, r2) = (&a*PEDERSEN_H_TABLE(), a*tag);
+        transcript.append_point(b"R1", &r1.compress());
+        transcript.append_point(b"R2", &r2.compress());
+        let c = transcript.challenge_scalar(b"c");
+        TagProof { r1, r2, z: a + c*sk }
+    }
+
+    pub(crate) fn verify(&self, transcript: &mut Transcript, pk: &RistrettoPoint, tag: &RistrettoPoint) -> bool {
+        transcript.append_point(b"R1", &self.r1.compress());
+        transcript.append_point(b"R2", &self.r2.compress());
+        let c = transcript.challenge_scalar(b"c");
+        &self.z*PEDERSEN_H_TABLE() == self.r1 + c*pk && self.z*tag == self.r2 + c*RISTRETTO_BASEPOINT_POINT
+    }
+}
+
 
 
 
@@ -397,3 +805,91 @@ impl Hash for OTAccount {
 
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receive_at_subaddress() {
+        let me = Account::new(&"subaddress owner");
+        let subs = me.subaddresses(8);
+        assert_eq!(me.subaddress(0), me);
+        assert_eq!(subs.len(), 8);
+
+        let sub = me.subaddress(5);
+        let sender_view = Account::from_name(&sub.name()).unwrap();
+        assert!(sender_view.subaddress);
+        assert_ne!(sub.apk, me.apk);
+        assert_ne!(sub.vpk, me.vpk);
+        assert_ne!(me.subaddress(6).apk, sub.apk);
+        let ota = sender_view.derive_ot(&Scalar::from(6u64)).publish_offer();
+
+        let (index, received) = subs.receive_ot(&ota).unwrap();
+        assert_eq!(index, 5);
+        assert_eq!(received.com.amount, Some(Scalar::from(6u64)));
+        assert_eq!(Account::tag_k_gen(received.get_sk().unwrap()), ota.pk);
+        assert_eq!(received.get_tag(), received.account.as_ref().unwrap().receive_ot(&ota).unwrap().get_tag());
+        assert!(me.receive_ot(&ota).is_err());
+        assert!(me.subaddresses(5).receive_ot(&ota).is_err());
+
+        let (index, _) = subs.receive_ot(&me.derive_ot(&Scalar::from(1u64)).publish_offer()).unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(Account::from_name(&me.name()).unwrap().pk, me.pk);
+        assert!(Account::from_name("not a name").is_none());
+        assert!(Account::new(&"someone else").subaddresses(8).receive_ot(&ota).is_err());
+
+        let watching = Account::from_watch_key(&me.watch_key()).unwrap();
+        assert!(watching.is_watch_only() && watching.name() == me.name());
+        assert!(watching.receive_ot(&me.derive_ot(&Scalar::one())).is_ok());
+        assert!(Account::from_watch_key("not a watch key").is_none());
+    }
+
+    #[test]
+    fn seed_generations() {
+        // every generation after a panic comes back from the seed
+        let root = Account::new(&"seed");
+        let next = Account::from_seed_generation(&"seed", 1);
+        assert_eq!(Account::from_seed_generation(&"seed", 0), root);
+        assert_eq!(next, Account::from_seed_generation(&"seed", 1));
+        assert!(next != root && next != Account::from_seed_generation(&"seed", 2));
+    }
+
+    #[test]
+    fn keys_wiped() {
+        // drop wipes them the same way
+        let mut acc = Account::new(&"seed");
+        let mut ota = acc.receive_ot(&acc.derive_ot(&Scalar::from(5u64))).unwrap();
+        assert!(ota.sk.is_some() && ota.s.is_some() && ota.ek.is_some());
+        let (pk, com) = (ota.pk, ota.com.com);
+        ota.zeroize();
+        acc.zeroize();
+        assert!(ota.sk.is_none() && ota.s.is_none() && ota.ek.is_none());
+        assert_eq!((ota.pk, ota.com.com), (pk, com));
+        assert_eq!((acc.sk, acc.ask, acc.vsk), (Scalar::zero(), Scalar::zero(), Scalar::zero()));
+        assert_eq!(acc, Account::from_pks(&acc.pk.compress(), &acc.apk.compress(), &acc.vpk.compress()));
+    }
+
+    #[test]
+    fn rollback_across_generation_switch() {
+        let gen = |g| Account::from_seed_generation(&"seed", g);
+        let mut generations = Generations::after(&"seed", 0, 3);
+        assert_eq!(generations.next(), Some(&gen(1)));
+
+        // the restore follows two panics then the blocks with both are undone
+        let me = generations.advance(&gen(0)).unwrap();
+        assert_eq!(me, gen(1));
+        let me = generations.advance(&me).unwrap();
+        assert_eq!((&me, generations.next()), (&gen(2), Some(&gen(3))));
+        generations.rewind(&me, &gen(0));
+        assert_eq!(generations.next(), Some(&gen(1)));
+
+        // and it follows them again from there
+        let me = generations.advance(&gen(0)).unwrap();
+        generations.rewind(&me, &me.clone());
+        assert_eq!(generations.next(), Some(&gen(2)));
+        assert_eq!(generations.advance(&me), Some(gen(2)));
+        assert_eq!(generations.advance(&gen(2)), Some(gen(3)));
+        assert_eq!(generations.advance(&gen(3)), None);
+    }
+}
@@ -11,10 +11,9 @@ use plumcast::service::ServiceBuilder;
 use rand::prelude::SliceRandom;
 use sloggers::terminal::{Destination, TerminalLoggerBuilder};
 use sloggers::Build;
-use std::fs::File;
-use std::io::{Read, Write};
 use std::net::SocketAddr;
-use std::path::Path;
+use std::io;
+use std::sync::Arc;
 use trackable::error::MainError;
 use crossbeam::channel;
 
@@ -26,13 +25,21 @@ use std::convert::TryInto;
 use std::time::{Duration, Instant};
 use std::borrow::Borrow;
 use kora::transaction::*;
-use curve25519_dalek::ristretto::{CompressedRistretto};
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
 use sha3::{Digest, Sha3_512};
 use rayon::prelude::*;
 use kora::bloom::*;
+use kora::spent::SpentTags;
+use kora::vault::Vault;
+use kora::mnemonic;
+use kora::watch::KeyImage;
 use kora::validation::*;
 use kora::ringmaker::*;
+use kora::storage::{Storage, FileStorage, BlockStore, NodeStore, JournalStore, Recovery, BlockKind, BLOOM, SPENT_INDEX, NODE};
+use kora::storage::format::{Versioned, Migration, format};
+use kora::blockcheck;
 use serde::{Serialize, Deserialize};
+use zeroize::{Zeroize, Zeroizing};
 use kora::validation::{NUMBER_OF_VALIDATORS, SIGNING_CUTOFF, QUEUE_LENGTH, REPLACERATE};
 
 use local_ipaddress;
@@ -43,6 +50,12 @@ const EXIT_TIME: usize = REPLACERATE*5;
 const USURP_TIME: u64 = 3600;
 /// the default port
 const DEFAULT_PORT: u64 = 8334;
+/// the number of recent full blocks a pruned node keeps (this has to be more than the number of blocks that can be undone)
+const FULL_BLOCK_WINDOW: u64 = 100;
+/// how many blocks a pruned node waits between compacting its full block file
+const PRUNE_INTERVAL: u64 = 10;
+/// how many panics after the login a restore looks for
+const RESTORE_GENERATIONS: u32 = 16;
 /// calculates the amount of time the current block takes to be created
 fn blocktime(cumtime: f64) -> f64 {
     // 60f64/(6.337618E-8f64*cumtime+2f64).ln()
@@ -53,6 +66,14 @@ fn reward(cumtime: f64, blocktime: f64) -> f64 {
     (1.0/(1.653439E-6*cumtime + 1.0) - 1.0/(1.653439E-6*(cumtime + blocktime) + 1.0))*10E16f64
 }
 
+/// tells you which of the tags were spent. if the spent tags can't be read they all count as spent
+fn spent_tags(spent: &SpentTags, bloom: &BloomFile, tags: &[Tag]) -> Vec<bool> {
+    spent.spent_batch(bloom, &tags.iter().map(|x| x.as_bytes()).collect::<Vec<_>>()).unwrap_or_else(|e| {
+        println!("couldn't look up a spent tag: {}", e);
+        vec![true; tags.len()]
+    })
+}
+
 fn main() -> Result<(), MainError> {
     let logger = track!(TerminalLoggerBuilder::new().destination(Destination::Stderr).level("info".parse().unwrap()).build())?; // info or debug
 
@@ -71,10 +92,56 @@ fn main() -> Result<(), MainError> {
     // this is the number of shards they keep track of
     let max_shards = 64usize; /* this if for testing purposes... there IS NO MAX SHARDS */
     
+    // everything the node saves goes in the data directory (the first argument, or the current directory) so you can run multiple nodes on 1 computer
+    let data_dir = std::env::args().nth(1).unwrap_or(".".to_string());
+    let storage: Arc<dyn Storage> = match FileStorage::new(&data_dir).and_then(|x| Versioned::open(x, &NODE_MIGRATIONS)) {
+        Ok(storage) => Arc::new(storage),
+        Err(e) => {
+            println!("couldn't open the data directory: {}", e);
+            std::process::exit(1)
+        }
+    };
+    println!("data directory: {}",data_dir);
+
+    // "khora <data directory> check" or "khora <data directory> repair" looks over the saved blocks instead of running the node
+    match std::env::args().nth(2).as_deref() {
+        Some("check") => {
+            check_blocks(storage, false);
+            return Ok(())
+        }
+        Some("repair") => {
+            check_blocks(storage, true);
+            return Ok(())
+        }
+        Some("password") => {
+            change_password(&*storage);
+            return Ok(())
+        }
+        // "khora <data directory> watchkey" prints the keys a watch-only node needs, "keyimages <file>" saves the key images
+        // it needs to see your money being spent and "import <file>" gives them to the watch-only node
+        Some("watchkey") => {
+            show_watch_key(&*storage);
+            return Ok(())
+        }
+        Some(x @ "keyimages") | Some(x @ "import") => {
+            match std::env::args().nth(3) {
+                Some(file) if x == "keyimages" => export_key_images(&*storage, &file),
+                Some(file) => import_key_images(&*storage, &file),
+                None => println!("which file should the key images be in?"),
+            }
+            return Ok(())
+        }
+        Some("restore") | Some("watch") if storage.has_node() => {
+            println!("there's already an account in {}", data_dir);
+            return Ok(())
+        }
+        _ => {}
+    }
 
-
-    // the myNode file only exists if you already have an account made
-    let setup = !Path::new("myNode").exists();
+    // the saved node only exists if you already have an account made
+    let setup = !storage.has_node();
+    let mut setup_vault = None;
+    let mut later_generations = Generations::default();
     if setup {
         // everyone agrees this person starts with 1 khora token
         let person0 = CompressedRistretto([46, 235, 227, 188, 55, 53, 9, 126, 167, 207, 202, 101, 150, 150, 172, 207, 209, 208, 211, 52, 47, 206, 19, 115, 199, 189, 202, 10, 56, 220, 138, 55]);
@@ -84,30 +151,45 @@ fn main() -> Result<(), MainError> {
         let (ui_sender_setup, mut urecv_setup) = mpsc::channel();
         let (usend_setup, ui_reciever_setup) = channel::unbounded();
 
-        // creates the setup screen (sets the values used in the loops and sets some gui options)
-        let app = gui::TemplateApp::new(
-            ui_reciever_setup,
-            ui_sender_setup,
-            "".to_string(),
-            "".to_string(),
-            vec![],
-            vec![],
-            vec![],
-            true,
-        );
-        let native_options = eframe::NativeOptions::default();
-        eframe::run_native(Box::new(app), native_options);
-        println!("You closed the app...");
-        let pswrd: Vec<u8>;
+        let watch = std::env::args().nth(2).as_deref() == Some("watch");
+        if std::env::args().nth(2).as_deref() == Some("restore") {
+            // "khora <data directory> restore" asks for a seed phrase instead of opening the setup screen
+            for mut m in restore_messages() {
+                ui_sender_setup.send(std::mem::take(&mut *m)).expect("couldn't restore the account");
+            }
+        } else if watch {
+            // "khora <data directory> watch" makes a watch-only node from a watch key
+            for mut m in watch_messages() {
+                ui_sender_setup.send(std::mem::take(&mut *m)).expect("couldn't set up the watch-only node");
+            }
+        } else {
+            // creates the setup screen (sets the values used in the loops and sets some gui options)
+            let app = gui::TemplateApp::new(
+                ui_reciever_setup,
+                ui_sender_setup,
+                "".to_string(),
+                "".to_string(),
+                Zeroizing::new(vec![]),
+                Zeroizing::new(vec![]),
+                Zeroizing::new(vec![]),
+                true,
+                Some(storage.clone()),
+            );
+            let native_options = eframe::NativeOptions::default();
+            eframe::run_native(Box::new(app), native_options);
+            println!("You closed the app...");
+        }
+        let mut pswrd: Zeroizing<Vec<u8>>;
         let will_stk: bool;
         let lightning_yielder: bool;
+        let pruning: bool;
         let wait_to_work = Instant::now();
         loop {
             if wait_to_work.elapsed().as_secs() > 2 {
                 panic!("you didn't hit the button you should have");
             }
             if let Async::Ready(Some(m)) = urecv_setup.poll().expect("Shouldn't fail") {
-                pswrd = m;
+                pswrd = Zeroizing::new(m);
                 break
             }
         }
@@ -126,23 +208,55 @@ fn main() -> Result<(), MainError> {
             }
             if let Async::Ready(Some(m)) = urecv_setup.poll().expect("Shouldn't fail") {
                 lightning_yielder = m[0] == 1;
+                pruning = m[0] == 2;
+                break
+            }
+        }
+        let vault: Vault;
+        loop {
+            if wait_to_work.elapsed().as_secs() > 2 {
+                panic!("you didn't hit the button you should have");
+            }
+            if let Async::Ready(Some(mut m)) = urecv_setup.poll().expect("Shouldn't fail") {
+                vault = Vault::create(&m); // the password that encrypts myNode
+                m.zeroize();
                 break
             }
         }
-        println!("{:?}",pswrd);
-        let me = Account::new(&pswrd);
+        let mut migrate_to = None;
+        let me = if watch {
+            Account::from_watch_key(&String::from_utf8_lossy(&pswrd)).expect("the watch key was checked when it was typed in")
+        } else {
+            // the login ends with the number of times the panic button was pressed since the account was made, the
+            // seed of the hardened login if it's an old login and a byte saying which it is: 0 for a login, 1 for a
+            // restore that looks for panics after that and 2 for an old login
+            let kind = pswrd.pop();
+            if kind == Some(2) {
+                let hardened = Zeroizing::new(pswrd.split_off(36));
+                migrate_to = Some(Account::new(&*hardened));
+            }
+            let restoring = kind == Some(1);
+            let generation = u32::from_le_bytes(pswrd.split_off(32).try_into().unwrap());
+            if restoring {
+                later_generations = Generations::after(&*pswrd, generation, RESTORE_GENERATIONS);
+            }
+            Account::from_seed_generation(&*pswrd, generation)
+        };
         let validator = me.stake_acc().receive_ot(&me.stake_acc().derive_stk_ot(&Scalar::from(1u8))).unwrap(); //make a new account
-        let key = validator.sk.unwrap();
+        let key = validator.sk.unwrap_or_default(); // watch-only nodes don't have one because they can't stake
         let mut keylocation = HashSet::new();
+        let history = History::with_cache(storage.clone(), HISTORY_CACHE);
         if will_stk {
             if !lightning_yielder {
-                NextBlock::initialize_saving();
+                NextBlock::initialize_saving(&*storage);
             }
-            LightningSyncBlock::initialize_saving();
-            History::initialize();
-            BloomFile::initialize_bloom_file();    
+            LightningSyncBlock::initialize_saving(&*storage);
+            history.initialize();
+            BloomFile::initialize_bloom_file(&*storage, EXPECTED_TAGS, FALSE_POSITIVE_RATE);
+            SpentTags::initialize(&*storage).expect("couldn't create the spent tags");
         }
-        let bloom = BloomFile::from_randomness();
+        let bloom = BloomFile::from_randomness(storage.clone()).expect("couldn't read the bloom filter");
+        let spent = SpentTags::new(storage.clone());
 
         let mut smine = vec![];
         for i in 0..initial_history.len() {
@@ -160,6 +274,7 @@ fn main() -> Result<(), MainError> {
             save_history: will_stk,
             me,
             mine: HashMap::new(),
+            balance: Scalar::zero(),
             smine: smine.clone(), // [location, amount]
             key,
             keylocation,
@@ -172,6 +287,9 @@ fn main() -> Result<(), MainError> {
             comittee: (0..max_shards).map(|_|(0..NUMBER_OF_VALIDATORS).into_par_iter().map(|x| (x%NUMBER_OF_VALIDATORS)%initial_history.len()).collect::<Vec<usize>>()).collect::<Vec<_>>(),
             lastname: Scalar::one().as_bytes().to_vec(),
             bloom,
+            spent,
+            history,
+            storage: storage.clone(),
             bnum: 0u64,
             lastbnum: 0u64,
             height: 0u64,
@@ -200,15 +318,29 @@ fn main() -> Result<(), MainError> {
             sync_returnaddr: None,
             sync_theirnum: 0u64,
             sync_lightning: false,
+            pruned_peers: HashMap::new(),
+            gui_received: HashMap::new(),
             outs: None,
             oldstk: None,
             cumtime: 0f64,
             blocktime: blocktime(0.0),
             lightning_yielder,
+            pruning,
+            vault,
             gui_timer: Instant::now(),
+            later_generations: Generations::default(),
+            migrate_to,
         };
         node.save();
+        setup_vault = Some(node.vault.clone());
     }
+    let (vault, buf) = match unlock_node(&*storage, setup_vault) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("couldn't unlock the saved node: {}", e);
+            std::process::exit(1)
+        }
+    };
 
 
     let executor = track_any_err!(ThreadPoolExecutor::new())?;
@@ -229,12 +361,21 @@ fn main() -> Result<(), MainError> {
 
 
 
-    let node = KhoraNode::load(frontnode, backnode, usend, urecv);
-    let mut mymoney = node.mine.iter().map(|x| node.me.receive_ot(&x.1).unwrap().com.amount.unwrap()).sum::<Scalar>().as_bytes()[..8].to_vec();
+    let mut node = match KhoraNode::load(storage.clone(), vault, &buf, frontnode, backnode, usend, urecv) {
+        Ok(node) => node,
+        Err(e) => {
+            println!("couldn't load the saved node: {}", e);
+            std::process::exit(1)
+        }
+    };
+    node.later_generations = later_generations; // only kept while the node that restored runs (they're never saved)
+    node.refresh_mine();
+    let mut mymoney = node.balance.as_bytes()[..8].to_vec();
     mymoney.extend(node.smine.iter().map(|x| x[1]).sum::<u64>().to_le_bytes());
     mymoney.push(0);
     println!("my money:\n---------------------------------\n{:?}",mymoney);
     node.gui_sender.send(mymoney).expect("something's wrong with the communication to the gui"); // this is how you send info to the gui
+    node.send_received();
     node.save();
     
     
@@ -246,10 +387,11 @@ fn main() -> Result<(), MainError> {
         ui_sender,
         node.me.name(),
         node.me.stake_acc().name(),
-        node.me.sk.as_bytes().to_vec(),
-        node.me.vsk.as_bytes().to_vec(),
-        node.me.ask.as_bytes().to_vec(),
+        Zeroizing::new(node.me.sk.as_bytes().to_vec()),
+        Zeroizing::new(node.me.vsk.as_bytes().to_vec()),
+        Zeroizing::new(node.me.ask.as_bytes().to_vec()),
         false,
+        Some(storage),
     );
     println!("starting!");
     let native_options = eframe::NativeOptions::default();
@@ -267,6 +409,266 @@ fn main() -> Result<(), MainError> {
     Ok(())
 }
 
+/// asks for a password without showing it
+fn ask_password(prompt: &str) -> io::Result<Zeroizing<Vec<u8>>> {
+    Ok(Zeroizing::new(rpassword::read_password_from_tty(Some(prompt))?.into_bytes()))
+}
+
+/// reads a line the user types in
+fn ask_line(prompt: &str) -> String {
+    println!("{}", prompt);
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).expect("couldn't read the line");
+    line.trim().to_string()
+}
+
+/// asks for a seed phrase and the node's settings on the command line and makes the messages the setup screen sends
+fn restore_messages() -> Vec<Zeroizing<Vec<u8>>> {
+    let mut seed = loop {
+        let phrase = ask_password("seed phrase: ").expect("couldn't read the seed phrase");
+        match mnemonic::from_phrase(&String::from_utf8_lossy(&phrase)) {
+            Ok(seed) => break Zeroizing::new(Zeroizing::new(seed).to_vec()),
+            Err(e) => println!("{}", e),
+        }
+    };
+    // the node follows the account through the panics it finds while it syncs
+    seed.extend(0u32.to_le_bytes());
+    seed.push(1);
+    let staking = ask_line("do you want to be a staker (y/n)?") == "y";
+    let lightning_yielder = !staking || ask_line("do you only want to store lightning blocks (y/n)?") == "y";
+    vec![seed, Zeroizing::new(vec![!staking as u8]), Zeroizing::new(vec![lightning_yielder as u8]), ask_node_password()]
+}
+
+/// asks for a watch key on the command line and makes the messages the setup screen sends for a watch-only node
+/// (it can't stake so it only stores lightning blocks)
+fn watch_messages() -> Vec<Zeroizing<Vec<u8>>> {
+    let key = loop {
+        let key = ask_password("watch key: ").expect("couldn't read the watch key");
+        if Account::from_watch_key(&String::from_utf8_lossy(&key)).is_some() {
+            break key
+        }
+        println!("that isn't a watch key");
+    };
+    vec![key, Zeroizing::new(vec![1]), Zeroizing::new(vec![1]), ask_node_password()]
+}
+
+/// asks for the password that encrypts a new saved node until it's typed the same twice
+fn ask_node_password() -> Zeroizing<Vec<u8>> {
+    loop {
+        let password = ask_password("password for the saved node: ").expect("couldn't read the password");
+        if ask_password("password again: ").expect("couldn't read the password") == password {
+            return password
+        }
+        println!("the passwords don't match");
+    }
+}
+
+/// decrypts the saved node with vault or with the password the user types in
+/// nodes saved before myNode was encrypted are encrypted with the password the next time they're saved
+/// the decrypted node holds the keys so it's wiped when it's dropped
+fn unlock_node(storage: &dyn Storage, vault: Option<Vault>) -> io::Result<(Vault, Zeroizing<Vec<u8>>)> {
+    let sealed = storage.load_node()?;
+    if let Some(vault) = vault {
+        let buf = vault.open(&sealed).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        return Ok((vault, Zeroizing::new(buf)))
+    }
+    if !Vault::is_sealed(&sealed) {
+        println!("the saved node isn't encrypted yet");
+        return Ok((Vault::create(&ask_node_password()), Zeroizing::new(sealed)))
+    }
+    for _ in 0..3 {
+        let password = ask_password("password: ")?;
+        match Vault::unlock(&password, &sealed) {
+            Ok((vault, buf)) => return Ok((vault, Zeroizing::new(buf))),
+            Err(e) => println!("{}", e),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::PermissionDenied, "wrong password"))
+}
+
+/// changes the password that encrypts the saved node
+fn change_password(storage: &dyn Storage) {
+    let (mut vault, buf) = match unlock_node(storage, None) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("couldn't unlock the saved node: {}", e);
+            return
+        }
+    };
+    let password = ask_password("new password: ").expect("couldn't read the password");
+    if ask_password("new password again: ").expect("couldn't read the password") != password {
+        println!("the passwords don't match");
+        return
+    }
+    vault.change_password(&password);
+    storage.save_node(&vault.seal(&buf)).expect("couldn't save the node");
+    println!("changed the password");
+}
+
+/// decrypts and reads the saved node with the password the user types in
+fn unlock_saved_node(storage: &dyn Storage) -> Option<(Vault, SavedNode)> {
+    match unlock_node(storage, None) {
+        Ok((vault, buf)) => match SavedNode::read(&buf) {
+            Ok(sn) => Some((vault, sn)),
+            Err(_) => {
+                println!("the saved node can't be read");
+                None
+            }
+        },
+        Err(e) => {
+            println!("couldn't unlock the saved node: {}", e);
+            None
+        }
+    }
+}
+
+/// prints the watch key of the saved account (a watch-only node is made from it with "khora <data directory> watch")
+fn show_watch_key(storage: &dyn Storage) {
+    if let Some((_, sn)) = unlock_saved_node(storage) {
+        println!("watch key (anyone with it sees every payment you get):\n{}", sn.me.watch_key());
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+/// the key images of every output an account hasn't spent as of height. a watch-only node that imports them knows the
+/// outputs it found before height that don't have one were spent
+struct KeyImages {
+    height: u64,
+    images: Vec<KeyImage>,
+}
+
+/// saves the key images of the saved account's outputs to file
+fn export_key_images(storage: &dyn Storage, file: &str) {
+    let (_, sn) = match unlock_saved_node(storage) {
+        Some(x) => x,
+        None => return,
+    };
+    let images = match sn.mine.iter().map(|(&location, x)| KeyImage::export(location, x)).collect::<Result<Vec<_>, _>>() {
+        Ok(x) => x,
+        Err(_) => {
+            println!("a watch-only node can't make key images");
+            return
+        }
+    };
+    let count = images.len();
+    match std::fs::write(file, bincode::serialize(&KeyImages { height: sn.height, images }).unwrap()) {
+        Ok(()) => println!("saved {} key images to {}", count, file),
+        Err(e) => println!("couldn't save the key images: {}", e),
+    }
+}
+
+/// gives a watch-only node the key images in file so it sees its outputs being spent and forgets the ones that were
+fn import_key_images(storage: &dyn Storage, file: &str) {
+    let (vault, mut sn) = match unlock_saved_node(storage) {
+        Some(x) => x,
+        None => return,
+    };
+    if !sn.me.is_watch_only() {
+        println!("only watch-only nodes import key images");
+        return
+    }
+    let exported = match std::fs::read(file).ok().and_then(|x| bincode::deserialize::<KeyImages>(&x).ok()) {
+        Some(x) => x,
+        None => {
+            println!("{} doesn't have key images in it", file);
+            return
+        }
+    };
+    if exported.height > sn.height {
+        println!("the key images are from a node that's further along. let this node catch up and import them again");
+        return
+    }
+    if let Some(image) = exported.images.iter().find(|x| sn.mine.get(&x.location).is_none_or(|y| !x.verify(y))) {
+        println!("the key image of output {} isn't for one of this node's outputs", image.location);
+        return
+    }
+    let imported = exported.images.iter().map(|x| x.location).collect::<HashSet<_>>();
+    let before = sn.mine.len();
+    sn.mine.retain(|location, _| *location >= exported.height || imported.contains(location));
+    for image in exported.images.iter() {
+        sn.mine.get_mut(&image.location).unwrap().tag = Some(image.tag);
+    }
+    storage.save_node(&vault.seal(&Zeroizing::new(bincode::serialize(&sn).unwrap()))).expect("couldn't save the node");
+    println!("imported {} key images ({} outputs were already spent)", imported.len(), before - sn.mine.len());
+}
+
+/// checks that the saved blocks, metadata, history and bloom filter agree with each other
+/// if repair is set, anything that's wrong is rebuilt from the blocks themselves
+fn check_blocks(storage: Arc<dyn Storage>, repair: bool) {
+    let saved = if storage.has_node() {
+        match unlock_node(&*storage, None) {
+            Ok((_, buf)) => SavedNode::read(&buf).ok(),
+            Err(e) => {
+                println!("couldn't unlock the saved node: {}", e);
+                return
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(sn) = &saved {
+        if storage.recover(sn.bnum).expect("couldn't recover the unfinished block") != Recovery::Clean {
+            println!("finished recovering the unfinished block");
+        }
+    } else {
+        println!("there's no saved node so the bloom filter won't be checked");
+    }
+    let mut broken_bloom = false;
+    let mut bloom = saved.as_ref().filter(|_| storage.exists(BLOOM)).and_then(|sn| match BloomFile::from_keys(storage.clone(), sn.bloom[0], sn.bloom[1]) {
+        Ok(bloom) => Some(bloom),
+        Err(e) => {
+            println!("problem: the bloom filter can't be read: {}", e);
+            broken_bloom = true;
+            None
+        }
+    });
+
+    let report = blockcheck::check(&*storage, bloom.as_ref());
+    println!("{} blocks ({} full, {} lightning) with {} outputs", report.height, report.full_blocks, report.lightning_blocks, report.outputs);
+    for problem in report.problems.iter() {
+        println!("problem: {}", problem);
+    }
+    if report.is_ok() && !broken_bloom {
+        println!("everything is consistent!");
+        return
+    }
+    if !repair {
+        println!("run with repair to rebuild the metadata, history and bloom filter from the blocks");
+        return
+    }
+
+    // the saved node knows how many blocks there should be even if the last few were empty
+    let height = saved.as_ref().map(|sn| sn.bnum).unwrap_or(0);
+    if storage.exists(BlockKind::Full.data()) {
+        let blocks = blockcheck::rebuild_metadata(&*storage, BlockKind::Full, height).expect("couldn't rebuild the full block metadata");
+        println!("rebuilt the metadata of {} full blocks", blocks);
+    }
+    let blocks = blockcheck::rebuild_metadata(&*storage, BlockKind::Lightning, height).expect("couldn't rebuild the lightning block metadata");
+    println!("rebuilt the metadata of {} lightning blocks", blocks);
+    let outputs = blockcheck::rebuild_history(storage.clone()).expect("couldn't rebuild the history");
+    println!("rebuilt the history with {} outputs", outputs);
+    if broken_bloom {
+        BloomFile::initialize_bloom_file(&*storage, EXPECTED_TAGS, FALSE_POSITIVE_RATE);
+        bloom = saved.as_ref().map(|sn| BloomFile::from_keys(storage.clone(), sn.bloom[0], sn.bloom[1]).expect("couldn't read the new bloom filter"));
+    }
+    if let Some(bloom) = &bloom {
+        let tags = blockcheck::rebuild_bloom(&*storage, bloom).expect("couldn't rebuild the bloom filter");
+        println!("rebuilt the bloom filter with {} tags", tags);
+    }
+    if storage.exists(SPENT_INDEX) {
+        let spent = SpentTags::new(storage.clone());
+        spent.rebuild_index().expect("couldn't rebuild the spent tag index");
+        println!("rebuilt the index of {} spent tags", spent.len());
+    }
+
+    let report = blockcheck::check(&*storage, bloom.as_ref());
+    for problem in report.problems.iter() {
+        println!("still a problem: {}", problem);
+    }
+    if report.is_ok() {
+        println!("everything is consistent now!");
+    }
+}
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 /// the information that you save to a file when the app is off (not including gui information like saved friends)
@@ -301,8 +703,34 @@ struct SavedNode {
     blocktime: f64,
     lightning_yielder: bool,
     is_validator: bool,
+    pruning: bool, // new fields go at the end so older saved nodes can be upgraded by adding them
+    migrate_to: Option<Account>, // the hardened login's account if the node was made with an old login
+}
+
+impl SavedNode {
+    /// reads a decrypted saved node. the node is sealed so fields added since can't be filled in by a migration and
+    /// are filled in here instead
+    fn read(buf: &[u8]) -> bincode::Result<SavedNode> {
+        bincode::deserialize::<SavedNode>(buf).or_else(|_| {
+            // migrate_to didn't exist before that
+            let mut buf = Zeroizing::new(buf.to_vec());
+            buf.push(0);
+            bincode::deserialize::<SavedNode>(&buf)
+        })
+    }
 }
 
+/// the changes to the saved node since myNode had a header (bump NODE's version in FORMATS when you add one)
+static NODE_MIGRATIONS: [Migration; 1] = [
+    // pruning didn't exist before that
+    Migration { name: NODE, from: 0, upgrade: |storage| {
+        let mut file = format(NODE).unwrap().header(1).to_vec();
+        file.extend(storage.read_all(NODE)?);
+        file.push(false as u8);
+        storage.write_all(NODE, &file)
+    } },
+];
+
 /// the node used to run all the networking
 struct KhoraNode {
     inner: Node<Vec<u8>>, // for sending and recieving messages as a validator (as in inner sanctum)
@@ -312,6 +740,7 @@ struct KhoraNode {
     save_history: bool, //just testing. in real code this is true; but i need to pretend to be different people on the same computer
     me: Account,
     mine: HashMap<u64, OTAccount>,
+    balance: Scalar, // the money in mine (see refresh_mine)
     smine: Vec<[u64; 2]>, // [location, amount]
     key: Scalar,
     keylocation: HashSet<u64>,
@@ -324,6 +753,9 @@ struct KhoraNode {
     comittee: Vec<Vec<usize>>,
     lastname: Vec<u8>,
     bloom: BloomFile,
+    spent: SpentTags,
+    history: History,
+    storage: Arc<dyn Storage>,
     bnum: u64,
     lastbnum: u64,
     height: u64,
@@ -350,21 +782,107 @@ struct KhoraNode {
     sync_returnaddr: Option<NodeId>,
     sync_theirnum: u64,
     sync_lightning: bool,
+    pruned_peers: HashMap<NodeId, u64>, // the first full block each pruned peer still has
+    gui_received: HashMap<u64, RistrettoPoint>, // the outputs the gui was told about (see send_received)
     outs: Option<Vec<(Account, Scalar)>>,
     oldstk: Option<(Account, Vec<[u64;2]>, u64)>,
     cumtime: f64,
     blocktime: f64,
     lightning_yielder: bool,
+    pruning: bool,
+    vault: Vault,
     gui_timer: Instant,
+    later_generations: Generations, // the accounts after panics a restore is still looking for
+    migrate_to: Option<Account>,
+}
+
+impl Drop for KhoraNode {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
 }
 
 impl KhoraNode {
-    /// saves the important information like staker state and block number to a file: "myNode"
+    /// caches the balance after mine changes
+    fn refresh_mine(&mut self) {
+        self.balance = self.mine.values().map(|x| x.com.amount.unwrap()).sum::<Scalar>();
+    }
+
+    /// tells the gui about the outputs you gained (with the memos that came with them) and lost since it was last told
+    fn send_received(&mut self) {
+        let added = self.mine.iter().filter(|(location, x)| self.gui_received.get(location) != Some(&x.pk)).map(|(location, x)| {
+            (*location, u64::from_le_bytes(x.com.amount.unwrap().as_bytes()[..8].try_into().unwrap()), x.memo.clone().unwrap_or_default())
+        }).collect::<Vec<(u64, u64, Vec<u8>)>>();
+        let removed = self.gui_received.keys().filter(|x| !self.mine.contains_key(x)).copied().collect::<Vec<u64>>();
+        if added.is_empty() && removed.is_empty() {
+            return
+        }
+        self.gui_received = self.mine.iter().map(|(location, x)| (*location, x.pk)).collect();
+        let mut m = bincode::serialize(&(added, removed)).unwrap();
+        m.push(5);
+        self.gui_sender.send(m).expect("something's wrong with the communication to the gui");
+    }
+
+    /// makes newacc the node's account (after a panic) and tells the gui about it
+    fn switch_account(&mut self, newacc: Account) {
+        self.mine = HashMap::new();
+        self.balance = Scalar::zero();
+        self.smine = vec![];
+        self.keylocation = HashSet::new();
+        self.set_account(newacc);
+    }
+
+    /// makes acc the node's account and staking key and sends its keys to the gui
+    fn set_account(&mut self, acc: Account) {
+        self.me = acc;
+        self.key.zeroize();
+        self.key = self.me.stake_acc().receive_ot(&self.me.stake_acc().derive_stk_ot(&Scalar::from(1u8))).unwrap().sk.unwrap_or_default();
+        let mut m1 = self.me.name().as_bytes().to_vec();
+        m1.extend([0,u8::MAX]);
+        let mut m2 = self.me.stake_acc().name().as_bytes().to_vec();
+        m2.extend([1,u8::MAX]);
+        // the gui keeps the keys it gets in Zeroizing too
+        let mut m3 = Zeroizing::new(self.me.sk.as_bytes().to_vec());
+        m3.extend([2,u8::MAX]);
+        let mut m4 = Zeroizing::new(self.me.vsk.as_bytes().to_vec());
+        m4.extend([3,u8::MAX]);
+        let mut m5 = Zeroizing::new(self.me.ask.as_bytes().to_vec());
+        m5.extend([4,u8::MAX]);
+        self.gui_sender.send(m1).expect("should be working");
+        self.gui_sender.send(m2).expect("should be working");
+        self.gui_sender.send(m3.to_vec()).expect("should be working");
+        self.gui_sender.send(m4.to_vec()).expect("should be working");
+        self.gui_sender.send(m5.to_vec()).expect("should be working");
+    }
+
+    /// the message that asks a peer to sync you from your block number
+    fn sync_request(&self) -> Vec<u8> {
+        let mut mynum = self.bnum.to_le_bytes().to_vec();
+        if self.lightning_yielder { // lightning users don't ask for full blocks
+            mynum.push(108); //l
+        } else {
+            mynum.push(102); //f
+        }
+        mynum.push(121);
+        mynum
+    }
+
+    /// a random peer to sync from other than the ones in skip. peers that pruned the full blocks you need aren't picked
+    fn sync_helper(&self, skip: &[NodeId]) -> Option<NodeId> {
+        let mut friend = self.outer.plumtree_node().all_push_peers();
+        friend.remove(self.outer.plumtree_node().id());
+        let friend = friend.into_iter().filter(|x| {
+            !skip.contains(x) && (self.lightning_yielder || self.pruned_peers.get(x).map_or(true, |&p| p <= self.bnum))
+        }).collect::<Vec<_>>();
+        friend.choose(&mut rand::thread_rng()).copied()
+    }
+
+    /// saves the important information like staker state and block number to storage
     fn save(&self) {
         if !self.moneyreset.is_some() && !self.oldstk.is_some() {
-            let sn = SavedNode {
+            let mut sn = SavedNode {
                 save_history: self.save_history,
-                me: self.me,
+                me: self.me.clone(),
                 mine: self.mine.clone(),
                 smine: self.smine.clone(), // [location, amount]
                 key: self.key,
@@ -392,26 +910,46 @@ impl KhoraNode {
                 cumtime: self.cumtime,
                 blocktime: self.blocktime,
                 lightning_yielder: self.lightning_yielder,
+                pruning: self.pruning,
                 is_validator: self.is_validator,
+                migrate_to: self.migrate_to.clone(),
             }; // just redo initial conditions on the rest
-            let mut sn = bincode::serialize(&sn).unwrap();
-            let mut f = File::create("myNode").unwrap();
-            f.write_all(&mut sn).unwrap();
+            let plain = Zeroizing::new(bincode::serialize(&sn).unwrap());
+            sn.key.zeroize();
+            let sn = self.vault.seal(&plain);
+            self.storage.commit_block(&sn).expect("couldn't save the node");
         }
     }
 
-    /// loads the node information from a file: "myNode"
-    fn load(inner: Node<Vec<u8>>, outer: Node<Vec<u8>>, gui_sender: channel::Sender<Vec<u8>>, gui_reciever: mpsc::Receiver<Vec<u8>>) -> KhoraNode {
-        let mut buf = Vec::<u8>::new();
-        let mut f = File::open("myNode").unwrap();
-        f.read_to_end(&mut buf).unwrap();
+    /// reads a saved node that was sealed by this node's vault (or saved before myNode was encrypted). its staking key is
+    /// wiped because the running node already has it
+    fn read_saved(&self, bytes: &[u8]) -> Option<SavedNode> {
+        let sn = if Vault::is_sealed(bytes) {
+            self.vault.open(bytes).ok().and_then(|x| SavedNode::read(&Zeroizing::new(x)).ok())
+        } else {
+            SavedNode::read(bytes).ok()
+        };
+        sn.map(|mut sn| {
+            sn.key.zeroize();
+            sn
+        })
+    }
 
-        let sn = bincode::deserialize::<SavedNode>(&buf).unwrap();
+    /// loads the node information from the decrypted saved node
+    fn load(storage: Arc<dyn Storage>, vault: Vault, buf: &[u8], inner: Node<Vec<u8>>, outer: Node<Vec<u8>>, gui_sender: channel::Sender<Vec<u8>>, gui_reciever: mpsc::Receiver<Vec<u8>>) -> io::Result<KhoraNode> {
+        let mut sn = SavedNode::read(buf).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "myNode doesn't hold a saved node"))?;
+
+        // if the node stopped in the middle of a block, put the files back in line with what was saved
+        match storage.recover(sn.bnum)? {
+            Recovery::Clean => {},
+            Recovery::RolledForward => println!("finished saving block {}", sn.bnum - 1),
+            Recovery::RolledBack => println!("undid the unfinished block after {}", sn.bnum),
+        }
 
         // tries to get back all the friends you may have lost since turning off the app
         let mut outer = outer;
         outer.dm(vec![], &sn.outer_view, true);
-        KhoraNode {
+        let mut node = KhoraNode {
             inner,
             outer,
             gui_sender,
@@ -427,6 +965,7 @@ impl KhoraNode {
             save_history: sn.save_history,
             me: sn.me,
             mine: sn.mine.clone(),
+            balance: Scalar::zero(),
             smine: sn.smine.clone(), // [location, amount]
             key: sn.key,
             keylocation: sn.keylocation.clone(),
@@ -438,7 +977,10 @@ impl KhoraNode {
             exitqueue: sn.exitqueue.clone(),
             comittee: sn.comittee.clone(),
             lastname: sn.lastname.clone(),
-            bloom: BloomFile::from_keys(sn.bloom[0],sn.bloom[1]),
+            bloom: BloomFile::from_keys(storage.clone(),sn.bloom[0],sn.bloom[1])?,
+            spent: SpentTags::new(storage.clone()),
+            history: History::with_cache(storage.clone(), HISTORY_CACHE),
+            storage,
             bnum: sn.bnum,
             lastbnum: sn.lastbnum,
             height: sn.height,
@@ -457,13 +999,25 @@ impl KhoraNode {
             sync_returnaddr: None,
             sync_theirnum: 0u64,
             sync_lightning: false,
+            pruned_peers: HashMap::new(),
+            gui_received: HashMap::new(),
             outs: None,
             oldstk: sn.oldstk,
             cumtime: sn.cumtime,
             blocktime: sn.blocktime,
             lightning_yielder: sn.lightning_yielder,
+            pruning: sn.pruning,
+            vault,
             gui_timer: Instant::now(),
-        }
+            later_generations: Generations::default(),
+            migrate_to: sn.migrate_to,
+        };
+        sn.key.zeroize();
+
+        // memos aren't saved so they're decrypted again
+        let me = node.me.clone();
+        node.mine.values_mut().filter(|x| x.has_memo_room()).for_each(|x| if let Ok(y) = me.read_ot(x) {*x = y;});
+        Ok(node)
     }
 
     /// reads a full block (by converting it to lightning then reading that)
@@ -473,8 +1027,107 @@ impl KhoraNode {
         self.readlightning(lastlightning,l,Some(m.clone()))
     }
 
+    /// takes the node back to before the block after bnum (using the undo records) so a competing block can be read in its place
+    fn rollback(&mut self, bnum: u64) -> bool {
+        let sn = match self.storage.rollback(bnum).ok().and_then(|x| self.read_saved(&x)) {
+            Some(sn) => sn,
+            None => {
+                println!("can't roll back to block {}", bnum);
+                return false
+            }
+        };
+        println!("rolled back from block {} to block {}", self.bnum, sn.bnum);
+        // undoing a panic (or a block a restore followed one in) goes back to the account from before it
+        if sn.me != self.me {
+            self.later_generations.rewind(&self.me, &sn.me);
+            self.set_account(sn.me);
+        }
+        self.mine = sn.mine;
+        self.smine = sn.smine;
+        self.keylocation = sn.keylocation;
+        self.leader = sn.leader;
+        self.overthrown = sn.overthrown;
+        self.votes = sn.votes;
+        self.stkinfo = sn.stkinfo;
+        self.queue = sn.queue;
+        self.exitqueue = sn.exitqueue;
+        self.comittee = sn.comittee;
+        self.lastname = sn.lastname;
+        self.bnum = sn.bnum;
+        self.lastbnum = sn.lastbnum;
+        self.height = sn.height;
+        self.sheight = sn.sheight;
+        self.alltagsever = sn.alltagsever;
+        self.headshard = sn.headshard;
+        self.is_validator = sn.is_validator;
+        self.moneyreset = sn.moneyreset;
+        self.oldstk = sn.oldstk;
+        self.cumtime = sn.cumtime;
+        self.blocktime = sn.blocktime;
+        self.sigs = vec![];
+        // the undone blocks' tags were taken out of the file so the filter has to be read again
+        self.bloom.reload().expect("couldn't read the bloom filter after rolling back");
+        self.history.clear_cache();
+
+        // the gui needs to forget about the money from the blocks that were undone
+        self.refresh_mine();
+        let mut mymoney = self.balance.as_bytes()[..8].to_vec();
+        mymoney.extend(self.smine.iter().map(|x| x[1]).sum::<u64>().to_le_bytes());
+        mymoney.push(0);
+        self.gui_sender.send(mymoney).expect("something's wrong with the communication to the gui");
+        self.send_received();
+        let mut thisbnum = self.bnum.to_le_bytes().to_vec();
+        thisbnum.push(2);
+        self.gui_sender.send(thisbnum).expect("something's wrong with the communication to the gui");
+        true
+    }
+
+    /// reads a block for a block number you already passed. if it isn't the block you read and more validators signed it, you roll back and read it instead
+    fn readfork(&mut self, lastlightning: LightningSyncBlock, m: Vec<u8>, largeblock: Option<Vec<u8>>) -> bool {
+        // you need your own copy of the block to know if this one is different
+        if !self.save_history {
+            return false
+        }
+        let ours = LightningSyncBlock::read(&*self.storage, &lastlightning.bnum).ok();
+        if ours.as_ref() == Some(&m) {
+            return false
+        }
+        let ourvalidators = ours.and_then(|x| bincode::deserialize::<LightningSyncBlock>(&x).ok()).map(|x| x.validators.len()).unwrap_or(0);
+        if lastlightning.validators.len() <= ourvalidators {
+            return false
+        }
+
+        // empty blocks you inferred are undone along with the block that came after them
+        let height = match self.storage.undo_heights().ok().and_then(|x| x.into_iter().filter(|&x| x <= lastlightning.bnum).max()) {
+            Some(x) => x,
+            None => return false,
+        };
+        let sn = match self.storage.undo_record(height).ok().and_then(|x| self.read_saved(&x.node)) {
+            Some(sn) => sn,
+            None => return false,
+        };
+
+        // the competing block has to be valid from where the chain was before it
+        if lastlightning.shards.is_empty() || (lastlightning.shards[0] as usize) < sn.headshard || lastlightning.last_name != sn.lastname {
+            return false
+        }
+        let com = match sn.comittee.get(lastlightning.shards[0] as usize) {
+            Some(x) => x.iter().map(|&y| y as u64).collect::<Vec<_>>(),
+            None => return false,
+        };
+        if lastlightning.verify(&com, &sn.stkinfo).is_err() {
+            return false
+        }
+
+        println!("-----------------------------------------------\nswitching to a competing block {}!\n-----------------------------------------------",lastlightning.bnum);
+        self.rollback(height) && self.readlightning(lastlightning, m, largeblock)
+    }
+
     /// reads a lightning block and saves information when appropriate
     fn readlightning(&mut self, lastlightning: LightningSyncBlock, m: Vec<u8>, largeblock: Option<Vec<u8>>) -> bool {
+        if lastlightning.bnum < self.bnum {
+            return self.readfork(lastlightning, m, largeblock)
+        }
         if lastlightning.bnum >= self.bnum {
             let com = self.comittee.par_iter().map(|x| x.par_iter().map(|y| *y as u64).collect::<Vec<_>>()).collect::<Vec<_>>();
             if lastlightning.shards.len() == 0 {
@@ -494,6 +1147,8 @@ impl KhoraNode {
             if v  {
                 // saves your current information BEFORE reading the new block. It's possible a leader is trying to cause a fork which can only be determined 1 block later based on what the comittee thinks is real
                 self.save();
+                // everything written for this block can be undone until the node is saved again at the end (and afterwards with the undo record)
+                self.storage.begin_block(self.bnum).expect("couldn't start the journal");
 
                 // if you are one of the validators who leave this turn, it is your responcibility to send the block to the outside world
                 if self.exitqueue[self.headshard].range(..REPLACERATE).map(|&x| self.comittee[self.headshard][x]).any(|x| self.keylocation.contains(&(x as u64))) {
@@ -525,9 +1180,9 @@ impl KhoraNode {
 
                     if self.save_history {
                         if !self.lightning_yielder {
-                            NextBlock::save(&vec![]);
+                            NextBlock::save(&*self.storage, &vec![]);
                         }
-                        LightningSyncBlock::save(&vec![]);
+                        LightningSyncBlock::save(&*self.storage, &vec![]);
                     }
 
                     // if you're panicing, the transaction you have saved may need to be updated based on if you gain or loose money
@@ -549,33 +1204,40 @@ impl KhoraNode {
                 // calculate the reward for this block as a function of the current time and scan either the block or an empty block based on conditions
                 let reward = reward(self.cumtime,self.blocktime);
                 if !(lastlightning.info.txout.is_empty() && lastlightning.info.stkin.is_empty() && lastlightning.info.stkout.is_empty()) {
+                    // a restore follows the account through its panics (a generation only gets money once the one
+                    // before it panicked) and stops at the first generation that never got any
+                    if self.later_generations.next().is_some_and(|next| lastlightning.info.txout.iter().any(|x| next.receive_ot(x).is_ok())) {
+                        let next = self.later_generations.advance(&self.me).unwrap();
+                        println!("found a panic, following the money to the next account");
+                        self.switch_account(next);
+                    }
                     let mut guitruster = !lastlightning.scanstk(&self.me, &mut self.smine, &mut self.sheight, &self.comittee, reward, &self.stkinfo);
                     guitruster = !lastlightning.scan(&self.me, &mut self.mine, &mut self.height, &mut self.alltagsever) && guitruster;
+                    self.refresh_mine();
                     self.gui_sender.send(vec![guitruster as u8,1]).expect("there's a problem communicating to the gui!");
 
                     if self.save_history {
                         println!("saving block...");
-                        lastlightning.update_bloom(&mut self.bloom,&self.is_validator);
+                        lastlightning.update_bloom(&*self.storage,&self.bloom);
+                        lastlightning.save_tags(&self.spent);
                         if !self.lightning_yielder {
-                            NextBlock::save(&largeblock.unwrap()); // important! if you select to recieve full blocks you CAN NOT recieve with lightning blocks (because if you do youd miss full blocks)
+                            NextBlock::save(&*self.storage, &largeblock.unwrap()); // important! if you select to recieve full blocks you CAN NOT recieve with lightning blocks (because if you do youd miss full blocks)
                         }
-                        LightningSyncBlock::save(&m);
+                        LightningSyncBlock::save(&*self.storage, &m);
                     }
                     self.keylocation = self.smine.iter().map(|x| x[0]).collect();
-                    lastlightning.scan_as_noone(&mut self.stkinfo, &mut self.queue, &mut self.exitqueue, &mut self.comittee, reward, self.save_history);
+                    lastlightning.scan_as_noone(&mut self.stkinfo, &mut self.queue, &mut self.exitqueue, &mut self.comittee, reward, self.save_history, &self.history);
 
                     self.lastbnum = self.bnum;
-                    let mut hasher = Sha3_512::new();
-                    hasher.update(m);
-                    self.lastname = Scalar::from_hash(hasher).as_bytes().to_vec();
+                    self.lastname = lastlightning.name();
                 } else {
                     self.gui_sender.send(vec![!NextBlock::pay_self_empty(&self.headshard, &self.comittee, &mut self.smine, reward) as u8,1]).expect("there's a problem communicating to the gui!");
                     NextBlock::pay_all_empty(&self.headshard, &mut self.comittee, &mut self.stkinfo, reward);
                     if self.save_history {
                         if !self.lightning_yielder {
-                            NextBlock::save(&vec![]);
+                            NextBlock::save(&*self.storage, &vec![]);
                         }
-                        LightningSyncBlock::save(&vec![]);
+                        LightningSyncBlock::save(&*self.storage, &vec![]);
                     }
                 }
                 self.votes[self.exitqueue[self.headshard][0]] = 0; self.votes[self.exitqueue[self.headshard][1]] = 0;
@@ -621,12 +1283,13 @@ impl KhoraNode {
 
 
                 // send info to the gui
-                let mut mymoney = self.mine.iter().map(|x| self.me.receive_ot(&x.1).unwrap().com.amount.unwrap()).sum::<Scalar>().as_bytes()[..8].to_vec();
+                let mut mymoney = self.balance.as_bytes()[..8].to_vec();
                 mymoney.extend(self.smine.iter().map(|x| x[1]).sum::<u64>().to_le_bytes());
                 mymoney.push(0);
-                println!("my money:\n---------------------------------\n{:?}",self.mine.iter().map(|x| self.me.receive_ot(&x.1).unwrap().com.amount.unwrap()).sum::<Scalar>());
+                println!("my money:\n---------------------------------\n{:?}",self.balance);
                 println!("my stake:\n---------------------------------\n{:?}",self.smine.iter().map(|x| x[1]).sum::<u64>().to_le_bytes());
                 self.gui_sender.send(mymoney).expect("something's wrong with the communication to the gui"); // this is how you send info to the gui
+                self.send_received();
                 let mut thisbnum = self.bnum.to_le_bytes().to_vec();
                 thisbnum.push(2);
                 self.gui_sender.send(thisbnum).expect("something's wrong with the communication to the gui"); // this is how you send info to the gui
@@ -642,19 +1305,23 @@ impl KhoraNode {
                 if self.save_history {
                     let s = self.stkinfo.borrow();
                     let bloom = self.bloom.borrow();
+                    let spent = self.spent.borrow();
+                    let history = self.history.borrow();
                     println!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!\nhad {} tx",self.txses.len());
                     self.txses = self.txses.iter().collect::<HashSet<_>>().into_iter().cloned().collect::<Vec<_>>();
-                    self.txses.retain(|x| {
-                        if let Ok(x) = bincode::deserialize::<PolynomialTransaction>(x) {
-                            if x.inputs.last() == Some(&1) {
-                                x.verifystk(s).is_ok()
-                            } else {
-                                x.tags.iter().all(|x| !bloom.contains(x.as_bytes())) && x.verify().is_ok()
-                            }
-                        } else {
-                            false
-                        }
-                    });
+                    let txs = self.txses.iter().map(|x| bincode::deserialize::<PolynomialTransaction>(x).ok()).collect::<Vec<_>>();
+                    let unspent = txs.iter().map(|x| match x {
+                        Some(x) if x.inputs.last() != Some(&1) => !spent_tags(spent, bloom, &x.tags).contains(&true),
+                        _ => false,
+                    }).collect::<Vec<_>>();
+                    let polys = txs.iter().zip(&unspent).filter_map(|(x,&u)| if u {x.as_ref()} else {None}).collect::<Vec<_>>();
+                    let mut verified = PolynomialTransaction::verify_batch(&polys, history).into_iter();
+                    let mut keep = txs.iter().zip(unspent).map(|(x,u)| match x {
+                        Some(x) if x.inputs.last() == Some(&1) => x.verifystk(s).is_ok(),
+                        Some(_) => u && verified.next() == Some(Ok(())),
+                        None => false,
+                    }).collect::<Vec<_>>().into_iter();
+                    self.txses.retain(|_| keep.next().unwrap());
                 } else {
                     self.txses = vec![];
                 }
@@ -737,6 +1404,15 @@ impl KhoraNode {
                     }
                 });
 
+                // the block is only finished once the node is saved
+                self.save();
+
+                // pruned nodes only keep the most recent full blocks
+                if self.pruning && self.bnum > FULL_BLOCK_WINDOW && self.bnum % PRUNE_INTERVAL == 0 {
+                    if let Err(e) = self.storage.prune_blocks(BlockKind::Full, self.bnum - FULL_BLOCK_WINDOW) {
+                        println!("couldn't prune the full blocks: {}", e);
+                    }
+                }
                 return true
             }
         }
@@ -900,7 +1576,7 @@ impl Future for KhoraNode {
                                         ).collect::<Vec<PolynomialTransaction>>();
 
                                         for keylocation in &self.keylocation {
-                                            let m = NextBlock::valicreate(&self.key, &keylocation, &self.leader, &m, &(self.headshard as u16), &self.bnum, &self.lastname, &self.bloom, &self.stkinfo);
+                                            let m = NextBlock::valicreate(&self.key, &keylocation, &self.leader, &m, &(self.headshard as u16), &self.bnum, &self.lastname, &self.bloom, &self.spent, &self.history, &self.stkinfo);
                                             println!("{:?}",m.txs.len());
                                             let mut m = bincode::serialize(&m).unwrap();
                                             m.push(2);
@@ -1020,7 +1696,7 @@ impl Future for KhoraNode {
                 if self.waitingforentrybool && (self.waitingforentrytime.elapsed().as_secs() > (0.66*self.blocktime) as u64) {
                     self.waitingforentrybool = false;
                     for keylocation in &self.keylocation {
-                        let m = NextBlock::valicreate(&self.key, &keylocation, &self.leader, &vec![], &(self.headshard as u16), &self.bnum, &self.lastname, &self.bloom, &self.stkinfo);
+                        let m = NextBlock::valicreate(&self.key, &keylocation, &self.leader, &vec![], &(self.headshard as u16), &self.bnum, &self.lastname, &self.bloom, &self.spent, &self.history, &self.stkinfo);
                         println!("trying to make an empty block...");
                         let mut m = bincode::serialize(&m).unwrap();
                         m.push(2);
@@ -1048,7 +1724,7 @@ impl Future for KhoraNode {
                     while self.sync_theirnum <= self.bnum {
                         println!("checking for file location for {}...",self.sync_theirnum);
                         if self.sync_lightning {
-                            if let Ok(mut x) = LightningSyncBlock::read(&self.sync_theirnum) {
+                            if let Ok(mut x) = LightningSyncBlock::read(&*self.storage, &self.sync_theirnum) {
                                 x.push(3);
                                 self.outer.dm(x,&vec![addr],false);
                                 self.sync_theirnum += 1;
@@ -1057,7 +1733,7 @@ impl Future for KhoraNode {
                                 self.sync_theirnum += 1;
                             }
                         } else {
-                            if let Ok(mut x) = NextBlock::read(&self.sync_theirnum) {
+                            if let Ok(mut x) = NextBlock::read(&*self.storage, &self.sync_theirnum) {
                                 x.push(3);
                                 self.outer.dm(x,&vec![addr],false);
                                 self.sync_theirnum += 1;
@@ -1088,7 +1764,7 @@ impl Future for KhoraNode {
                                             t.verifystk(&self.stkinfo).is_ok()
                                         } else {
                                             let bloom = self.bloom.borrow();
-                                            t.tags.iter().all(|y| !bloom.contains(y.as_bytes())) && t.verify().is_ok()
+                                            !spent_tags(&self.spent, bloom, &t.tags).contains(&true) && t.verify(&self.history).is_ok()
                                         }
                                     };
                                     if ok {
@@ -1110,26 +1786,14 @@ impl Future for KhoraNode {
                                 self.outer.handle_gossip_now(fullmsg, false);
                             }
                         } else if mtype == 60 /* < */ { // redo sync request
-                            let mut mynum = self.bnum.to_le_bytes().to_vec();
-                            if self.lightning_yielder { // lightning users don't ask for full blocks
-                                mynum.push(108); //l
-                            } else {
-                                mynum.push(102); //f
-                            }
-                            mynum.push(121);
+                            let mynum = self.sync_request();
                             if let Ok(x) = bincode::deserialize(&m) {
                                 self.outer.dm(mynum, &[x], false);
+                            } else if let Some(friend) = self.sync_helper(&[msg.id.node()]) {
+                                println!("asking for help from {:?}",friend);
+                                self.outer.dm(mynum, &[friend], false);
                             } else {
-                                let mut friend = self.outer.plumtree_node().all_push_peers();
-                                friend.remove(&msg.id.node());
-                                friend.remove(self.outer.plumtree_node().id());
-                                let friend = friend.into_iter().collect::<Vec<_>>();
-                                if let Some(friend) = friend.choose(&mut rand::thread_rng()) {
-                                    println!("asking for help from {:?}",friend);
-                                    self.outer.dm(mynum, &[*friend], false);
-                                } else {
-                                    println!("you're isolated");
-                                }
+                                println!("you're isolated");
                             }
                         } else if mtype == 97 /* a */ {
                             self.outer.plumtree_node.lazy_push_peers.insert(fullmsg.sender);
@@ -1139,14 +1803,31 @@ impl Future for KhoraNode {
                                     self.readlightning(lastblock, m, None); // that whole thing with 3 and 8 makes it super unlikely to get more blocks (expecially for my small thing?)
                                 }
                             }
+                        } else if mtype == 112 /* p */ { // a pruned node telling you the first full block it still has
+                            if let Ok(x) = m.try_into() {
+                                let pruned = u64::from_le_bytes(x);
+                                println!("{:?} only has full blocks from block {} on", msg.id.node(), pruned);
+                                self.pruned_peers.insert(msg.id.node(), pruned);
+                                // they can't send the full blocks you're missing so ask someone who can
+                                if !self.lightning_yielder && pruned > self.bnum {
+                                    if let Some(friend) = self.sync_helper(&[msg.id.node()]) {
+                                        println!("asking for help from {:?}",friend);
+                                        self.outer.dm(self.sync_request(), &[friend], false);
+                                    } else {
+                                        println!("none of your peers have the full blocks you need");
+                                    }
+                                }
+                            }
                         } else if mtype == 113 /* q */ { // they just sent you a ring member
                             self.rmems.insert(u64::from_le_bytes(m[64..72].try_into().unwrap()),History::read_raw(&m));
                         } else if mtype == 114 /* r */ { // answer their ring question
                             let mut y = m[..8].to_vec();
-                            let mut x = History::get_raw(&u64::from_le_bytes(y.clone().try_into().unwrap())).to_vec();
-                            x.append(&mut y);
-                            x.push(113);
-                            self.outer.dm(x,&vec![msg.id.node()],false);
+                            if let Ok(a) = self.history.get_accounts(&[u64::from_le_bytes(y.clone().try_into().unwrap())]) {
+                                let mut x = [a[0].pk.compress().to_bytes(),a[0].com.com.compress().to_bytes()].concat();
+                                x.append(&mut y);
+                                x.push(113);
+                                self.outer.dm(x,&vec![msg.id.node()],false);
+                            }
                         } else if mtype == 118 /* v */ { // someone announcing they're about to be in the comittee
                             if let Some(who) = Signature::recieve_signed_message(&mut m, &self.stkinfo) {
                                 if let Ok(m) = bincode::deserialize::<NodeId>(&m) {
@@ -1164,6 +1845,8 @@ impl Future for KhoraNode {
                             }
                         } else if mtype == 121 /* y */ { // someone sent a sync request
                             let mut i_cant_do_this = true;
+                            // pruned nodes can only send the full blocks they still have
+                            let pruned = self.storage.pruned_at(BlockKind::Full).map(|x| x.0).unwrap_or(0);
                             if self.save_history {
                                 if self.sync_returnaddr.is_none() {
                                     if let Some(theyfast) = m.pop() {
@@ -1174,7 +1857,7 @@ impl Future for KhoraNode {
                                                 self.sync_theirnum = u64::from_le_bytes(m);
                                                 i_cant_do_this = false;
                                             } else {
-                                                if !self.lightning_yielder {
+                                                if !self.lightning_yielder && u64::from_le_bytes(m) >= pruned {
                                                     self.sync_lightning = false;
                                                     self.sync_returnaddr = Some(msg.id.node());
                                                     self.sync_theirnum = u64::from_le_bytes(m);
@@ -1185,6 +1868,12 @@ impl Future for KhoraNode {
                                     }
                                 }
                             }
+                            // let them know which full blocks you can send
+                            if self.pruning && msg.id.node() != *self.outer.plumtree_node().id() {
+                                let mut p = pruned.to_le_bytes().to_vec();
+                                p.push(112);
+                                self.outer.dm(p, &[msg.id.node()], false);
+                            }
                             if msg.id.node() != *self.outer.plumtree_node().id() && i_cant_do_this {
                                 let mut friend = self.outer.plumtree_node().all_push_peers().into_iter().collect::<Vec<_>>();
                                 friend.retain(|&x| x != fullmsg.sender);
@@ -1252,13 +1941,19 @@ impl Future for KhoraNode {
             }
             // interacting with the gui
             while let Async::Ready(Some(mut m)) = self.gui_reciever.poll().expect("Never fails") {
-                println!("got message from gui!\n{}",String::from_utf8_lossy(&m));
+                println!("got message from gui!"); // it can hold a login
                 if let Some(istx) = m.pop() {
                     let mut validtx = true;
-                    if istx == 33 /* ! */ { // a transaction
+                    if self.me.is_watch_only() && (istx == 33 || istx == u8::MAX) {
+                        println!("a watch-only node can't make transactions");
+                    } else if istx == 33 /* ! */ { // a transaction
                         let txtype = m.pop().unwrap();
                         let mut outs = vec![];
                         while m.len() > 0 {
+                            let subaddress = m[0] == SUBADDRESS_MARK as u8;
+                            if subaddress {
+                                m.remove(0);
+                            }
                             let mut pks = vec![];
                             for _ in 0..3 { // read the pk address
                                 if m.len() >= 64 {
@@ -1275,27 +1970,26 @@ impl Future for KhoraNode {
                                     validtx = false;
                                 }
                             }
+                            let mut recv = Account::from_pks(&pks[0], &pks[1], &pks[2]);
+                            recv.subaddress = subaddress;
                             if m.len() >= 8 {
                                 if let Ok(x) = m.drain(..8).collect::<Vec<_>>().try_into() {
                                     let x = u64::from_le_bytes(x);
                                     println!("amounts {:?}",x);
                                     let y = x/2u64.pow(BETA as u32) + 1;
                                     println!("need to split this up into {} txses!",y);
-                                    let recv = Account::from_pks(&pks[0], &pks[1], &pks[2]);
                                     for _ in 0..y {
                                         let amnt = Scalar::from(x/y);
-                                        outs.push((recv,amnt));
+                                        outs.push((recv.clone(),amnt));
                                     }
                                 } else {
-                                    let recv = Account::from_pks(&pks[0], &pks[1], &pks[2]);
                                     let amnt = Scalar::zero();
-                                    outs.push((recv,amnt));
+                                    outs.push((recv.clone(),amnt));
                                     validtx = false;
                                 }
                             } else {
-                                let recv = Account::from_pks(&pks[0], &pks[1], &pks[2]);
                                 let amnt = Scalar::zero();
-                                outs.push((recv,amnt));
+                                outs.push((recv.clone(),amnt));
                                 validtx = false;
                             }
                         }
@@ -1335,8 +2029,7 @@ impl Future for KhoraNode {
                                 println!("ring: {:?}",ring);
                                 println!("mine: {:?}",acc.iter().map(|x|x.pk.compress()).collect::<Vec<_>>());
                                 // println!("ring: {:?}",ring.iter().map(|x|OTAccount::summon_ota(&History::get(&x)).pk.compress()).collect::<Vec<_>>());
-                                let mut rlring = ring.into_iter().map(|x| {
-                                    let x = OTAccount::summon_ota(&History::get(&x));
+                                let mut rlring = self.history.get_accounts(&ring).expect("the ring should be in the history").into_iter().map(|x| {
                                     if acc.iter().all(|a| a.pk != x.pk) {
                                         println!("not mine!");
                                         x
@@ -1346,11 +2039,11 @@ impl Future for KhoraNode {
                                     }
                                 }).collect::<Vec<OTAccount>>();
                                 println!("ring len: {:?}",rlring.len());
-                                let me = self.me;
+                                let me = self.me.clone();
                                 rlring.iter_mut().for_each(|x|if let Ok(y)=me.receive_ot(&x) {*x = y;});
                                 let tx = Transaction::spend_ring(&rlring, &outs.par_iter().map(|x|(&x.0,&x.1)).collect::<Vec<(&Account,&Scalar)>>());
                                 let tx = tx.polyform(&rname);
-                                if tx.verify().is_ok() {
+                                if tx.verify(&self.history).is_ok() {
                                     txbin = bincode::serialize(&tx).unwrap();
                                     println!("transaction made!");
                                 } else {
@@ -1398,7 +2091,28 @@ impl Future for KhoraNode {
                         if stkamnt == amnt {
                             stkamnt -= 1;
                         }
-                        let newacc = Account::new(&format!("{}",String::from_utf8_lossy(&m)));
+                        // the byte after the amounts says where the money goes
+                        let newacc = if m.pop() == Some(1) {
+                            // the hardened login's account the node got when it was made with an old login
+                            match self.migrate_to.take() {
+                                Some(acc) => acc,
+                                None => {
+                                    println!("this node wasn't made with an old login");
+                                    continue
+                                }
+                            }
+                        } else {
+                            // otherwise it's the login and how many times it panicked. the next generation comes from the
+                            // login so whoever has this account's keys can't find it
+                            let generation = u32::from_le_bytes(m.split_off(32).try_into().unwrap());
+                            if Account::from_seed_generation(&m, generation) != self.me {
+                                m.zeroize();
+                                println!("that login and number of panics doesn't open this node's account");
+                                continue
+                            }
+                            Account::from_seed_generation(&m, generation + 1)
+                        };
+                        m.zeroize();
 
                         // send unstaked money
                         if self.mine.len() > 0 {
@@ -1411,7 +2125,7 @@ impl Future for KhoraNode {
                             println!("made rings");
                             /* you don't use a ring for panics (the ring is just your own accounts) */ 
                             let mut rlring = ring.iter().map(|&x| self.mine.iter().filter(|(&y,_)| y == x).collect::<Vec<_>>()[0].1.clone()).collect::<Vec<OTAccount>>();
-                            let me = self.me;
+                            let me = self.me.clone();
                             rlring.iter_mut().for_each(|x|if let Ok(y)=me.receive_ot(&x) {*x = y;});
                             
                             let mut outs = vec![];
@@ -1427,7 +2141,7 @@ impl Future for KhoraNode {
                             if tx.verify().is_ok() {
                                 let tx = tx.polyform(&rname);
                                 if self.save_history {
-                                    tx.verify().unwrap(); // as a user you won't be able to check this
+                                    tx.verify(&self.history).unwrap(); // as a user you won't be able to check this
                                 }
                                 let mut txbin = bincode::serialize(&tx).unwrap();
                                 self.txses.push(txbin.clone());
@@ -1472,35 +2186,9 @@ impl Future for KhoraNode {
                         }
 
 
-                        self.mine = HashMap::new();
-                        self.smine = vec![];
-                        self.me = newacc;
-                        self.key = self.me.stake_acc().receive_ot(&self.me.stake_acc().derive_stk_ot(&Scalar::from(1u8))).unwrap().sk.unwrap();
-                        self.keylocation = HashSet::new();
-                        let mut m1 = self.me.name().as_bytes().to_vec();
-                        m1.extend([0,u8::MAX]);
-                        let mut m2 = self.me.stake_acc().name().as_bytes().to_vec();
-                        m2.extend([1,u8::MAX]);
-                        let mut m3 = self.me.sk.as_bytes().to_vec();
-                        m3.extend([2,u8::MAX]);
-                        let mut m4 = self.me.vsk.as_bytes().to_vec();
-                        m4.extend([3,u8::MAX]);
-                        let mut m5 = self.me.ask.as_bytes().to_vec();
-                        m5.extend([4,u8::MAX]);
-                        self.gui_sender.send(m1).expect("should be working");
-                        self.gui_sender.send(m2).expect("should be working");
-                        self.gui_sender.send(m3).expect("should be working");
-                        self.gui_sender.send(m4).expect("should be working");
-                        self.gui_sender.send(m5).expect("should be working");
+                        self.switch_account(newacc);
 
                     } else if istx == 121 /* y */ { // you clicked sync
-                        let mut mynum = self.bnum.to_le_bytes().to_vec();
-                        if self.lightning_yielder {
-                            mynum.push(108); //l
-                        } else {
-                            mynum.push(102); //f
-                        }
-                        mynum.push(121);
                         let mut friend = self.outer.plumtree_node().all_push_peers();
                         friend.remove(self.outer.plumtree_node().id());
                         println!("{:?}",friend);
@@ -1509,9 +2197,9 @@ impl Future for KhoraNode {
                         let mut gm = (friend.len() as u16).to_le_bytes().to_vec();
                         gm.push(4);
                         self.gui_sender.send(gm).expect("should be working");
-                        if let Some(friend) = friend.choose(&mut rand::thread_rng()) {
+                        if let Some(friend) = self.sync_helper(&[]) {
                             println!("asking for help from {:?}",friend);
-                            self.outer.dm(mynum, &[*friend], false);
+                            self.outer.dm(self.sync_request(), &[friend], false);
                         } else {
                             println!("you're isolated");
                         }
@@ -0,0 +1,371 @@
+//! checks that the blocks a node saved are consistent with each other and rebuilds what can be rebuilt from them
+//!
+//! every stored block is read through its metadata, deserialized, and checked against the block number it's saved at
+//! and the name of the block before it. the metadata, history and bloom filter can all be recreated from the raw block files.
+use bincode::Options;
+use curve25519_dalek::scalar::Scalar;
+use std::convert::TryInto;
+use std::sync::Arc;
+use crate::bloom::BloomFile;
+use crate::storage::{Storage, BlockStore, BlockKind, HistoryStore};
+use crate::validation::{History, LightningSyncBlock, NextBlock};
+
+/// what was found while checking the block files
+#[derive(Clone, Default, Debug)]
+pub struct Report {
+    /// the number of blocks in the metadata (including empty blocks)
+    pub height: u64,
+    /// the number of full blocks that aren't empty
+    pub full_blocks: u64,
+    /// the number of lightning blocks that aren't empty
+    pub lightning_blocks: u64,
+    /// the number of OTAccounts in the blocks
+    pub outputs: u64,
+    /// everything that's wrong
+    pub problems: Vec<String>,
+}
+
+impl Report {
+    /// tells you if nothing is wrong
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// the name of the block before the first block
+pub fn first_name() -> Vec<u8> {
+    Scalar::one().as_bytes().to_vec()
+}
+
+/// reads the end offset of every block from the metadata
+fn offsets(storage: &dyn Storage, kind: BlockKind, report: &mut Report) -> Option<Vec<u64>> {
+    let meta = match storage.read_all(kind.metadata()) {
+        Ok(x) => x,
+        Err(_) => {
+            report.problems.push(format!("{} is missing", kind.metadata()));
+            return None
+        }
+    };
+    if meta.len() % 8 != 0 {
+        report.problems.push(format!("{} has {} extra bytes", kind.metadata(), meta.len() % 8));
+    }
+    let offsets = meta.chunks_exact(8).map(|x| u64::from_le_bytes(x.try_into().unwrap())).collect::<Vec<_>>();
+    if offsets.first() != Some(&0) {
+        report.problems.push(format!("{} doesn't start at 0", kind.metadata()));
+    }
+    if let Some(i) = offsets.windows(2).position(|x| x[1] < x[0]) {
+        report.problems.push(format!("{} goes backwards at block {}", kind.metadata(), i));
+    }
+    let len = storage.blocks_len(kind).unwrap_or(0);
+    match offsets.last() {
+        Some(&end) if end > len => report.problems.push(format!("{} points past the end of {} ({} > {})", kind.metadata(), kind.data(), end, len)),
+        Some(&end) if end < len => report.problems.push(format!("{} has {} bytes that aren't in {}", kind.data(), len - end, kind.metadata())),
+        _ => {},
+    }
+    Some(offsets)
+}
+
+/// walks every block and checks offsets, block numbers, the chain of names, the history and (if you have its keys) the bloom filter
+pub fn check(storage: &dyn Storage, bloom: Option<&BloomFile>) -> Report {
+    let mut report = Report::default();
+
+    let mut names = vec![];
+    if let Some(lightning) = offsets(storage, BlockKind::Lightning, &mut report) {
+        report.height = lightning.len().saturating_sub(1) as u64;
+        let mut name = first_name();
+        let mut outputs = 0u64;
+        for bnum in 0..report.height {
+            names.push(name.clone());
+            let bytes = match storage.read_block(BlockKind::Lightning, &bnum) {
+                Ok(x) => x,
+                Err("We skipped that block") | Err("We pruned that block") => continue,
+                Err(e) => {
+                    report.problems.push(format!("lightning block {}: {}", bnum, e));
+                    continue
+                }
+            };
+            let block = match bincode::deserialize::<LightningSyncBlock>(&bytes) {
+                Ok(x) => x,
+                Err(_) => {
+                    report.problems.push(format!("lightning block {} can't be read", bnum));
+                    continue
+                }
+            };
+            report.lightning_blocks += 1;
+            if block.bnum != bnum {
+                report.problems.push(format!("lightning block {} says it's block {}", bnum, block.bnum));
+            }
+            if block.last_name != name {
+                report.problems.push(format!("lightning block {} doesn't follow the block before it", bnum));
+            }
+            name = block.name();
+
+            for acc in block.info.txout.iter() {
+                match storage.history_get(&outputs) {
+                    Ok(x) if x[..32] == acc.pk.compress().as_bytes()[..] && x[32..] == acc.com.com.compress().as_bytes()[..] => {},
+                    _ => report.problems.push(format!("history entry {} doesn't match lightning block {}", outputs, bnum)),
+                }
+                outputs += 1;
+            }
+            if let Some(bloom) = bloom {
+                if bloom.contains_batch(&block.info.tags.iter().map(|x| x.as_bytes()).collect::<Vec<_>>()).contains(&false) {
+                    report.problems.push(format!("the bloom filter is missing tags from lightning block {}", bnum));
+                }
+            }
+        }
+        report.outputs = outputs;
+        match storage.history_len() {
+            Ok(x) if x != outputs => report.problems.push(format!("the history has {} entries but the blocks have {} outputs", x, outputs)),
+            Err(_) => report.problems.push("the history is missing".to_string()),
+            _ => {},
+        }
+    }
+
+    // nodes that only keep lightning blocks don't have full blocks
+    if storage.exists(BlockKind::Full.metadata()) {
+        if let Some(full) = offsets(storage, BlockKind::Full, &mut report) {
+            let height = full.len().saturating_sub(1) as u64;
+            if height != report.height {
+                report.problems.push(format!("there are {} full blocks but {} lightning blocks", height, report.height));
+            }
+            for bnum in 0..height {
+                let bytes = match storage.read_block(BlockKind::Full, &bnum) {
+                    Ok(x) => x,
+                    Err("We pruned that block") => continue,
+                    Err("We skipped that block") => {
+                        if storage.read_block(BlockKind::Lightning, &bnum).is_ok() {
+                            report.problems.push(format!("full block {} is empty but its lightning block isn't", bnum));
+                        }
+                        continue
+                    }
+                    Err(e) => {
+                        report.problems.push(format!("full block {}: {}", bnum, e));
+                        continue
+                    }
+                };
+                let block = match bincode::deserialize::<NextBlock>(&bytes) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        report.problems.push(format!("full block {} can't be read", bnum));
+                        continue
+                    }
+                };
+                report.full_blocks += 1;
+                if block.bnum != bnum {
+                    report.problems.push(format!("full block {} says it's block {}", bnum, block.bnum));
+                }
+                if names.get(bnum as usize) != Some(&block.last_name) {
+                    report.problems.push(format!("full block {} doesn't follow the block before it", bnum));
+                }
+                if storage.read_block(BlockKind::Lightning, &bnum) != Ok(bincode::serialize(&block.tolightning()).unwrap()) {
+                    report.problems.push(format!("full block {} doesn't match its lightning block", bnum));
+                }
+            }
+        }
+    }
+    report
+}
+
+/// recreates the metadata by reading the blocks one after another out of the block file
+/// empty blocks are filled in from gaps in the block numbers and the end is padded with empty blocks up to height
+/// if the end of the block file can't be read it's cut off. returns the number of blocks in the new metadata
+pub fn rebuild_metadata(storage: &dyn Storage, kind: BlockKind, height: u64) -> Result<u64, &'static str> {
+    let data = storage.read_all(kind.data()).map_err(|_| "the block file is missing")?;
+    // pruned blocks are left as empty blocks before where the data file starts
+    let (_, cut) = storage.pruned_at(kind).map_err(|_| "the pruning file is corrupted")?;
+    let mut metadata = 0u64.to_le_bytes().to_vec();
+    let mut blocks = 0u64;
+    let mut end = 0usize;
+    while end < data.len() {
+        let mut rest = &data[end..];
+        let bnum = match kind {
+            BlockKind::Full => bincode::options().with_fixint_encoding().allow_trailing_bytes().with_limit(rest.len() as u64)
+                .deserialize_from::<_, NextBlock>(&mut rest).map(|x| x.bnum),
+            BlockKind::Lightning => bincode::options().with_fixint_encoding().allow_trailing_bytes().with_limit(rest.len() as u64)
+                .deserialize_from::<_, LightningSyncBlock>(&mut rest).map(|x| x.bnum),
+        };
+        let bnum = match bnum {
+            Ok(x) if x >= blocks => x,
+            _ => break,
+        };
+        for _ in blocks..bnum {
+            metadata.extend(&(end as u64 + cut).to_le_bytes());
+        }
+        end = data.len() - rest.len();
+        metadata.extend(&(end as u64 + cut).to_le_bytes());
+        blocks = bnum + 1;
+    }
+    if end < data.len() {
+        storage.truncate(kind.data(), end as u64).map_err(|_| "couldn't cut off the end of the block file")?;
+    }
+    for _ in blocks..height {
+        metadata.extend(&(end as u64 + cut).to_le_bytes());
+    }
+    storage.write_all(kind.metadata(), &metadata).map_err(|_| "couldn't write the metadata")?;
+    Ok(std::cmp::max(blocks, height))
+}
+
+/// recreates the history from the outputs in the lightning blocks
+pub fn rebuild_history(storage: Arc<dyn Storage>) -> Result<u64, &'static str> {
+    let history = History::new(storage.clone());
+    history.initialize();
+    let mut outputs = 0u64;
+    for bnum in 0..block_count(&*storage, BlockKind::Lightning) {
+        if let Ok(bytes) = storage.read_block(BlockKind::Lightning, &bnum) {
+            let block = bincode::deserialize::<LightningSyncBlock>(&bytes).map_err(|_| "a lightning block can't be read")?;
+            history.append(&block.info.txout);
+            outputs += block.info.txout.len() as u64;
+        }
+    }
+    Ok(outputs)
+}
+
+/// recreates the bloom filter from the tags in the lightning blocks
+pub fn rebuild_bloom(storage: &dyn Storage, bloom: &BloomFile) -> Result<u64, &'static str> {
+    let (num_bits, num_hashes) = bloom.params();
+    BloomFile::initialize_sized(storage, num_bits, num_hashes);
+    bloom.reload().map_err(|_| "the new bloom file can't be read")?;
+    let mut tags = 0u64;
+    for bnum in 0..block_count(storage, BlockKind::Lightning) {
+        if let Ok(bytes) = storage.read_block(BlockKind::Lightning, &bnum) {
+            let block = bincode::deserialize::<LightningSyncBlock>(&bytes).map_err(|_| "a lightning block can't be read")?;
+            bloom.insert_batch(&block.info.tags.iter().map(|x| x.as_bytes()).collect::<Vec<_>>());
+            tags += block.info.tags.len() as u64;
+        }
+    }
+    Ok(tags)
+}
+
+/// the number of blocks in the metadata
+fn block_count(storage: &dyn Storage, kind: BlockKind) -> u64 {
+    storage.len(kind.metadata()).map(|x| (x/8).saturating_sub(1)).unwrap_or(0)
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Account;
+    use crate::storage::MemoryStorage;
+    use curve25519_dalek::ristretto::RistrettoPoint;
+
+    /// saves 5 blocks (2 empty) and returns the bloom filter
+    /// blocks with outputs are only saved as lightning blocks because full blocks need real transactions to have outputs
+    fn chain(storage: &Arc<dyn Storage>, outputs: bool) -> BloomFile {
+        if !outputs {
+            storage.initialize_blocks(BlockKind::Full).unwrap();
+        }
+        storage.initialize_blocks(BlockKind::Lightning).unwrap();
+        History::new(storage.clone()).initialize();
+        BloomFile::initialize_sized(&**storage, 8192, 4);
+        let bloom = BloomFile::from_randomness(storage.clone()).unwrap();
+
+        let acc = Account::new(&"blocks");
+        let mut name = first_name();
+        for bnum in 0..5u64 {
+            if bnum % 2 == 1 {
+                if !outputs {
+                    storage.save_block(BlockKind::Full, &[]).unwrap();
+                }
+                storage.save_block(BlockKind::Lightning, &[]).unwrap();
+                continue
+            }
+            let mut full = NextBlock::default();
+            full.bnum = bnum;
+            full.last_name = name.clone();
+            let mut lightning = full.tolightning();
+            if outputs {
+                lightning.info.txout = vec![acc.derive_ot(&Scalar::from(bnum)), acc.derive_ot(&Scalar::one())];
+                lightning.info.tags = vec![RistrettoPoint::random(&mut rand::thread_rng()).compress()];
+                History::new(storage.clone()).append(&lightning.info.txout);
+                lightning.info.tags.iter().for_each(|x| bloom.insert(x.as_bytes()));
+            } else {
+                storage.save_block(BlockKind::Full, &bincode::serialize(&full).unwrap()).unwrap();
+            }
+            let l = bincode::serialize(&lightning).unwrap();
+            name = lightning.name();
+            storage.save_block(BlockKind::Lightning, &l).unwrap();
+        }
+        bloom
+    }
+
+    #[test]
+    fn consistent_blocks() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let bloom = chain(&storage, true);
+        let report = check(&*storage, Some(&bloom));
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.height, 5);
+        assert_eq!(report.full_blocks, 0);
+        assert_eq!(report.lightning_blocks, 3);
+        assert_eq!(report.outputs, 6);
+
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        chain(&storage, false);
+        let report = check(&*storage, None);
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.full_blocks, 3);
+        assert_eq!(report.lightning_blocks, 3);
+    }
+
+    #[test]
+    fn finds_problems() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        chain(&storage, false);
+        storage.write_at(BlockKind::Lightning.metadata(), 8, &[0u8; 8]).unwrap();
+        storage.history_append(&[0u8; 64]).unwrap();
+        storage.append(BlockKind::Full.data(), &[1]).unwrap();
+        let report = check(&*storage, None);
+        assert!(report.problems.iter().any(|x| x.contains("lightning block 1 says it's block 0")), "{:?}", report.problems);
+        assert!(report.problems.iter().any(|x| x.contains("full block 0 doesn't match its lightning block")));
+        assert!(report.problems.iter().any(|x| x.contains("the history has 1 entries but the blocks have 0 outputs")));
+        assert!(report.problems.iter().any(|x| x.contains("bytes that aren't in")));
+    }
+
+    #[test]
+    fn rebuilds_everything() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let bloom = chain(&storage, true);
+        let metadata = storage.read_all(BlockKind::Lightning.metadata()).unwrap();
+        let history = storage.read_all(crate::storage::HISTORY).unwrap();
+
+        // a half written block at the end and a broken index
+        storage.append(BlockKind::Lightning.data(), &[1, 2, 3]).unwrap();
+        storage.write_all(BlockKind::Lightning.metadata(), &[0u8; 20]).unwrap();
+        storage.history_append(&[0u8; 64]).unwrap();
+        BloomFile::initialize_sized(&*storage, 8192, 4);
+        bloom.reload().unwrap();
+        assert!(!check(&*storage, Some(&bloom)).is_ok());
+
+        assert_eq!(rebuild_metadata(&*storage, BlockKind::Lightning, 5), Ok(5));
+        assert_eq!(rebuild_history(storage.clone()), Ok(6));
+        assert_eq!(rebuild_bloom(&*storage, &bloom), Ok(3));
+        assert_eq!(storage.read_all(BlockKind::Lightning.metadata()).unwrap(), metadata);
+        assert_eq!(storage.read_all(crate::storage::HISTORY).unwrap(), history);
+        let report = check(&*storage, Some(&bloom));
+        assert!(report.is_ok(), "{:?}", report.problems);
+
+        // empty blocks at the end can only come from the height you tell it
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        chain(&storage, false);
+        storage.save_block(BlockKind::Full, &[]).unwrap();
+        storage.save_block(BlockKind::Lightning, &[]).unwrap();
+        let metadata = storage.read_all(BlockKind::Full.metadata()).unwrap();
+        storage.write_all(BlockKind::Full.metadata(), &[]).unwrap();
+        assert_eq!(rebuild_metadata(&*storage, BlockKind::Full, 0), Ok(5));
+        assert_eq!(rebuild_metadata(&*storage, BlockKind::Full, 6), Ok(6));
+        assert_eq!(storage.read_all(BlockKind::Full.metadata()).unwrap(), metadata);
+        assert!(check(&*storage, None).is_ok());
+
+        // pruned blocks stay pruned
+        storage.prune_blocks(BlockKind::Full, 2).unwrap();
+        assert!(check(&*storage, None).is_ok());
+        storage.write_all(BlockKind::Full.metadata(), &[]).unwrap();
+        assert_eq!(rebuild_metadata(&*storage, BlockKind::Full, 6), Ok(6));
+        let report = check(&*storage, None);
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.full_blocks, 2);
+    }
+}
@@ -30,9 +30,10 @@ use std::cmp::{min,max};
 use std::hash::Hasher;
 use std::iter::Iterator;
 use ahash::AHasher;
-use std::fs::OpenOptions;
-use std::fs::File;
-use std::io::{Seek, SeekFrom, Read, Write};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::io;
+use crate::storage::{Storage, TagStore, BLOOM};
 
 #[derive(Clone)]
 
@@ -57,54 +58,81 @@ impl Iterator for HashIter {
 
 
 
+/// the number of tags a new bloom file is sized for
+pub const EXPECTED_TAGS: u32 = 500_000;
+/// the chance a new bloom file says an unspent tag was spent once it holds EXPECTED_TAGS tags
+pub const FALSE_POSITIVE_RATE: f32 = 0.02;
+
+/// the bits of the filter kept in memory (the file only needs to be read when it's loaded)
+struct Filter {
+    num_bits: u64,
+    num_hashes: u32,
+    bits: Vec<AtomicU8>,
+    /// false if there's no bloom file so changes are only kept in memory
+    saved: bool,
+}
+
+impl Filter {
+    /// reads the bloom file (a node without one keeps the filter in memory but a broken one is an error)
+    fn load(storage: &dyn Storage) -> io::Result<Filter> {
+        if !storage.exists(BLOOM) {
+            let num_bits = (needed_bits(FALSE_POSITIVE_RATE, EXPECTED_TAGS) as u64).div_ceil(8)*8;
+            return Ok(Filter {
+                num_bits,
+                num_hashes: optimal_num_hashes(num_bits as usize, EXPECTED_TAGS),
+                bits: (0..num_bits/8).map(|_| AtomicU8::new(0)).collect(),
+                saved: false,
+            })
+        }
+        let (num_bits, num_hashes) = storage.tag_params()?;
+        Ok(Filter {
+            num_bits,
+            num_hashes,
+            bits: storage.tag_bytes()?.into_iter().map(AtomicU8::new).collect(),
+            saved: true,
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct BloomFile {
     h: AHasher,
     key1: u128,
     key2: u128,
+    storage: Arc<dyn Storage>,
+    filter: Arc<RwLock<Filter>>,
+    writing: Arc<Mutex<()>>,
 }
 
-static FILE_NAME: &str = "bloomfile";
-// const FILE_SIZE: usize = 1_000_000*4;// 1_000_000_000*4; // problems are somewhere
-// const HASHES: u32 = 13;
-const FILE_SIZE: u64 = 4_000_000; // for tests (in bits)
-const HASHES: u32 = 6; // for tests
-
 impl BloomFile {
 
-    /// initializes the bloom file as a large binary file full of 0s
-    pub fn initialize_bloom_file() {
-        // 6 hashes is best for 1_000_000_000 outputs
-        // fs::remove_file(FILE_NAME).unwrap(); // this is just for testing in reality this wouldn't be unwrapped because the file may not exist
-        let mut f = File::create(FILE_NAME).unwrap();
-        f.write(&[0b00000000u8;FILE_SIZE as usize/8]).unwrap();
-        // let mut f = OpenOptions::new().append(true).open(FILE_NAME).unwrap();
-        // f.write_all(&[0u8;FILE_SIZE as usize]).unwrap();
-        // for _ in 0..100 {
-        //     f.write_all(&[0u8;FILE_SIZE/100]).unwrap();
-        // }
+    /// initializes the bloom file as a binary file full of 0s big enough for expected_tags at false_positive_rate
+    pub fn initialize_bloom_file(storage: &dyn Storage, expected_tags: u32, false_positive_rate: f32) {
+        let num_bits = needed_bits(false_positive_rate, expected_tags) as u64;
+        BloomFile::initialize_sized(storage, num_bits, optimal_num_hashes(num_bits as usize, expected_tags));
+    }
+
+    /// initializes the bloom file with exactly num_bits (rounded up to whole bytes) and num_hashes
+    pub fn initialize_sized(storage: &dyn Storage, num_bits: u64, num_hashes: u32) {
+        storage.initialize_tags(num_bits.div_ceil(8)*8, num_hashes).expect("couldn't create the bloom file");
     }
 
     /// creates an object used to interact with the bloom file
-    pub fn from_keys(key1: u128, key2: u128) -> BloomFile {
-        BloomFile {
+    pub fn from_keys(storage: Arc<dyn Storage>, key1: u128, key2: u128) -> io::Result<BloomFile> {
+        Ok(BloomFile {
             h: AHasher::new_with_keys(key1,key2),
             key1,
             key2,
-        }
+            filter: Arc::new(RwLock::new(Filter::load(&*storage)?)),
+            storage,
+            writing: Arc::new(Mutex::new(())),
+        })
     }
 
     /// creates an object used to interact with the bloom file with random keys
-    pub fn from_randomness() -> BloomFile {
+    pub fn from_randomness(storage: Arc<dyn Storage>) -> io::Result<BloomFile> {
         let mut rng = thread_rng();
-        let key1 = rng.gen();
-        let key2 = rng.gen();
-        
-        BloomFile {
-            h: AHasher::new_with_keys(key1,key2),
-            key1,
-            key2,
-        }
+        BloomFile::from_keys(storage, rng.gen(), rng.gen())
     }
 
     /// get the keys used to define how the bloom filter works
@@ -112,62 +140,80 @@ impl BloomFile {
         [self.key1, self.key2]
     }
 
+    /// the number of bits and hashes of the filter
+    pub fn params(&self) -> (u64, u32) {
+        let filter = self.filter.read().unwrap();
+        (filter.num_bits, filter.num_hashes)
+    }
+
+    /// reads the filter from the bloom file again (after it's been changed by something else)
+    pub fn reload(&self) -> io::Result<()> {
+        *self.filter.write().unwrap() = Filter::load(&*self.storage)?;
+        Ok(())
+    }
+
     /// Insert item into this bloomfilter
     pub fn insert(&self, item: &[u8;32]) { // loc, pk, com = 32*3 = 96
-        for h in self.get_hashes(item) {
-            let h = h % FILE_SIZE;
-
-            let mut byte = [0u8];
-            let mut f = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(false)
-                .open(FILE_NAME)
-                .unwrap();
-            f.seek(SeekFrom::Start(h/8)).expect("Seek failed");
-
-            f.read(&mut byte).unwrap();
-            let mut delta = 0b00000001u8;
-            delta <<= h%8;
-            byte[0] |= delta;
-            assert!(byte[0] & delta != 0b00000000u8);
-            f.seek(SeekFrom::Start(h/8)).expect("Seek failed");
-            f.write(&byte).expect("Unable to write data");
-
-            
+        self.insert_batch(&[item]);
+    }
+
+    /// inserts every item and writes each changed byte of the file once
+    pub fn insert_batch(&self, items: &[&[u8;32]]) {
+        let filter = self.filter.read().unwrap();
+        let mut changed = items.iter().flat_map(|item| {
+            self.get_hashes(item, filter.num_hashes).filter_map(|h| {
+                let h = h % filter.num_bits;
+                let delta = 0b00000001u8 << (h%8);
+                let byte = filter.bits[(h/8) as usize].fetch_or(delta, Ordering::SeqCst);
+                if byte & delta == 0 {
+                    Some(h/8)
+                } else {
+                    None
+                }
+            }).collect::<Vec<_>>()
+        }).collect::<Vec<_>>();
+        if filter.saved && !changed.is_empty() {
+            changed.sort_unstable();
+            changed.dedup();
+            // the bytes are read again under the lock so the last write of a byte has every bit set by then
+            let _writing = self.writing.lock().unwrap();
+            let bytes = changed.into_iter().map(|b| (b, filter.bits[b as usize].load(Ordering::SeqCst))).collect::<Vec<_>>();
+            self.storage.set_tag_bytes(&bytes).expect("Unable to write data");
         }
     }
 
+    /// the bytes of the bloom file that inserting items would change with their current values
+    pub fn bytes(&self, items: &[&[u8;32]]) -> Vec<(u64, u8)> {
+        let filter = self.filter.read().unwrap();
+        let mut bytes = items.iter().flat_map(|item| self.get_hashes(item, filter.num_hashes).map(|h| (h % filter.num_bits)/8)).collect::<Vec<_>>();
+        bytes.sort_unstable();
+        bytes.dedup();
+        bytes.into_iter().map(|b| (b, filter.bits[b as usize].load(Ordering::SeqCst))).collect()
+    }
+
     /// test if the bloom filter contains the item
     pub fn contains(&self, item: &[u8;32]) -> bool {
-        for h in self.get_hashes(item) {
-            let h = h % FILE_SIZE;
-            let mut byte = [0u8];
-            let mut r = OpenOptions::new()
-                .read(true)
-                .write(false)
-                .create(false)
-                .open(FILE_NAME)
-                .unwrap();
-            r.seek(SeekFrom::Start(h/8)).expect("Seek failed");
-
-            r.read(&mut byte).expect("Unable to read data");
-            let mut delta = 0b00000001u8;
-            delta <<= h%8;
-            if (byte[0] & delta) == 0b00000000u8 {
-                return false;
-            }
-        }
-        true
+        self.contains_batch(&[item])[0]
     }
 
-    fn get_hashes(&self, item: &[u8;32]) -> HashIter {
+    /// tests every item in one pass over the filter
+    pub fn contains_batch(&self, items: &[&[u8;32]]) -> Vec<bool> {
+        let filter = self.filter.read().unwrap();
+        items.iter().map(|item| {
+            self.get_hashes(item, filter.num_hashes).all(|h| {
+                let h = h % filter.num_bits;
+                filter.bits[(h/8) as usize].load(Ordering::Relaxed) & (0b00000001u8 << (h%8)) != 0
+            })
+        }).collect()
+    }
+
+    fn get_hashes(&self, item: &[u8;32], count: u32) -> HashIter {
         let mut h = self.h.clone();
         h.write(item);
         HashIter {
             h,
             i: 0,
-            count: HASHES, // 4 is best for ~1_283_000_000 outputs (1 in 20 wrong)
+            count,
         }
     }
 }
@@ -265,17 +311,62 @@ mod tests {
     use curve25519_dalek::scalar::Scalar;
     use rand::{self,Rng};
     use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
-    use super::BloomFile;
+    use std::sync::Arc;
+    use crate::storage::{MemoryStorage, Storage, TagStore};
+    use super::{BloomFile, needed_bits, optimal_num_hashes};
+
+    fn new_bloom(key1: u128, key2: u128) -> BloomFile {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        BloomFile::initialize_sized(&*storage, 4_000_000, 6);
+        BloomFile::from_keys(storage, key1, key2).unwrap()
+    }
 
     #[test]
     fn simple() {
-        BloomFile::initialize_bloom_file();
-        let b: BloomFile = BloomFile::from_keys(0,0);
+        let b: BloomFile = new_bloom(0,0);
         b.insert(&Scalar::from(1u8).as_bytes());
         assert!(b.contains(&Scalar::from(1u8).as_bytes()));
         assert!(!b.contains(&Scalar::from(2u8).as_bytes()));
     }
 
+    #[test]
+    fn sized_from_rate() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        BloomFile::initialize_bloom_file(&*storage, 1000, 0.01);
+        let b = BloomFile::from_keys(storage.clone(), 3, 4).unwrap();
+        let (bits, hashes) = b.params();
+        assert_eq!(bits % 8, 0);
+        assert!(bits >= needed_bits(0.01, 1000) as u64);
+        assert_eq!(hashes, optimal_num_hashes(needed_bits(0.01, 1000), 1000));
+        assert_eq!(storage.tag_params().unwrap(), (bits, hashes));
+
+        // what's inserted is in the file so a new object with the same keys sees it
+        b.insert(&Scalar::from(5u8).as_bytes());
+        assert!(BloomFile::from_keys(storage.clone(), 3, 4).unwrap().contains(&Scalar::from(5u8).as_bytes()));
+
+        // changes made to the file directly are only seen after reloading
+        storage.initialize_tags(bits, hashes).unwrap();
+        assert!(b.contains(&Scalar::from(5u8).as_bytes()));
+        b.reload().unwrap();
+        assert!(!b.contains(&Scalar::from(5u8).as_bytes()));
+
+        // a broken header is an error instead of an empty filter
+        storage.write_all(crate::storage::BLOOM, &[0u8; 16]).unwrap();
+        assert!(b.reload().is_err());
+        assert!(BloomFile::from_keys(storage.clone(), 3, 4).is_err());
+    }
+
+    #[test]
+    fn batches() {
+        let b: BloomFile = new_bloom(5,6);
+        let items = (0..100u32).map(|v| *Scalar::from(v).as_bytes()).collect::<Vec<_>>();
+        b.insert_batch(&items[..50].iter().collect::<Vec<_>>());
+        let found = b.contains_batch(&items.iter().collect::<Vec<_>>());
+        assert!(found[..50].iter().all(|&x| x));
+        assert!(found[50..].iter().filter(|&&x| x).count() < 5);
+        assert_eq!(found, items.iter().map(|x| b.contains(x)).collect::<Vec<_>>());
+    }
+
     #[test]
     fn bloom_test() {
         let cnt = 500_000;
@@ -283,8 +374,7 @@ mod tests {
         let _hashes = 6; // the problem disapears when there's 1 hash..., problem starts at like 3ish
         let rate = 0.021577141 as f32;
 
-        BloomFile::initialize_bloom_file();
-        let b: BloomFile = BloomFile::from_keys(1,2);
+        let b: BloomFile = new_bloom(1,2);
         let mut set: HashSet<[u8;32]> = HashSet::new();
         let mut rng = rand::thread_rng();
 
@@ -329,8 +419,7 @@ mod tests {
         let _hashes = 6; // the problem disapears when there's 1 hash..., problem starts at like 3ish
         let rate = 0.021577141 as f32;
 
-        BloomFile::initialize_bloom_file();
-        let b: BloomFile = BloomFile::from_keys(1,2);
+        let b: BloomFile = new_bloom(1,2);
         let mut set: HashSet<[u8;32]> = HashSet::new();
 
 
@@ -375,8 +464,7 @@ mod tests {
         let _hashes = 6; // the problem disapears when there's 1 hash..., problem starts at like 3ish
         let rate = 0.021577141 as f32;
 
-        BloomFile::initialize_bloom_file();
-        let b: BloomFile = BloomFile::from_keys(1,2);
+        let b: BloomFile = new_bloom(1,2);
         let mut set: HashSet<[u8;32]> = HashSet::new();
 
 
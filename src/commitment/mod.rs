@@ -1,10 +1,10 @@
-use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_TABLE;
 use curve25519_dalek::ristretto::RistrettoPoint;
 use curve25519_dalek::scalar::Scalar;
 use rand::thread_rng;
 use serde::{Serialize, Deserialize};
 use std::ops::Add;
-use crate::constants::PEDERSEN_H;
+use crate::constants::PEDERSEN_H_TABLE;
 
 
 #[derive(Copy, Clone, Debug, Eq, Serialize, Deserialize)]
@@ -27,7 +27,7 @@ impl Default for Commitment{
 
 impl Commitment {
     pub fn commit(amount: &Scalar, randomness: &Scalar) -> Commitment {
-        let com = amount*RISTRETTO_BASEPOINT_POINT+randomness*PEDERSEN_H();
+        let com = amount*&RISTRETTO_BASEPOINT_TABLE + randomness*PEDERSEN_H_TABLE();
         //let com = RistrettoPoint::multiscalar_mul(&[amount,randomness], &[PEDERSEN_H(), RISTRETTO_BASEPOINT_POINT]);
         Commitment{com,  amount: Some(*amount), randomness: Some(*randomness), ..Default::default()}
     }
@@ -1,15 +1,24 @@
 #![allow(non_snake_case)]
 
+use std::sync::OnceLock;
 use curve25519_dalek::constants::RISTRETTO_BASEPOINT_COMPRESSED;
-use curve25519_dalek::ristretto::{RistrettoPoint, CompressedRistretto};
+use curve25519_dalek::ristretto::{RistrettoPoint, CompressedRistretto, RistrettoBasepointTable};
 use sha3::{Sha3_512};
 
 pub const COMPRESSED_PEDERSEN_H: CompressedRistretto = CompressedRistretto([140, 146, 64, 180, 86, 169, 230, 220, 101, 195, 119, 161, 4, 141, 116, 95, 148, 160, 140, 219, 127, 68, 203, 205, 123, 70, 243, 64, 72, 135, 17, 52]);
 pub const COMPRESSED_NATIVE: CompressedRistretto = CompressedRistretto([72, 2, 95, 153, 203, 254, 246, 104, 19, 19, 203, 9, 150, 245, 105, 42, 71, 184, 185, 77, 228, 204, 239, 66, 196, 171, 214, 194, 232, 253, 206, 21]);
 
+static PEDERSEN_H_POINT: OnceLock<RistrettoPoint> = OnceLock::new();
+static PEDERSEN_H_TABLE_CACHE: OnceLock<RistrettoBasepointTable> = OnceLock::new();
+
+/// the second generator of commitments (it's hashed the first time it's used then kept)
 pub fn PEDERSEN_H() -> RistrettoPoint {
-    RistrettoPoint::hash_from_bytes::<Sha3_512>(
-        RISTRETTO_BASEPOINT_COMPRESSED.as_bytes())
+    *PEDERSEN_H_POINT.get_or_init(|| RistrettoPoint::hash_from_bytes::<Sha3_512>(
+        RISTRETTO_BASEPOINT_COMPRESSED.as_bytes()))
+}
+/// precomputed multiples of PEDERSEN_H for fast multiplication: use `&x * PEDERSEN_H_TABLE()` instead of `x*PEDERSEN_H()`
+pub fn PEDERSEN_H_TABLE() -> &'static RistrettoBasepointTable {
+    PEDERSEN_H_TABLE_CACHE.get_or_init(|| RistrettoBasepointTable::create(&PEDERSEN_H()))
 }
 pub fn NATIVE() -> RistrettoPoint {
     RistrettoPoint::hash_from_bytes::<Sha3_512>(
@@ -18,11 +27,14 @@ pub fn NATIVE() -> RistrettoPoint {
 
 #[cfg(test)]
 mod tests {
-    use super::{PEDERSEN_H, COMPRESSED_PEDERSEN_H, NATIVE, COMPRESSED_NATIVE};
+    use super::{PEDERSEN_H, PEDERSEN_H_TABLE, COMPRESSED_PEDERSEN_H, NATIVE, COMPRESSED_NATIVE};
+    use curve25519_dalek::scalar::Scalar;
 
     #[test]
     fn test_pedersen() {
         assert_eq!(PEDERSEN_H().compress(), COMPRESSED_PEDERSEN_H);
+        let x = Scalar::from(123456789u64);
+        assert_eq!(&x * PEDERSEN_H_TABLE(), x*PEDERSEN_H());
     }
 
     #[test]
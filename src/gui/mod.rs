@@ -1,12 +1,15 @@
-use std::{convert::TryInto, fs, time::Instant};
+use std::{convert::TryInto, sync::Arc, time::Instant};
 
-use curve25519_dalek::scalar::Scalar;
 use eframe::{egui::{self, Button, Checkbox, Label, Sense, TextEdit}, epi};
 use crossbeam::channel;
 use fibers::sync::mpsc;
 
 use getrandom::getrandom;
-use sha3::{Digest, Sha3_512};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{login, mnemonic};
+use crate::storage::{NodeStore, Storage};
+use crate::vault::Vault;
 
 /*
 cargo run --bin full_staker --release 9876 pig
@@ -37,15 +40,15 @@ fn random_pswrd() -> String {
     });
     chars.into_iter().map(char::from).collect()
 }
-fn get_pswrd(a: &String, b: &String, c: &String) -> Vec<u8> {
-    println!("{}",a);
-    println!("{}",b);
-    println!("{}",c);
-    let mut hasher = Sha3_512::new();
-    hasher.update(&a.as_bytes());
-    hasher.update(&b.as_bytes());
-    hasher.update(&c.as_bytes());
-    Scalar::from_hash(hasher).as_bytes().to_vec()
+/// the seed of the login typed in (the seed phrase or the username, password and secret key with its kdf code)
+fn typed_seed(use_phrase: bool, legacy_login: bool, phrase: &str, username: &str, password: &str, secret_key: &str, login_kdf: &str) -> Option<Zeroizing<Vec<u8>>> {
+    if use_phrase {
+        mnemonic::from_phrase(phrase).ok().map(|x| Zeroizing::new(Zeroizing::new(x).to_vec()))
+    } else if legacy_login {
+        Some(Zeroizing::new(login::legacy_seed(username, password, secret_key).to_vec()))
+    } else {
+        Some(Zeroizing::new(login::login_seed(&login::from_kdf_code(login_kdf)?, username, password, secret_key).to_vec()))
+    }
 }
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
 #[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
@@ -74,16 +77,25 @@ pub struct TemplateApp {
     stkaddr: String,
     edit_names: Vec<bool>,
     dont_trust_amounts: bool,
-    password0: String,
-    pswd_guess0: String,
+    #[cfg_attr(feature = "persistence", serde(skip))] // the password is only kept while the app is unlocked and never saved
+    password0: Zeroizing<String>,
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    pswd_guess0: Zeroizing<String>,
+    #[cfg_attr(feature = "persistence", serde(skip))] // the app starts locked until the password opens the saved node
+    unlocked: bool,
     username: String,
     secret_key: String,
     pswd_shown: bool,
     block_number: u64,
-    show_next_pswrd: bool,
-    next_pswrd0: String,
-    next_pswrd1: String,
-    next_pswrd2: String,
+    use_phrase: bool,
+    legacy_login: bool, // the account was made before logins went through a kdf
+    migrate: bool, // the panic button moves the money to the hardened login
+    login_kdf: String, // the kdf code of the login (kept with the password and secret key)
+    #[cfg_attr(feature = "persistence", serde(skip))] // the seed phrase is never saved
+    phrase: Zeroizing<String>,
+    generation: String, // how many times the panic button was pressed (the login restores every generation)
+    #[cfg_attr(feature = "persistence", serde(skip))] // the password is typed in again to panic and never saved
+    panic_pswrd: Zeroizing<String>,
     panic_fee: String,
     entrypoint: String,
     stkspeand: bool,
@@ -94,11 +106,15 @@ pub struct TemplateApp {
     send_addr: Vec<String>,
     send_amnt: Vec<String>,
     lightning_yielder: bool,
+    pruning: bool,
     validating: bool,
     lonely: u16,
-    sk: Vec<u8>,
-    vsk: Vec<u8>,
-    tsk: Vec<u8>,
+    #[cfg_attr(feature = "persistence", serde(skip))] // the node sends the keys every time it starts
+    sk: Zeroizing<Vec<u8>>,
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    vsk: Zeroizing<Vec<u8>>,
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    tsk: Zeroizing<Vec<u8>>,
 
     #[cfg_attr(feature = "persistence", serde(skip))] // this feature doesn't work for sender
     timekeeper: Instant,
@@ -106,6 +122,13 @@ pub struct TemplateApp {
     // this how you opt-out of serialization of a member
     #[cfg_attr(feature = "persistence", serde(skip))]
     you_cant_do_that: bool,
+
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    node_storage: Option<Arc<dyn Storage>>,
+
+    /// the money you have: [location, amount, memo]
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    received: Vec<(u64, u64, String)>,
 }
 impl Default for TemplateApp {
     fn default() -> Self {
@@ -128,16 +151,20 @@ impl Default for TemplateApp {
             addr: "".to_string(),
             stkaddr: "".to_string(),
             dont_trust_amounts: false,
-            password0: "".to_string(),
-            pswd_guess0: "".to_string(),
+            password0: Zeroizing::new("".to_string()),
+            pswd_guess0: Zeroizing::new("".to_string()),
+            unlocked: false,
             username: "".to_string(),
             secret_key: "".to_string(),
             pswd_shown: true,
             block_number: 0,
-            show_next_pswrd: true,
-            next_pswrd0: random_pswrd(),
-            next_pswrd1: "".to_string(),
-            next_pswrd2: random_pswrd()[..5].to_string(),
+            use_phrase: true,
+            legacy_login: false,
+            migrate: false,
+            login_kdf: login::kdf_code(&login::DEFAULT_LOGIN_KDF),
+            phrase: Zeroizing::new("".to_string()),
+            generation: "0".to_string(),
+            panic_pswrd: Zeroizing::new("".to_string()),
             panic_fee: "1".to_string(),
             entrypoint: "".to_string(),
             stkspeand: false,
@@ -150,30 +177,31 @@ impl Default for TemplateApp {
             send_addr: vec!["".to_string()],
             send_amnt: vec!["".to_string()],
             lightning_yielder: false,
+            pruning: false,
             validating: false,
             lonely: 0,
-            sk: vec![],
-            vsk: vec![],
-            tsk: vec![],
+            sk: Zeroizing::new(vec![]),
+            vsk: Zeroizing::new(vec![]),
+            tsk: Zeroizing::new(vec![]),
+            node_storage: None,
+            received: vec![],
         }
     }
 }
 impl TemplateApp {
     pub fn new_minimal(reciever: channel::Receiver<Vec<u8>>, sender: mpsc::Sender<Vec<u8>>) -> Self {
-        TemplateApp{reciever, sender, ..Default::default()}
+        TemplateApp { reciever, sender, ..TemplateApp::default() }
     }
-    pub fn new(reciever: channel::Receiver<Vec<u8>>, sender: mpsc::Sender<Vec<u8>>, addr: String, stkaddr: String, sk: Vec<u8>, vsk: Vec<u8>, tsk: Vec<u8>, setup: bool) -> Self {
-        TemplateApp{
-            reciever,
-            sender,
-            addr,
-            stkaddr,
-            setup,
-            sk,
-            vsk,
-            tsk,
-            ..Default::default()
-        }
+    pub fn new(reciever: channel::Receiver<Vec<u8>>, sender: mpsc::Sender<Vec<u8>>, addr: String, stkaddr: String, sk: Zeroizing<Vec<u8>>, vsk: Zeroizing<Vec<u8>>, tsk: Zeroizing<Vec<u8>>, setup: bool, node_storage: Option<Arc<dyn Storage>>) -> Self {
+        let mut app = TemplateApp::new_minimal(reciever, sender);
+        app.addr = addr;
+        app.stkaddr = stkaddr;
+        app.setup = setup;
+        app.sk = sk;
+        app.vsk = vsk;
+        app.tsk = tsk;
+        app.node_storage = node_storage;
+        app
     }
 }
 impl epi::App for TemplateApp {
@@ -199,9 +227,10 @@ impl epi::App for TemplateApp {
                 let s = self.sender.clone();
                 let a = self.addr.clone();
                 let sa = self.stkaddr.clone();
-                let sk = self.sk.clone();
-                let vsk = self.vsk.clone();
-                let tsk = self.tsk.clone();
+                let sk = std::mem::replace(&mut self.sk, Zeroizing::new(vec![]));
+                let vsk = std::mem::replace(&mut self.vsk, Zeroizing::new(vec![]));
+                let tsk = std::mem::replace(&mut self.tsk, Zeroizing::new(vec![]));
+                let node_storage = self.node_storage.clone();
                 *self = epi::get_value(storage, "Khora").unwrap_or_default();
                 self.sender = s;
                 self.reciever = r;
@@ -210,9 +239,11 @@ impl epi::App for TemplateApp {
                 self.sk = sk;
                 self.vsk = vsk;
                 self.tsk = tsk;
+                self.node_storage = node_storage;
             }
         } else {
             self.secret_key = random_pswrd()[..5].to_string();
+            self.phrase = Zeroizing::new(mnemonic::to_phrase(&mnemonic::new_seed()));
         }
     }
 
@@ -244,6 +275,13 @@ impl epi::App for TemplateApp {
                 self.validating = i == vec![1];
             } else if modification == 4 {
                 self.lonely = u16::from_le_bytes(i.try_into().unwrap());
+            } else if modification == 5 {
+                // the outputs you gained and the locations of the ones you lost since the last update
+                if let Ok((added, removed)) = bincode::deserialize::<(Vec<(u64, u64, Vec<u8>)>, Vec<u64>)>(&i) {
+                    self.received.retain(|x| !removed.contains(&x.0) && added.iter().all(|y| y.0 != x.0));
+                    self.received.extend(added.into_iter().map(|(l, a, m)| (l, a, String::from_utf8_lossy(&m).to_string())));
+                    self.received.sort_by_key(|x| x.0);
+                }
             } else if modification == 128 {
                 self.eta = i[0] as i8;
                 self.timekeeper = Instant::now();
@@ -254,11 +292,11 @@ impl epi::App for TemplateApp {
                 } else if info == 1 {
                     self.stkaddr = String::from_utf8_lossy(&i).to_string();
                 } else if info == 2 {
-                    self.sk = i;
+                    self.sk = Zeroizing::new(i);
                 } else if info == 3 {
-                    self.vsk = i;
+                    self.vsk = Zeroizing::new(i);
                 } else if info == 4 {
-                    self.tsk = i;
+                    self.tsk = Zeroizing::new(i);
                 }
             }
         }
@@ -282,16 +320,20 @@ impl epi::App for TemplateApp {
             dont_trust_amounts,
             password0,
             pswd_guess0,
+            unlocked,
             username,
             secret_key,
             eta,
             timekeeper,
             pswd_shown,
             block_number,
-            show_next_pswrd,
-            next_pswrd0,
-            next_pswrd1,
-            next_pswrd2,
+            use_phrase,
+            legacy_login,
+            migrate,
+            login_kdf,
+            phrase,
+            generation,
+            panic_pswrd,
             panic_fee,
             entrypoint,
             stkspeand,
@@ -302,11 +344,14 @@ impl epi::App for TemplateApp {
             send_addr,
             send_amnt,
             lightning_yielder,
+            pruning,
             validating,
             lonely,
             sk,
             vsk,
             tsk,
+            node_storage,
+            received,
         } = self;
 
  
@@ -336,7 +381,9 @@ impl epi::App for TemplateApp {
                         *show_reset = !*show_reset;
                     }
                     if ui.button("Log Out - will require resync with blockchain").clicked() {
-                        fs::remove_file("myNode").expect("should work");
+                        if let Some(node_storage) = node_storage {
+                            node_storage.remove_node().expect("should work");
+                        }
                         frame.quit();
                     }
                     if ui.button("Quit").clicked() {
@@ -395,40 +442,66 @@ impl epi::App for TemplateApp {
                     ui.heading("Password");
                 }
                 ui.horizontal(|ui| {
-                    ui.text_edit_singleline(pswd_guess0);
+                    ui.text_edit_singleline(&mut **pswd_guess0);
                     ui.label("-");
                     if *setup {
                         ui.text_edit_singleline(secret_key);
                     } else {
                         ui.label(&*secret_key);
                     }
+                    // the password is checked against the saved node it encrypts
+                    if !*setup && !*unlocked && ui.button("Unlock").clicked() {
+                        *unlocked = node_storage.as_ref().and_then(|x| x.load_node().ok()).is_some_and(|x| Vault::check_password(pswd_guess0.as_bytes(), &x));
+                        if *unlocked {
+                            *password0 = std::mem::replace(pswd_guess0, Zeroizing::new("".to_string()));
+                        }
+                    }
                 });
             }
-            if *password0 == *pswd_guess0 && *pswd_shown {
+            if *unlocked && *pswd_shown {
                 ui.horizontal(|ui| {
-                    if ui.button("📋").on_hover_text("Click to copy your password and secret key to clipboard").clicked() {
-                        ui.output().copied_text = format!("{} - {}",password0,secret_key);
+                    if ui.button("📋").on_hover_text("Click to copy your password, secret key and login strength to clipboard").clicked() {
+                        ui.output().copied_text = format!("{} - {} - {}",password0.as_str(),secret_key,login_kdf);
                     }
-                    ui.label(format!("{} - {}",password0,secret_key));
+                    ui.label(format!("{} - {} - {}",password0.as_str(),secret_key,login_kdf));
                 });
                 if !*setup {
                     ui.horizontal(|ui| {
                         if ui.button("📋").on_hover_text("Click to copy your backend secret keys to clipboard (all of these are generated from your front end secret key, username, and password)").clicked() {
-                            ui.output().copied_text = format!("sk: {:?}\nvsk: {:?}\ntsk: {:?}",sk,vsk,tsk);
+                            ui.output().copied_text = format!("sk: {:?}\nvsk: {:?}\ntsk: {:?}",&sk[..],&vsk[..],&tsk[..]);
                         }
-                        ui.add(Label::new(format!("sk: {:?}\nvsk: {:?}\ntsk: {:?}",sk,vsk,tsk)).underline());
+                        ui.add(Label::new(format!("sk: {:?}\nvsk: {:?}\ntsk: {:?}",&sk[..],&vsk[..],&tsk[..])).underline());
                     });
                 }
             } else if *setup {
                 ui.horizontal(|ui| {
-                    if ui.button("📋").on_hover_text("Click to copy your password and secret key to clipboard").clicked() {
-                        ui.output().copied_text = format!("{} - {}",pswd_guess0,secret_key);
+                    if ui.button("📋").on_hover_text("Click to copy your password, secret key and login strength to clipboard").clicked() {
+                        ui.output().copied_text = format!("{} - {} - {}",pswd_guess0.as_str(),secret_key,login_kdf);
                     }
-                    ui.label(format!("{} - {}",pswd_guess0,secret_key));
+                    ui.label(format!("{} - {} - {}",pswd_guess0.as_str(),secret_key,login_kdf));
                 });
+                ui.heading("Panics");
+                ui.text_edit_singleline(generation);
+                ui.add(Checkbox::new(use_phrase,"Use a seed phrase instead of the username and secret key"));
+                if *use_phrase {
+                    ui.horizontal(|ui| {
+                        ui.heading("Seed Phrase");
+                        if ui.button("New Phrase").clicked() {
+                            *phrase = Zeroizing::new(mnemonic::to_phrase(&mnemonic::new_seed()));
+                        }
+                        if ui.button("📋").on_hover_text("Click to copy your seed phrase to clipboard").clicked() {
+                            ui.output().copied_text = phrase.to_string();
+                        }
+                    });
+                    ui.add(TextEdit::multiline(&mut **phrase));
+                } else {
+                    ui.heading("Login Strength");
+                    ui.text_edit_singleline(login_kdf).on_hover_text("Keep this with your password and secret key. It says how hard your login is to guess and you need it to log in.");
+                    ui.add(Checkbox::new(legacy_login,"My account was made before logins were hardened"));
+                }
             }
-            if *password0 != *pswd_guess0 && !*setup {
-                ui.add(Label::new("password incorrect, account features disabled, enter correct password to unlock").text_color(egui::Color32::RED));
+            if !*unlocked && !*setup {
+                ui.add(Label::new("account features are locked, enter your password and press unlock").text_color(egui::Color32::RED));
             }
             if !*setup {
                 ui.horizontal(|ui| {
@@ -480,7 +553,7 @@ impl epi::App for TemplateApp {
                     });
                     ui.horizontal(|ui| {
                         ui.text_edit_singleline(stake);
-                        if pswd_guess0 == password0 {
+                        if *unlocked {
                             if ui.button("Stake").clicked() && !*setup {
                                 let mut m = vec![];
                                 m.extend(stkaddr.as_bytes().to_vec());
@@ -499,7 +572,7 @@ impl epi::App for TemplateApp {
                     });
                     ui.horizontal(|ui| {
                         ui.text_edit_singleline(unstake);
-                        if pswd_guess0 == password0 {
+                        if *unlocked {
                             if ui.button("Unstake").clicked() && !*setup {
                                 // println!("unstaking {:?}!",unstake.parse::<u64>());
                                 let mut m = vec![];
@@ -549,9 +622,26 @@ impl epi::App for TemplateApp {
                 } else {
                     ui.add(Label::new(" "));
                 } 
-                if secret_key.len() != 5 {
+                if *use_phrase {
+                    match mnemonic::from_phrase(phrase) {
+                        Ok(_) => {ui.add(Label::new("Write your seed phrase down and keep it safe. It restores your account and staking account on any computer."));},
+                        Err(e) => {
+                            ui.add(Label::new(format!("{}",e)).text_color(egui::Color32::RED));
+                            bad_log_info = false;
+                        }
+                    }
+                } else if secret_key.len() != 5 {
                     ui.add(Label::new("Secret key must be exactly 5 characters").text_color(egui::Color32::RED));
                     bad_log_info = false;
+                } else if login::from_kdf_code(login_kdf).is_none() {
+                    ui.add(Label::new("Login strength must be the code you saved with your login (like argon2id-65536-3-1)").text_color(egui::Color32::RED));
+                    bad_log_info = false;
+                } else {
+                    ui.add(Label::new(" "));
+                }
+                if generation.parse::<u32>().is_err() {
+                    ui.add(Label::new("Panics must be the number of times you pressed the panic button (0 for a new account)").text_color(egui::Color32::RED));
+                    bad_log_info = false;
                 } else {
                     ui.add(Label::new(" "));
                 }
@@ -561,7 +651,17 @@ impl epi::App for TemplateApp {
                     if ui.add(Button::new("Login").enabled(bad_log_info)).clicked() {
                         *password0 = pswd_guess0.clone();
                         loop {
-                            if sender.send(get_pswrd(&*password0,&*username,&*secret_key)).is_ok() {
+                            let mut seed = typed_seed(*use_phrase, *legacy_login, phrase, username, password0, secret_key, login_kdf).expect("the login was checked when it was typed in");
+                            seed.extend(generation.parse::<u32>().unwrap().to_le_bytes());
+                            if *legacy_login && !*use_phrase {
+                                // the node keeps the hardened login's account to move the money to with the panic button
+                                seed.extend(login::login_seed(&login::from_kdf_code(login_kdf).unwrap(), username, password0, secret_key).iter());
+                                seed.push(2);
+                            } else {
+                                seed.push(0); // you know how many times you panicked
+                            }
+                            // the node keeps what it's sent in Zeroizing too
+                            if sender.send(std::mem::take(&mut *seed)).is_ok() {
                                 break
                             }
                         }
@@ -571,11 +671,21 @@ impl epi::App for TemplateApp {
                             }
                         }
                         *lightning_yielder = !*staking || *lightning_yielder;
+                        *pruning = !*lightning_yielder && *pruning;
                         loop {
-                            if sender.send(vec![*lightning_yielder as u8]).is_ok() {
+                            if sender.send(vec![if *lightning_yielder {1} else if *pruning {2} else {0}]).is_ok() {
                                 break
                             }
                         }
+                        loop {
+                            if sender.send(password0.as_bytes().to_vec()).is_ok() {
+                                break
+                            }
+                        }
+                        // everything secret has been sent to the node
+                        password0.zeroize();
+                        pswd_guess0.zeroize();
+                        phrase.zeroize();
                         *setup = false;
                         frame.quit();
                     }
@@ -586,11 +696,33 @@ impl epi::App for TemplateApp {
                         ui.add(Checkbox::new(lightning_yielder,"I only want to store lightning blocks!"));
                         ui.add(Label::new("Checking this box means you'll use less memory on your computer").text_color(egui::Color32::YELLOW));    
                     });
+                    if !*lightning_yielder {
+                        ui.horizontal(|ui| {
+                            ui.add(Checkbox::new(pruning,"I only want to store recent full blocks!"));
+                            ui.add(Label::new("Checking this box means you can't help people sync from the start").text_color(egui::Color32::YELLOW));
+                        });
+                    }
                 }
             }
             if *dont_trust_amounts {
                 ui.add(Label::new("money owned is not yet verified").text_color(egui::Color32::RED));
             }
+            if !*setup && !received.is_empty() {
+                ui.collapsing("Received", |ui| {
+                    egui::Grid::new("received_grid").min_col_width(90.0).max_col_width(300.0).show(ui, |ui| {
+                        ui.add(Label::new("Location").heading());
+                        ui.add(Label::new("Amount").heading());
+                        ui.add(Label::new("Memo").heading());
+                        ui.end_row();
+                        for (location, amount, memo) in received.iter() {
+                            ui.label(location.to_string());
+                            ui.label(amount.to_string());
+                            ui.label(memo);
+                            ui.end_row();
+                        }
+                    });
+                });
+            }
             if !*setup {
                 let mut delete_row_x = usize::MAX;
                 egui::ScrollArea::auto_sized().show(ui,|ui| {
@@ -630,7 +762,7 @@ impl epi::App for TemplateApp {
                             send_amnt.remove(delete_row_x);
                         }
                     }
-                    if pswd_guess0 == password0 {
+                    if *unlocked {
                         ui.horizontal(|ui| {
                             ui.heading("                                                                                  ");
                             if ui.button("Delete All Rows").clicked() {
@@ -693,36 +825,44 @@ impl epi::App for TemplateApp {
             egui::warn_if_debug_build(ui);
         });
 
-        if  pswd_guess0 == password0 || *setup { // add warning to not panic 2ce in a row
+        if *unlocked || *setup { // add warning to not panic 2ce in a row
             egui::Window::new("Panic Button").open(show_reset).show(ctx, |ui| {
-                ui.label("The Panic button will transfer all of your Khora to a new non-staker account and delete your old account. \nDo not turn off your client until you receive your Khora on your new account. \nAccount information will be reset to the information entered below \nSave the below information in a safe place.");
-                
-                ui.horizontal(|ui| {
-                    ui.add(Checkbox::new(show_next_pswrd,"Show Password On Reset"));
-                    if ui.button("Suggest New Account Info").clicked() {
-                        *next_pswrd0 = random_pswrd();
-                        *next_pswrd1 = username.clone();
-                        *next_pswrd2 = random_pswrd()[..5].to_string();
-                    }
-                });
-                ui.label("New Username");
-                ui.text_edit_singleline(next_pswrd1);
-                ui.label("New Password - Secret Key");
-                ui.horizontal(|ui| {
-                    if ui.button("📋").on_hover_text("Click to copy your password and secret key to clipboard").clicked() {
-                        ui.output().copied_text = format!("{} - {}",next_pswrd0,next_pswrd2);
+                ui.label("The Panic button will transfer all of your Khora to a new non-staker account and delete your old account. \nDo not turn off your client until you receive your Khora on your new account. \nThe new account comes from your login and the number of panics (not from this account's keys, which may have been stolen) so type your login in again. \nTo restore it later log in with the same login and one more panic than you last logged in with.");
+                if *legacy_login && !*use_phrase {
+                    ui.add(Checkbox::new(migrate,"Move to a hardened login instead (for accounts made before logins were hardened)"));
+                }
+                if *migrate {
+                    ui.label(format!("Your money moves to the account of the username, password and secret key you logged in with as a hardened login with the login strength {}. \nLog in with them without checking the old login box from now on.", login_kdf));
+                } else {
+                    ui.horizontal(|ui| {
+                        ui.label("Panics So Far");
+                        ui.text_edit_singleline(generation);
+                    });
+                    ui.add(Checkbox::new(use_phrase,"Seed phrase login"));
+                    if *use_phrase {
+                        ui.label("Seed Phrase");
+                        ui.add(TextEdit::multiline(&mut **phrase));
+                    } else {
+                        ui.label("Username");
+                        ui.text_edit_singleline(username);
+                        ui.label("Password - Secret Key");
+                        ui.horizontal(|ui| {
+                            ui.text_edit_singleline(&mut **panic_pswrd);
+                            ui.label("-");
+                            ui.text_edit_singleline(secret_key);
+                        });
+                        ui.label("Login Strength");
+                        ui.text_edit_singleline(login_kdf);
                     }
-                    ui.text_edit_singleline(next_pswrd0);   
-                    ui.label("-");            
-                    ui.label(&*next_pswrd2);
-                });
+                }
                 ui.horizontal(|ui| {
                     ui.label("Account Reset Network Fee");
                     ui.text_edit_singleline(panic_fee);
                 });
                 
-                if ui.button("PANIC").clicked() {
-                    let mut x = vec![];
+                let typed = generation.parse::<u32>().is_ok() && if *use_phrase {mnemonic::from_phrase(phrase).is_ok()} else {!panic_pswrd.is_empty() && (*legacy_login || login::from_kdf_code(login_kdf).is_some())};
+                if ui.add(Button::new("PANIC").enabled(*migrate || typed)).clicked() {
+                    let mut x = Zeroizing::new(vec![]);
                     let pf = panic_fee.parse::<u64>().unwrap();
 
                     let s = unstaked.parse::<u64>().unwrap();
@@ -737,16 +877,26 @@ impl epi::App for TemplateApp {
                     } else {
                         x.extend(s.to_le_bytes());
                     }
-                    x.extend(get_pswrd(&*next_pswrd0,&*next_pswrd1,&*next_pswrd2));
+                    if *migrate {
+                        // the node moves the money to the hardened login's account it got when you logged in
+                        x.push(1);
+                        *migrate = false;
+                        *legacy_login = false;
+                        *generation = "0".to_string();
+                    } else {
+                        // the node checks the login is its own and moves the money to its next generation
+                        let g = generation.parse::<u32>().unwrap();
+                        let seed = typed_seed(*use_phrase, *legacy_login, phrase, username, panic_pswrd, secret_key, login_kdf).unwrap();
+                        x.extend(seed.iter());
+                        x.extend(g.to_le_bytes());
+                        x.push(0);
+                        *generation = format!("{}", g + 1);
+                        panic_pswrd.zeroize();
+                        phrase.zeroize();
+                    }
                     x.push(u8::MAX);
                     if !*setup {
-                        sender.send(x).expect("something's wrong with communication from the gui");
-                    }
-                    *password0 = next_pswrd0.clone();
-                    *username = next_pswrd1.clone();
-                    *secret_key = next_pswrd2.clone();
-                    if *show_next_pswrd {
-                        *pswd_guess0 = next_pswrd0.clone();
+                        sender.send(std::mem::take(&mut *x)).expect("something's wrong with communication from the gui");
                     }
                 }
             });
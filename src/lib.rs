@@ -8,5 +8,14 @@ pub mod external;
 pub mod ringmaker;
 pub mod randblock;
 pub mod bloom;
+pub mod spent;
 pub mod validation;
+pub mod storage;
+pub mod blockcheck;
+pub mod vault;
+pub mod watch;
+pub mod payment;
+pub mod reserve;
+pub mod mnemonic;
+pub mod login;
 pub mod gui;
\ No newline at end of file
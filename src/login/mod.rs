@@ -0,0 +1,87 @@
+//! the seed an account is made from (see Account::new) when someone logs in with a username, password and secret key
+//!
+//! logins go through argon2 salted with the username and secret key so every guess at a weak password costs a lot of
+//! memory and time. the parameters are part of the login: they're written as a short code (kdf_code) that's kept with
+//! the password and secret key, so new logins can be made harder without locking out the ones made before. accounts
+//! made before this hashed the login once with sha3 (legacy_seed). they can still log in that way and move their money
+//! to a hardened login with the panic button.
+use curve25519_dalek::scalar::Scalar;
+use sha3::{Digest, Sha3_256, Sha3_512};
+use zeroize::Zeroizing;
+
+use crate::vault::Kdf;
+
+/// the kdf new logins go through
+pub const DEFAULT_LOGIN_KDF: Kdf = Kdf::Argon2 { memory: 64*1024, iterations: 3, lanes: 1 };
+
+/// the code kept with a login that says which kdf it goes through (like argon2id-65536-3-1)
+pub fn kdf_code(kdf: &Kdf) -> String {
+    match kdf {
+        Kdf::Argon2 { memory, iterations, lanes } => format!("argon2id-{}-{}-{}", memory, iterations, lanes),
+        Kdf::Pbkdf2 { rounds } => format!("pbkdf2-{}", rounds),
+    }
+}
+
+/// reads a login's kdf code (logins only go through argon2 so anything else or parameters it can't use are None)
+pub fn from_kdf_code(code: &str) -> Option<Kdf> {
+    match code.trim().split('-').collect::<Vec<_>>()[..] {
+        ["argon2id", memory, iterations, lanes] => {
+            let (memory, iterations, lanes) = (memory.parse().ok()?, iterations.parse().ok()?, lanes.parse().ok()?);
+            argon2::Params::new(memory, iterations, lanes, Some(32)).ok()?;
+            Some(Kdf::Argon2 { memory, iterations, lanes })
+        }
+        _ => None,
+    }
+}
+
+/// the salt of a login (the username and secret key aren't secret enough to be the key but they make every account's guesses different)
+fn login_salt(username: &str, secret_key: &str) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"khora login");
+    hasher.update((username.len() as u64).to_le_bytes());
+    hasher.update(username.as_bytes());
+    hasher.update(secret_key.as_bytes());
+    hasher.finalize().into()
+}
+
+/// the seed of a login made with kdf (read from the login's kdf code)
+pub fn login_seed(kdf: &Kdf, username: &str, password: &str, secret_key: &str) -> Zeroizing<[u8; 32]> {
+    kdf.derive(password.as_bytes(), &login_salt(username, secret_key)).expect("the login kdf's parameters are out of range")
+}
+
+/// the seed of a login made before logins went through a kdf
+pub fn legacy_seed(username: &str, password: &str, secret_key: &str) -> Zeroizing<[u8; 32]> {
+    let mut hasher = Sha3_512::new();
+    hasher.update(password.as_bytes());
+    hasher.update(username.as_bytes());
+    hasher.update(secret_key.as_bytes());
+    Zeroizing::new(Scalar::from_hash(hasher).to_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Account;
+
+    #[test]
+    fn login_seeds() {
+        let kdf = Kdf::Argon2 { memory: 64, iterations: 1, lanes: 1 };
+        let seed = login_seed(&kdf, "user", "password", "abcde");
+        assert_eq!(seed, login_seed(&kdf, "user", "password", "abcde"));
+        assert!(seed != login_seed(&kdf, "user", "password", "abcdf"));
+        assert!(seed != login_seed(&kdf, "use", "password", "rabcde"));
+        assert!(seed != legacy_seed("user", "password", "abcde"));
+        assert!(seed != login_seed(&Kdf::Argon2 { memory: 64, iterations: 2, lanes: 1 }, "user", "password", "abcde"));
+
+        // the parameters are kept with the login
+        assert_eq!(from_kdf_code(&kdf_code(&DEFAULT_LOGIN_KDF)), Some(DEFAULT_LOGIN_KDF));
+        assert_eq!(from_kdf_code(" argon2id-64-1-1\n"), Some(kdf));
+        assert_eq!(from_kdf_code("argon2id-1-1-1"), None);
+        assert_eq!(from_kdf_code("argon2id-64-1"), None);
+        assert_eq!(from_kdf_code(&kdf_code(&Kdf::Pbkdf2 { rounds: 10 })), None);
+
+        // the founder's account in the initial history was made with a legacy login
+        let founder = Account::new(&*legacy_seed("1234", "1234567", "12345")).stake_acc().derive_stk_ot(&Scalar::one()).pk.compress();
+        assert_eq!(founder.as_bytes(), &[46, 235, 227, 188, 55, 53, 9, 126, 167, 207, 202, 101, 150, 150, 172, 207, 209, 208, 211, 52, 47, 206, 19, 115, 199, 189, 202, 10, 56, 220, 138, 55]);
+    }
+}
@@ -20,6 +20,14 @@ pub enum LpkeError{
 }
 
 
+/// one byte of the shared secret that lets whoever isn't the recipient skip the ciphertext after one scalar multiplication
+fn view_tag_of(P: &RistrettoPoint) -> u8 {
+    let mut hasher = Sha3_512::new();
+    hasher.update(b"view tag");
+    hasher.update(P.compress().as_bytes());
+    hasher.finalize()[0]
+}
+
 #[derive(Debug, Clone, Eq, Serialize, Deserialize, Default)]
 pub struct Ciphertext {
     pub R: RistrettoPoint,
@@ -36,9 +44,54 @@ impl PartialEq for Ciphertext {
 
 impl Ciphertext{
     pub fn encrypt(pk: &RistrettoPoint, label: &[u8], message: &[u8]) -> Ciphertext {
+        Ciphertext::encrypt_over(&RISTRETTO_BASEPOINT_POINT, pk, label, message)
+    }
+
+    /// encrypts to pk = sk*base. R is over base too so sk still decrypts it
+    pub fn encrypt_over(base: &RistrettoPoint, pk: &RistrettoPoint, label: &[u8], message: &[u8]) -> Ciphertext {
+        Ciphertext::encrypt_shared(base, pk, label, message).0
+    }
+
+    /// encrypts the message and gives you its view tag to publish with it so scanning for it is cheap
+    pub fn encrypt_with_view_tag(pk: &RistrettoPoint, label: &[u8], message: &[u8]) -> (Ciphertext, u8) {
+        Ciphertext::encrypt_with_view_tag_over(&RISTRETTO_BASEPOINT_POINT, pk, label, message)
+    }
+
+    /// encrypt_over with a view tag
+    pub fn encrypt_with_view_tag_over(base: &RistrettoPoint, pk: &RistrettoPoint, label: &[u8], message: &[u8]) -> (Ciphertext, u8) {
+        let (c, P) = Ciphertext::encrypt_shared(base, pk, label, message);
+        (c, view_tag_of(&P))
+    }
+
+    /// encrypt_with_view_tag_over with randomness r you choose (R = r*base). anyone who knows r can open the ciphertext
+    /// with open_with_randomness so r has to be as secret as the message
+    pub fn encrypt_with_view_tag_from(r: &Scalar, base: &RistrettoPoint, pk: &RistrettoPoint, label: &[u8], message: &[u8]) -> (Ciphertext, u8) {
+        let (c, P) = Ciphertext::encrypt_with(r, base, pk, label, message);
+        (c, view_tag_of(&P))
+    }
+
+    /// opens a ciphertext made for pk over base with the randomness it was made with instead of the secret key
+    pub fn open_with_randomness(&self, r: &Scalar, base: &RistrettoPoint, pk: &RistrettoPoint, label: &[u8]) -> Result<Vec<u8>,LpkeError> {
+        if r*base != self.R {
+            return Err(LpkeError::DecryptionError)
+        }
+        self.open_shared(&(r*pk), label)
+    }
+
+    /// false if the view tag says the ciphertext isn't for sk
+    pub fn may_be_for(&self, sk: &Scalar, view_tag: u8) -> bool {
+        view_tag_of(&(sk*self.R)) == view_tag
+    }
+
+    /// encrypts the message and also returns the shared secret
+    fn encrypt_shared(base: &RistrettoPoint, pk: &RistrettoPoint, label: &[u8], message: &[u8]) -> (Ciphertext, RistrettoPoint) {
         let mut csrng = thread_rng();
-        let r = Scalar::random(&mut csrng);
-        let R = r*RISTRETTO_BASEPOINT_POINT;
+        Ciphertext::encrypt_with(&Scalar::random(&mut csrng), base, pk, label, message)
+    }
+
+    /// encrypts the message with the randomness r and also returns the shared secret
+    fn encrypt_with(r: &Scalar, base: &RistrettoPoint, pk: &RistrettoPoint, label: &[u8], message: &[u8]) -> (Ciphertext, RistrettoPoint) {
+        let R = r*base;
         let P = r*pk;
 
         let mut hasher = Sha3_512::new();
@@ -64,16 +117,20 @@ impl Ciphertext{
 
         let result = mac.finalize().into_bytes().to_vec();
 
-        Ciphertext{
+        (Ciphertext{
             R,
             e,
             nonce,
             mac: result
-        }
+        }, P)
     }
 
     pub fn decrypt(&self, sk: &Scalar, label: &[u8]) -> Result<Vec<u8>,LpkeError>{
-        let P = sk*self.R;
+        self.open_shared(&(sk*self.R), label)
+    }
+
+    /// decrypts with the shared secret
+    fn open_shared(&self, P: &RistrettoPoint, label: &[u8]) -> Result<Vec<u8>,LpkeError>{
         let mut hasher = Sha3_512::new();
         hasher.update(P.compress().as_bytes());
         hasher.update(label);
@@ -126,4 +183,34 @@ mod tests {
 
         assert_eq!(ek.to_vec(),msg.unwrap());
     }
+
+    #[test]
+    fn view_tags() {
+        let mut csrng = thread_rng();
+        let sk = Scalar::random(&mut csrng);
+        let pk = sk*RISTRETTO_BASEPOINT_POINT;
+
+        let (c, tag) = Ciphertext::encrypt_with_view_tag(&pk, b"label", b"message");
+        assert!(c.may_be_for(&sk, tag));
+        assert_eq!(c.decrypt(&sk, b"label").unwrap(), b"message".to_vec());
+
+        // over another base the same secret key opens it
+        let base = RistrettoPoint::random(&mut csrng);
+        let (over, over_tag) = Ciphertext::encrypt_with_view_tag_over(&base, &(sk*base), b"label", b"message");
+        assert!(over.may_be_for(&sk, over_tag));
+        assert_eq!(over.decrypt(&sk, b"label").unwrap(), b"message".to_vec());
+
+        // a wrong key gets through the view tag about 1 in 256 times
+        let skipped = (0..256).filter(|_| !c.may_be_for(&Scalar::random(&mut csrng), tag)).count();
+        assert!(skipped > 200);
+
+        // so does the randomness it was made with
+        let r = Scalar::random(&mut csrng);
+        let (from, from_tag) = Ciphertext::encrypt_with_view_tag_from(&r, &base, &(sk*base), b"label", b"message");
+        assert!(from.may_be_for(&sk, from_tag));
+        assert_eq!(from.decrypt(&sk, b"label").unwrap(), b"message".to_vec());
+        assert_eq!(from.open_with_randomness(&r, &base, &(sk*base), b"label").unwrap(), b"message".to_vec());
+        assert!(from.open_with_randomness(&r, &RISTRETTO_BASEPOINT_POINT, &pk, b"label").is_err());
+        assert!(from.open_with_randomness(&Scalar::random(&mut csrng), &base, &(sk*base), b"label").is_err());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,79 @@
+//! seed phrases: the 32 byte seed an account is made from (see Account::new) written as 24 words of the bip39 english
+//! word list, the last of which carries a checksum so typos are caught before they make the wrong account
+use std::convert::TryInto;
+use std::fmt;
+use bip39::{Language, Mnemonic};
+use rand::{thread_rng, Rng};
+
+use crate::account::Account;
+
+/// the number of words in a seed phrase
+pub const PHRASE_WORDS: usize = 24;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MnemonicError{
+    WrongLength(usize),
+    UnknownWord(String),
+    BadChecksum,
+}
+
+impl fmt::Display for MnemonicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MnemonicError::WrongLength(n) => write!(f, "the phrase has {} words but it should have {}", n, PHRASE_WORDS),
+            MnemonicError::UnknownWord(w) => write!(f, "\"{}\" isn't one of the seed phrase words", w),
+            MnemonicError::BadChecksum => write!(f, "the words don't fit together, one of them is probably mistyped"),
+        }
+    }
+}
+
+/// a new random seed
+pub fn new_seed() -> [u8; 32] {
+    thread_rng().gen()
+}
+
+/// the phrase of a seed
+pub fn to_phrase(seed: &[u8; 32]) -> String {
+    Mnemonic::from_entropy_in(Language::English, seed).unwrap().to_string()
+}
+
+/// the seed of a phrase (extra spaces and capitals are ignored)
+pub fn from_phrase(phrase: &str) -> Result<[u8; 32], MnemonicError> {
+    let words = phrase.split_whitespace().map(|w| w.to_lowercase()).collect::<Vec<_>>();
+    if words.len() != PHRASE_WORDS {
+        return Err(MnemonicError::WrongLength(words.len()))
+    }
+    match Mnemonic::parse_in_normalized(Language::English, &words.join(" ")) {
+        Ok(m) => Ok(m.to_entropy()[..].try_into().unwrap()),
+        Err(bip39::Error::UnknownWord(i)) => Err(MnemonicError::UnknownWord(words[i].clone())),
+        Err(_) => Err(MnemonicError::BadChecksum),
+    }
+}
+
+/// the account of a phrase after the panic button was pressed generation times (its staking account is stake_acc)
+pub fn account(phrase: &str, generation: u32) -> Result<Account, MnemonicError> {
+    Ok(Account::from_seed_generation(&from_phrase(phrase)?, generation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phrases() {
+        let seed = new_seed();
+        let phrase = to_phrase(&seed);
+        assert_eq!(phrase.split(' ').count(), PHRASE_WORDS);
+        assert_eq!(from_phrase(&phrase), Ok(seed));
+        assert_eq!(from_phrase(&format!("  {}\n", phrase.to_uppercase().replace(' ', "   "))), Ok(seed));
+        assert_eq!(account(&phrase, 1).unwrap().stake_acc(), Account::from_seed_generation(&seed, 1).stake_acc());
+
+        let mut words = phrase.split(' ').collect::<Vec<_>>();
+        assert_eq!(from_phrase(&words[1..].join(" ")), Err(MnemonicError::WrongLength(23)));
+        words[3] = "khora";
+        assert_eq!(from_phrase(&words.join(" ")), Err(MnemonicError::UnknownWord("khora".to_string())));
+        let zeros = format!("{} art", vec!["abandon"; 23].join(" "));
+        assert_eq!(from_phrase(&zeros), Ok([0u8; 32]));
+        assert_eq!(from_phrase(&zeros.replace("art", "abandon")), Err(MnemonicError::BadChecksum));
+    }
+}
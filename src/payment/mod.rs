@@ -0,0 +1,114 @@
+//! proofs that an output on chain was paid to an address with an amount
+//!
+//! when the sender derives an output they know its one time key ek and the opening of its commitment. ek shows the
+//! output's key is the recipient's key moved by hash(pk || ek) (so only the recipient can spend it) and the opening
+//! shows the amount. the randomness of the output's tracking key ciphertext is derived from ek, so ek also opens that
+//! ciphertext with the recipient's tracking key and shows the recipient can find the output. none of it says anything
+//! about the sender's or the recipient's secret keys.
+//!
+//! outputs made before the ciphertext's randomness came from ek can't be proven.
+use std::convert::TryInto;
+use curve25519_dalek::scalar::Scalar;
+use serde::{Serialize, Deserialize};
+
+use crate::account::{Account, OTAccount, one_time_s, eek_randomness};
+use crate::commitment::Commitment;
+use crate::validation::History;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PaymentError{
+    NotSenderRecord,
+    WrongRecipient,
+    WrongAmount,
+    WrongCiphertext,
+    WrongLocation,
+}
+
+/// proves the output at location was paid to a recipient with amount
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PaymentProof {
+    pub location: u64,
+    pub amount: u64,
+    ek: Vec<u8>,
+    randomness: Scalar,
+}
+
+impl PaymentProof {
+    /// made by the sender from the output derive_ot gave them (before it was published) once it's at location on chain
+    pub fn new(location: u64, sent: &OTAccount) -> Result<PaymentProof, PaymentError> {
+        match (&sent.ek, sent.com.amount, sent.com.randomness) {
+            (Some(ek), Some(amount), Some(randomness)) => Ok(PaymentProof {
+                location,
+                amount: u64::from_le_bytes(amount.as_bytes()[..8].try_into().unwrap()),
+                ek: ek.clone(),
+                randomness,
+            }),
+            _ => Err(PaymentError::NotSenderRecord),
+        }
+    }
+
+    /// checks the proof against the recipient's public keys and the output as it is in its block (the history doesn't
+    /// have the tracking key ciphertext but it says the output is the one at location)
+    pub fn verify(&self, recipient: &Account, output: &OTAccount, history: &History) -> Result<(), PaymentError> {
+        if recipient.pk + Account::tag_k_gen(one_time_s(&recipient.pk.compress(), &self.ek)) != output.pk {
+            return Err(PaymentError::WrongRecipient)
+        }
+        if Commitment::commit(&Scalar::from(self.amount), &self.randomness).com != output.com.com {
+            return Err(PaymentError::WrongAmount)
+        }
+        let mut label = output.pk.compress().as_bytes().to_vec();
+        label.extend(output.com.com.compress().as_bytes());
+        match output.eek.as_ref().map(|eek| eek.open_with_randomness(&eek_randomness(&self.ek), &recipient.encryption_base(), &recipient.apk, &label)) {
+            Some(Ok(ek)) if ek == self.ek => {}
+            _ => return Err(PaymentError::WrongCiphertext),
+        }
+        match history.get_many(&[self.location]) {
+            Ok(x) if x[0] == [output.pk.compress(), output.com.com.compress()] => Ok(()),
+            _ => Err(PaymentError::WrongLocation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::storage::{MemoryStorage, Storage};
+
+    #[test]
+    fn prove_payment() {
+        let merchant = Account::new(&"merchant");
+        let address = Account::from_pks(&merchant.pk.compress(), &merchant.apk.compress(), &merchant.vpk.compress());
+        let sent = address.derive_ot(&Scalar::from(250u64));
+        let on_chain = sent.publish_offer();
+        let history = History::new(Arc::new(MemoryStorage::new()) as Arc<dyn Storage>);
+        history.initialize();
+        let other = Account::new(&"other").derive_ot(&Scalar::from(1u64));
+        history.append(&vec![other.clone(), on_chain.clone(), other]);
+
+        let proof = PaymentProof::new(1, &sent).unwrap();
+        let proof: PaymentProof = bincode::deserialize(&bincode::serialize(&proof).unwrap()).unwrap();
+        assert_eq!(proof.amount, 250);
+        assert_eq!(PaymentProof { location: 2, ..proof.clone() }.verify(&address, &on_chain, &history), Err(PaymentError::WrongLocation));
+        assert_eq!(PaymentProof { location: 4, ..proof.clone() }.verify(&address, &on_chain, &history), Err(PaymentError::WrongLocation));
+        assert_eq!(proof.verify(&address, &on_chain, &history), Ok(()));
+        assert_eq!(proof.verify(&Account::new(&"someone else"), &on_chain, &history), Err(PaymentError::WrongRecipient));
+        assert_eq!(PaymentProof { amount: 251, ..proof.clone() }.verify(&address, &on_chain, &history), Err(PaymentError::WrongAmount));
+        assert_eq!(proof.verify(&address, &address.derive_ot(&Scalar::from(250u64)), &history), Err(PaymentError::WrongRecipient));
+        assert_eq!(PaymentProof::new(17, &on_chain), Err(PaymentError::NotSenderRecord));
+
+        // the recipient has to be able to find it
+        let mut unfindable = on_chain.clone();
+        unfindable.eek = address.derive_ot(&Scalar::from(250u64)).eek.clone();
+        assert_eq!(proof.verify(&address, &unfindable, &history), Err(PaymentError::WrongCiphertext));
+        let wrong_apk = Account::from_pks(&merchant.pk.compress(), &merchant.vpk.compress(), &merchant.vpk.compress());
+        assert_eq!(proof.verify(&wrong_apk, &on_chain, &history), Err(PaymentError::WrongCiphertext));
+
+        // subaddresses too
+        let sub = Account::from_name(&merchant.subaddress(3).name()).unwrap();
+        let sent = sub.derive_ot(&Scalar::from(9u64));
+        history.append(&vec![sent.publish_offer()]);
+        assert_eq!(PaymentProof::new(3, &sent).unwrap().verify(&sub, &sent.publish_offer(), &history), Ok(()));
+        assert!(merchant.subaddresses(4).receive_ot(&sent.publish_offer()).is_ok());
+    }
+}
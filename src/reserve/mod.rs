@@ -0,0 +1,274 @@
+//! reserve proofs that show a wallet holds at least an amount in unspent outputs (like an exchange showing it holds its customers' money)
+//!
+//! the proof lists the outputs by location with their tags. each tag comes with a proof that it's the tag of the output
+//! (so the prover knows the output's key) and the verifier checks none of the tags have been spent. the amounts stay
+//! hidden: the sum of the commitments minus claimed*B is a commitment to what's left over and a bulletproof shows
+//! that's between 0 and 2^64 so the outputs add up to at least claimed.
+//!
+//! publishing the tags is a trade-off, not a leak we hide: a tag is what a spend reveals, so anyone holding the proof
+//! can tell when (and in which transaction) each of the outputs is spent later. there's no proof here that a tag is
+//! unspent without showing it. only prove reserves with outputs you don't mind being linked to their spends (or move
+//! them to fresh outputs afterwards).
+#![allow(non_snake_case)]
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::iter;
+use curve25519_dalek::constants::{RISTRETTO_BASEPOINT_POINT, RISTRETTO_BASEPOINT_TABLE};
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::{MultiscalarMul, VartimeMultiscalarMul};
+use merlin::Transcript;
+use serde::{Serialize, Deserialize};
+
+use crate::account::{OTAccount, Tag, TagProof};
+use crate::constants::{PEDERSEN_H, PEDERSEN_H_TABLE};
+use crate::external::inner_product_proof::InnerProductProof;
+use crate::external::transcript::TranscriptProtocol;
+use crate::external::util::{exp_iter, sum_of_powers, VecPoly1};
+use crate::validation::History;
+
+/// the number of bits the amount over the claim is proven to fit in
+const BITS: usize = 64;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ReserveError{
+    NotOurOutput,
+    WatchOnly,
+    DuplicateOutput,
+    NotEnough,
+    MissingOutput,
+    SpentOutput,
+    InvalidProof,
+}
+
+/// a bulletproof that a commitment (to B and H) is to an amount under 2^BITS
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct ExcessProof {
+    A: CompressedRistretto,
+    S: CompressedRistretto,
+    T1: CompressedRistretto,
+    T2: CompressedRistretto,
+    tau: Scalar,
+    mu: Scalar,
+    t: Scalar,
+    ipp_proof: InnerProductProof,
+}
+
+/// the generators of the bit vectors (taken from the transcript like the seal's)
+fn excess_generators(transcript: &mut Transcript) -> (Vec<RistrettoPoint>, Vec<RistrettoPoint>) {
+    let G = (0..BITS).map(|_| transcript.challenge_point(b"G")).collect();
+    let H = (0..BITS).map(|_| transcript.challenge_point(b"H")).collect();
+    (G, H)
+}
+
+impl ExcessProof {
+    fn prove(transcript: &mut Transcript, amount: u64, blind: &Scalar) -> ExcessProof {
+        let mut rng = rand::thread_rng();
+        transcript.vsigma_domain_sep(BITS as u64);
+        let (G, H) = excess_generators(transcript);
+
+        let aL = (0..BITS).map(|i| Scalar::from((amount >> i) & 1)).collect::<Vec<_>>();
+        let aR = aL.iter().map(|b| b - Scalar::one()).collect::<Vec<_>>();
+        let alpha = Scalar::random(&mut rng);
+        let A = &alpha*PEDERSEN_H_TABLE() + RistrettoPoint::multiscalar_mul(aL.iter().chain(aR.iter()), G.iter().chain(H.iter()));
+        let sL = (0..BITS).map(|_| Scalar::random(&mut rng)).collect::<Vec<_>>();
+        let sR = (0..BITS).map(|_| Scalar::random(&mut rng)).collect::<Vec<_>>();
+        let rho = Scalar::random(&mut rng);
+        let S = &rho*PEDERSEN_H_TABLE() + RistrettoPoint::multiscalar_mul(sL.iter().chain(sR.iter()), G.iter().chain(H.iter()));
+        transcript.append_point(b"A", &A.compress());
+        transcript.append_point(b"S", &S.compress());
+
+        let y = transcript.challenge_scalar(b"y");
+        let z = transcript.challenge_scalar(b"z");
+        let yexp = exp_iter(y).take(BITS).collect::<Vec<_>>();
+        let twoexp = exp_iter(Scalar::from(2u64)).take(BITS).collect::<Vec<_>>();
+        let l_x = VecPoly1(aL.iter().map(|a| a - z).collect(), sL);
+        let r_x = VecPoly1(
+            (0..BITS).map(|i| yexp[i]*(aR[i] + z) + z*z*twoexp[i]).collect(),
+            (0..BITS).map(|i| yexp[i]*sR[i]).collect(),
+        );
+        let t_x = l_x.inner_product(&r_x);
+
+        let tau_1 = Scalar::random(&mut rng);
+        let tau_2 = Scalar::random(&mut rng);
+        let T1 = &t_x.1*&RISTRETTO_BASEPOINT_TABLE + &tau_1*PEDERSEN_H_TABLE();
+        let T2 = &t_x.2*&RISTRETTO_BASEPOINT_TABLE + &tau_2*PEDERSEN_H_TABLE();
+        transcript.append_point(b"T1", &T1.compress());
+        transcript.append_point(b"T2", &T2.compress());
+
+        let x = transcript.challenge_scalar(b"x");
+        let tau = z*z*blind + tau_1*x + tau_2*x*x;
+        let mu = alpha + rho*x;
+        let t = t_x.eval(x);
+        transcript.append_scalar(b"tau", &tau);
+        transcript.append_scalar(b"mu", &mu);
+        transcript.append_scalar(b"t", &t);
+
+        let Q = transcript.challenge_scalar(b"ippw")*RISTRETTO_BASEPOINT_POINT;
+        let H_factors = exp_iter(y.invert()).take(BITS).collect::<Vec<_>>();
+        let ipp_proof = InnerProductProof::create(transcript, &Q, &vec![Scalar::one(); BITS], &H_factors, G, H, l_x.eval(x), r_x.eval(x));
+        ExcessProof { A: A.compress(), S: S.compress(), T1: T1.compress(), T2: T2.compress(), tau, mu, t, ipp_proof }
+    }
+
+    fn verify(&self, transcript: &mut Transcript, com: &RistrettoPoint) -> bool {
+        transcript.vsigma_domain_sep(BITS as u64);
+        let (G, H) = excess_generators(transcript);
+        let points = [self.A, self.S, self.T1, self.T2].iter().map(|x| x.decompress()).collect::<Option<Vec<_>>>();
+        let (A, S, T1, T2) = match points {
+            Some(p) => (p[0], p[1], p[2], p[3]),
+            None => return false,
+        };
+        transcript.append_point(b"A", &self.A);
+        transcript.append_point(b"S", &self.S);
+        let y = transcript.challenge_scalar(b"y");
+        let z = transcript.challenge_scalar(b"z");
+        transcript.append_point(b"T1", &self.T1);
+        transcript.append_point(b"T2", &self.T2);
+        let x = transcript.challenge_scalar(b"x");
+        transcript.append_scalar(b"tau", &self.tau);
+        transcript.append_scalar(b"mu", &self.mu);
+        transcript.append_scalar(b"t", &self.t);
+        let Q = transcript.challenge_scalar(b"ippw")*RISTRETTO_BASEPOINT_POINT;
+
+        // t(x) = z^2 amount + delta + t1 x + t2 x^2
+        let delta = (z - z*z)*sum_of_powers(&y, BITS) - z*z*z*sum_of_powers(&Scalar::from(2u64), BITS);
+        if &self.t*&RISTRETTO_BASEPOINT_TABLE + &self.tau*PEDERSEN_H_TABLE() != z*z*com + delta*RISTRETTO_BASEPOINT_POINT + x*T1 + x*x*T2 {
+            return false
+        }
+
+        let yinv = exp_iter(y.invert()).take(BITS).collect::<Vec<_>>();
+        let twoexp = exp_iter(Scalar::from(2u64)).take(BITS);
+        let hscalars = yinv.iter().zip(twoexp).map(|(yi, two)| z + z*z*two*yi).collect::<Vec<_>>();
+        let P = RistrettoPoint::vartime_multiscalar_mul(
+            [Scalar::one(), x, -self.mu, self.t].iter().copied().chain(iter::repeat_n(-z, BITS)).chain(hscalars),
+            [A, S, PEDERSEN_H(), Q].iter().copied().chain(G.iter().copied()).chain(H.iter().copied()),
+        );
+        self.ipp_proof.verify(BITS, transcript, vec![Scalar::one(); BITS], yinv, &P, &Q, &G, &H).is_ok()
+    }
+}
+
+/// proves the outputs at locations are unspent and hold at least claimed between them. it reveals their tags so their
+/// later spends are linked to it
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ReserveProof {
+    pub claimed: u64,
+    pub locations: Vec<u64>,
+    pub revealed_tags: Vec<Tag>,
+    ownership: Vec<TagProof>,
+    excess: ExcessProof,
+}
+
+/// what the reserve proof is bound to
+fn reserve_transcript(claimed: u64, locations: &[u64], outputs: &[&OTAccount], tags: &[Tag]) -> Transcript {
+    let mut transcript = Transcript::new(b"reserve proof");
+    transcript.append_u64(b"claimed", claimed);
+    for ((location, output), tag) in locations.iter().zip(outputs).zip(tags) {
+        transcript.append_u64(b"location", *location);
+        transcript.append_point(b"pk", &output.pk.compress());
+        transcript.append_point(b"com", &output.com.com.compress());
+        transcript.append_point(b"tag", tag);
+    }
+    transcript
+}
+
+impl ReserveProof {
+    /// made from outputs you received (and can spend) with their locations. use [`Account::prove_reserve_revealing_tags`](crate::account::Account::prove_reserve_revealing_tags)
+    pub(crate) fn prove(outputs: &[(u64, OTAccount)], claimed: u64) -> Result<ReserveProof, ReserveError> {
+        let locations = outputs.iter().map(|x| x.0).collect::<Vec<_>>();
+        if locations.iter().collect::<HashSet<_>>().len() != locations.len() {
+            return Err(ReserveError::DuplicateOutput)
+        }
+        let owned = outputs.iter().map(|x| &x.1).collect::<Vec<_>>();
+        let mut keys = vec![];
+        let mut amount = Scalar::zero();
+        let mut blind = Scalar::zero();
+        for output in owned.iter() {
+            match (output.get_sk(), output.get_tag(), output.com.amount, output.com.randomness) {
+                (Ok(sk), Ok(tag), Some(a), Some(r)) => {
+                    keys.push((sk, tag));
+                    amount += a;
+                    blind += r;
+                }
+                _ => return Err(ReserveError::NotOurOutput),
+            }
+        }
+        let total = u128::from_le_bytes(amount.as_bytes()[..16].try_into().unwrap());
+        if total < claimed as u128 {
+            return Err(ReserveError::NotEnough)
+        }
+        // outputs are under 2^BETA so whatever a wallet can hold fits
+        let excess = (total - claimed as u128).try_into().map_err(|_| ReserveError::InvalidProof)?;
+
+        let tags = keys.iter().map(|x| x.1).collect::<Vec<_>>();
+        let mut transcript = reserve_transcript(claimed, &locations, &owned, &tags);
+        let ownership = keys.iter().map(|(sk, tag)| TagProof::prove(&mut transcript, sk, &tag.decompress().unwrap())).collect();
+        let excess = ExcessProof::prove(&mut transcript, excess, &blind);
+        Ok(ReserveProof { claimed, locations, revealed_tags: tags, ownership, excess })
+    }
+
+    /// checks the proof against the outputs at its locations. spent says if a tag has been spent (like SpentTags::contains)
+    pub fn verify<F: Fn(&Tag) -> bool>(&self, outputs: &[OTAccount], spent: F) -> Result<(), ReserveError> {
+        if outputs.len() != self.locations.len() || self.revealed_tags.len() != self.locations.len() || self.ownership.len() != self.locations.len() {
+            return Err(ReserveError::InvalidProof)
+        }
+        if self.locations.iter().collect::<HashSet<_>>().len() != self.locations.len() {
+            return Err(ReserveError::DuplicateOutput)
+        }
+        if self.revealed_tags.iter().any(spent) {
+            return Err(ReserveError::SpentOutput)
+        }
+        let outputs = outputs.iter().collect::<Vec<_>>();
+        let mut transcript = reserve_transcript(self.claimed, &self.locations, &outputs, &self.revealed_tags);
+        for ((output, tag), proof) in outputs.iter().zip(&self.revealed_tags).zip(&self.ownership) {
+            match tag.decompress() {
+                Some(t) if proof.verify(&mut transcript, &output.pk, &t) => {},
+                _ => return Err(ReserveError::InvalidProof),
+            }
+        }
+        let com = outputs.iter().map(|x| x.com.com).sum::<RistrettoPoint>() - Scalar::from(self.claimed)*RISTRETTO_BASEPOINT_POINT;
+        if !self.excess.verify(&mut transcript, &com) {
+            return Err(ReserveError::InvalidProof)
+        }
+        Ok(())
+    }
+
+    /// checks the proof against the outputs at its locations in the history
+    pub fn verify_in<F: Fn(&Tag) -> bool>(&self, history: &History, spent: F) -> Result<(), ReserveError> {
+        let outputs = history.get_accounts(&self.locations).map_err(|_| ReserveError::MissingOutput)?;
+        self.verify(&outputs, spent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Account;
+
+    #[test]
+    fn prove_reserve() {
+        let exchange = Account::new(&"exchange");
+        let owned = [30u64, 12, 8].iter().enumerate().map(|(i, &a)| {
+            (i as u64 * 3, exchange.receive_ot(&exchange.derive_ot(&Scalar::from(a)).publish_offer()).unwrap())
+        }).collect::<Vec<_>>();
+        let on_chain = owned.iter().map(|x| x.1.publish_offer()).collect::<Vec<_>>();
+
+        let proof = exchange.prove_reserve_revealing_tags(&owned, 45).unwrap();
+        let proof: ReserveProof = bincode::deserialize(&bincode::serialize(&proof).unwrap()).unwrap();
+        assert_eq!(proof.verify(&on_chain, |_| false), Ok(()));
+        assert_eq!(exchange.prove_reserve_revealing_tags(&owned, 50).unwrap().verify(&on_chain, |_| false), Ok(()));
+        assert_eq!(exchange.prove_reserve_revealing_tags(&owned, 51).err(), Some(ReserveError::NotEnough));
+
+        // claiming more than the proof was made for, spent tags and other outputs are caught
+        assert_eq!(ReserveProof { claimed: 51, ..proof.clone() }.verify(&on_chain, |_| false), Err(ReserveError::InvalidProof));
+        assert_eq!(proof.verify(&on_chain, |x| *x == proof.revealed_tags[1]), Err(ReserveError::SpentOutput));
+        let mut swapped = on_chain.clone();
+        swapped[2] = exchange.derive_ot(&Scalar::from(8u64)).publish_offer();
+        assert_eq!(proof.verify(&swapped, |_| false), Err(ReserveError::InvalidProof));
+
+        let twice = vec![owned[0].clone(), owned[0].clone()];
+        assert_eq!(exchange.prove_reserve_revealing_tags(&twice, 1).err(), Some(ReserveError::DuplicateOutput));
+        let watcher = Account::watch_only(&exchange.pk.compress(), &exchange.ask, &exchange.vsk);
+        assert_eq!(watcher.prove_reserve_revealing_tags(&owned, 1).err(), Some(ReserveError::WatchOnly));
+        assert_eq!(exchange.prove_reserve_revealing_tags(&[(0, on_chain[0].clone())], 1).err(), Some(ReserveError::NotOurOutput));
+    }
+}
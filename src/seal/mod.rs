@@ -1,15 +1,15 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 use core::iter;
-use curve25519_dalek::constants::{RISTRETTO_BASEPOINT_POINT};
+use curve25519_dalek::constants::{RISTRETTO_BASEPOINT_POINT, RISTRETTO_BASEPOINT_TABLE};
 use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
 use curve25519_dalek::scalar::Scalar;
-use curve25519_dalek::traits::{VartimeMultiscalarMul, MultiscalarMul};
+use curve25519_dalek::traits::{VartimeMultiscalarMul, MultiscalarMul, IsIdentity};
 use merlin::Transcript;
 use rayon::prelude::*;
 use serde::{Serialize, Deserialize};
 
-use crate::constants::{PEDERSEN_H};
+use crate::constants::{PEDERSEN_H, PEDERSEN_H_TABLE};
 use crate::external::inner_product_proof::{InnerProductProof, inner_product};
 use crate::external::transcript::TranscriptProtocol;
 use crate::external::inner_product_proof;
@@ -66,14 +66,19 @@ impl BinRep for Scalar {
 }
 
 impl SealSig{
-    fn get_G(u: &Scalar, v: &Scalar, ring: &[&OTAccount], tags: &[&Tag], _outputs: &[&OTAccount], w: &Scalar, P: &Vec<RistrettoPoint>, Gprime: &Vec<RistrettoPoint> ) -> Vec<RistrettoPoint> {
+    /// the generators that come from the ring: the basepoint, PEDERSEN_H, the tags and every pk + u*com
+    /// signing needs them twice (with and without w) so they're only made once
+    fn get_innerG(u: &Scalar, v: &Scalar, ring: &[&OTAccount], tags: &[&Tag]) -> Vec<RistrettoPoint> {
         let T_hat = RistrettoPoint::vartime_multiscalar_mul(
             exp_iter(*v).take(tags.len()).map(|vexp|u*u*vexp), //exp_iter makes a vector of powers
             tags.iter().map(|tag| tag.decompress().unwrap()));
 
         let mut innerG = vec![RISTRETTO_BASEPOINT_POINT, PEDERSEN_H(), T_hat];
         innerG.par_extend(ring.par_iter().map(|inp| inp.pk + u*inp.com.com).collect::<Vec<RistrettoPoint>>());
+        innerG
+    }
 
+    fn get_G(innerG: &[RistrettoPoint], w: &Scalar, P: &[RistrettoPoint], Gprime: &[RistrettoPoint]) -> Vec<RistrettoPoint> {
         let mut Gw: Vec<RistrettoPoint> = innerG.par_iter().zip(P).map(|(gP,iP)|w*gP + iP).collect();
         Gw.par_extend(Gprime);
         Gw
@@ -186,7 +191,8 @@ impl SealSig{
         let Gprime: Vec<RistrettoPoint> = (0..(ring.len()*tags.len() + outputs.len()*BETA + 3*tags.len())).map(|_| transcript.challenge_point(b"Gprime")).collect();
         let H: Vec<RistrettoPoint> = (0..m).map(|_| transcript.challenge_point(b"H")).collect();
 
-        let G0 = SealSig::get_G(&u, &v, &ring, &tags,&outputs, &Scalar::zero(), &P, &Gprime );
+        let innerG = SealSig::get_innerG(&u, &v, ring, tags);
+        let G0 = [&P[..], &Gprime[..]].concat();
 
         let mut csrng = rand::thread_rng();
         let rA = Scalar::random(&mut csrng);
@@ -264,7 +270,7 @@ impl SealSig{
         let w = transcript.challenge_scalar(b"w");
 
         let rS = Scalar::random(&mut csrng);
-        let Gw = SealSig::get_G(&u, &v, &ring, &tags, &outputs, &w, &P, &Gprime );
+        let Gw = SealSig::get_G(&innerG, &w, &P, &Gprime );
         let sl: Vec<Scalar> = (0..Gw.len()).map(|_| Scalar::random(&mut csrng)).collect();
         let sr: Vec<Scalar> = cr.iter().map(|c| { match *c == Scalar::zero() {
             true => Scalar::zero(),
@@ -288,8 +294,8 @@ impl SealSig{
         let tau_1 = Scalar::random(&mut csrng);
         let tau_2 = Scalar::random(&mut csrng);
 
-        let T1 = t_x.1*RISTRETTO_BASEPOINT_POINT + tau_1*PEDERSEN_H();
-        let T2 = t_x.2*RISTRETTO_BASEPOINT_POINT + tau_2*PEDERSEN_H();
+        let T1 = &t_x.1*&RISTRETTO_BASEPOINT_TABLE + &tau_1*PEDERSEN_H_TABLE();
+        let T2 = &t_x.2*&RISTRETTO_BASEPOINT_TABLE + &tau_2*PEDERSEN_H_TABLE();
 
         transcript.append_point(b"T1 commitment", &T1.compress());
         transcript.append_point(b"T2 commitment", &T2.compress());
@@ -358,7 +364,8 @@ impl SealSig{
         })
     }
 
-    pub fn verify(&self, transcript: &mut Transcript, ring: &[&OTAccount], tags: &[&Tag], outputs: &[&OTAccount]) -> Result<(), SealError> {
+    /// recomputes the challenges and generators of the proof from the transcript (up to the inner product proof)
+    fn replay(&self, transcript: &mut Transcript, ring: &[&OTAccount], tags: &[&Tag], outputs: &[&OTAccount]) -> Replay {
 
         let m = 3 + ring.len() + ring.len()*tags.len() + outputs.len()*BETA + 3 * tags.len();
 
@@ -401,7 +408,7 @@ impl SealSig{
         let x = transcript.challenge_scalar(b"x");
 
         
-        let Gw = SealSig::get_G(&u, &v, &ring, &tags, &outputs, &w, &P, &Gprime );
+        let Gw = SealSig::get_G(&SealSig::get_innerG(&u, &v, ring, tags), &w, &P, &Gprime );
         
 
         transcript.append_scalar(b"tau", &self.tau);
@@ -412,6 +419,30 @@ impl SealSig{
 
         let ippw = transcript.challenge_scalar(b"ippw");
 
+        let padlen = m.next_power_of_two() -m;
+
+        let mut H_factors: Vec<Scalar> = theta_inv;
+        H_factors.par_extend(vec![Scalar::zero(); padlen]);
+
+        let mut Gpad = Gw;
+        let mut Hpad = H;
+        
+        for _ in 0..padlen {
+            Gpad.push(transcript.challenge_point(b"padding G"));
+            Hpad.push(transcript.challenge_point(b"padding H"));
+        }
+
+        Replay { m, x, y, z, F, ippw, alpha, beta, delta, H_factors, Gpad, Hpad }
+    }
+
+    pub fn verify(&self, transcript: &mut Transcript, ring: &[&OTAccount], tags: &[&Tag], outputs: &[&OTAccount]) -> Result<(), SealError> {
+
+        let Replay { m, x, y, z, F, ippw, alpha, beta, delta, H_factors, Gpad, Hpad } = self.replay(transcript, ring, tags, outputs);
+        let A = self.A.decompress().ok_or(SealError::VerificationError)?;
+        let S = self.S.decompress().ok_or(SealError::VerificationError)?;
+        let T1 = self.T1.decompress().ok_or(SealError::VerificationError)?;
+        let T2 = self.T2.decompress().ok_or(SealError::VerificationError)?;
+
         let Q = ippw * RISTRETTO_BASEPOINT_POINT;
 
         
@@ -421,38 +452,24 @@ impl SealSig{
                                                                 .chain(beta)
                                                                 .chain(iter::once(-self.r))
                                                                 .chain(iter::once(self.t)),
-                                                            iter::once(self.A.decompress().unwrap())
-                                                                .chain(iter::once(self.S.decompress().unwrap()))
-                                                                .chain(Gw.clone()).chain(H.clone())
+                                                            iter::once(A)
+                                                                .chain(iter::once(S))
+                                                                .chain(Gpad[..m].iter().cloned()).chain(Hpad[..m].iter().cloned())
                                                                 .chain(iter::once(F))
                                                                 .chain(iter::once(Q)));
 
 
-        
-        let padlen = m.next_power_of_two() -m;
-
         let mut G_factors: Vec<Scalar> = iter::repeat(Scalar::one()).take(m).collect();
-        G_factors.par_extend(vec![Scalar::zero(); padlen]);
-        let mut H_factors: Vec<Scalar> = theta_inv;
-        H_factors.par_extend(vec![Scalar::zero(); padlen]);
-
-        let mut Gpad = Gw.clone();
-        let mut Hpad = H.clone();
-        
-        for _ in 0..padlen {
-            Gpad.push(transcript.challenge_point(b"padding G"));
-            Hpad.push(transcript.challenge_point(b"padding H"));
-        }
-        
+        G_factors.par_extend(vec![Scalar::zero(); Gpad.len() - m]);
         
         if self.ipp_proof.verify(Gpad.len(), transcript, G_factors, H_factors, &ipPmQ, &Q, &Gpad, &Hpad).is_err() {
             return Err(SealError::VerificationErrorIPP)
         }
 
         
-        let lnd = self.t*RISTRETTO_BASEPOINT_POINT + self.tau*PEDERSEN_H();
+        let lnd = &self.t*&RISTRETTO_BASEPOINT_TABLE + &self.tau*PEDERSEN_H_TABLE();
         let comsum = exp_iter(y).take(outputs.len()).zip(outputs).map(|(yexp,acct)| (z*z*yexp)*acct.com.com).sum::<RistrettoPoint>();
-        let rnd = delta*RISTRETTO_BASEPOINT_POINT + comsum + x*self.T1.decompress().unwrap() + x*x*self.T2.decompress().unwrap();
+        let rnd = delta*RISTRETTO_BASEPOINT_POINT + comsum + x*T1 + x*x*T2;
 
         if lnd != rnd {
             return Err(SealError::VerificationError)
@@ -461,8 +478,121 @@ impl SealSig{
 
         Ok(())
     }
+
+    /// the terms of both of verify's checks moved to one side and multiplied by random weights, so they add up to the identity
+    fn batch_terms(&self, statement: &SealStatement) -> Result<Terms, SealError> {
+        let mut transcript = statement.transcript.clone();
+        let Replay { m, x, y, z, F, ippw, alpha, beta, delta, H_factors, Gpad, Hpad } = self.replay(&mut transcript, &statement.ring, &statement.tags, &statement.outputs);
+        let (u_sq, u_inv_sq, s) = self.ipp_proof.verification_scalars(Gpad.len(), &mut transcript).map_err(|_| SealError::VerificationErrorIPP)?;
+        let decompress = |p: &CompressedRistretto| p.decompress().ok_or(SealError::VerificationError);
+        let Ls = self.ipp_proof.L_vec.iter().map(decompress).collect::<Result<Vec<_>, _>>()?;
+        let Rs = self.ipp_proof.R_vec.iter().map(decompress).collect::<Result<Vec<_>, _>>()?;
+
+        let mut csrng = rand::thread_rng();
+        let c = Scalar::random(&mut csrng);
+        let d = Scalar::random(&mut csrng);
+        let (a, b) = (self.ipp_proof.a, self.ipp_proof.b);
+        let n = s.len();
+
+        // the inner product proof: a*b*Q + <a*s, G> + <b*theta_inv/s, H> - <u^2, L> - <u^-2, R> = A + x*S + <alpha, G> + <beta, H> - r*F + t*Q
+        // (the padding generators have factors of 0 so they drop out)
+        let mut scalars: Vec<Scalar> = (0..m).map(|i| c*(a*s[i] - alpha[i])).collect();
+        scalars.extend((0..m).map(|i| c*(b*s[n - 1 - i]*H_factors[i] - beta[i])));
+        scalars.extend(u_sq.iter().chain(&u_inv_sq).map(|u| -c*u));
+        scalars.extend(vec![-c, -c*x, c*self.r]);
+        let mut points: Vec<RistrettoPoint> = Gpad[..m].iter().chain(&Hpad[..m]).chain(&Ls).chain(&Rs).cloned().collect();
+        points.extend(vec![decompress(&self.A)?, decompress(&self.S)?, F]);
+
+        // the commitments: t*B + tau*H = delta*B + z^2*<y^i, outputs> + x*T1 + x^2*T2
+        scalars.extend(exp_iter(y).take(statement.outputs.len()).map(|yexp| -d*z*z*yexp));
+        points.extend(statement.outputs.iter().map(|acct| acct.com.com));
+        scalars.extend(vec![-d*x, -d*x*x]);
+        points.extend(vec![decompress(&self.T1)?, decompress(&self.T2)?]);
+
+        Ok(Terms {
+            basepoint: c*ippw*(a*b - self.t) + d*(self.t - delta),
+            pedersen_h: d*self.tau,
+            scalars,
+            points,
+        })
+    }
+
+    /// verifies many proofs at once with one multiscalar multiplication. it only tells you if they're all valid
+    pub fn verify_batch(statements: &[SealStatement]) -> Result<(), SealError> {
+        let terms = statements.par_iter().map(|x| x.seal.batch_terms(x)).collect::<Result<Vec<_>, _>>()?;
+        if Terms::sum_is_identity(&terms) {
+            Ok(())
+        } else {
+            Err(SealError::VerificationError)
+        }
+    }
+
+    /// verifies many proofs at once and, if that fails, splits the batch in half until it finds the invalid ones
+    pub fn verify_each(statements: &[SealStatement]) -> Vec<Result<(), SealError>> {
+        let terms = statements.par_iter().map(|x| x.seal.batch_terms(x)).collect::<Vec<_>>();
+        let mut results = terms.iter().map(|x| x.as_ref().map(|_| ()).map_err(|e| e.clone())).collect::<Vec<_>>();
+        let (places, terms): (Vec<usize>, Vec<Terms>) = terms.into_iter().enumerate().filter_map(|(i, x)| x.ok().map(|x| (i, x))).unzip();
+        let mut batches = vec![(places.as_slice(), terms.as_slice())];
+        while let Some((places, terms)) = batches.pop() {
+            if terms.is_empty() || Terms::sum_is_identity(terms) {
+                continue
+            }
+            if terms.len() == 1 {
+                results[places[0]] = Err(SealError::VerificationError);
+                continue
+            }
+            let half = terms.len()/2;
+            batches.push((&places[..half], &terms[..half]));
+            batches.push((&places[half..], &terms[half..]));
+        }
+        results
+    }
+}
+
+/// a proof with everything it's verified against
+pub struct SealStatement<'a> {
+    pub seal: &'a SealSig,
+    pub transcript: Transcript,
+    pub ring: Vec<&'a OTAccount>,
+    pub tags: Vec<&'a Tag>,
+    pub outputs: Vec<&'a OTAccount>,
+}
+
+/// the challenges and generators a proof is checked with
+struct Replay {
+    m: usize,
+    x: Scalar,
+    y: Scalar,
+    z: Scalar,
+    F: RistrettoPoint,
+    ippw: Scalar,
+    alpha: Vec<Scalar>,
+    beta: Vec<Scalar>,
+    delta: Scalar,
+    H_factors: Vec<Scalar>,
+    Gpad: Vec<RistrettoPoint>,
+    Hpad: Vec<RistrettoPoint>,
+}
+
+/// a weighted multiscalar multiplication that's the identity if the proof is valid
+/// the basepoint and PEDERSEN_H are in every proof so their scalars are kept apart and added up
+struct Terms {
+    basepoint: Scalar,
+    pedersen_h: Scalar,
+    scalars: Vec<Scalar>,
+    points: Vec<RistrettoPoint>,
 }
 
+impl Terms {
+    /// tells you if the terms of all the proofs add up to the identity
+    fn sum_is_identity(terms: &[Terms]) -> bool {
+        let mut scalars = vec![terms.iter().map(|x| x.basepoint).sum::<Scalar>(), terms.iter().map(|x| x.pedersen_h).sum::<Scalar>()];
+        scalars.extend(terms.iter().flat_map(|x| x.scalars.iter().cloned()));
+        let mut points = vec![RISTRETTO_BASEPOINT_POINT, PEDERSEN_H()];
+        points.extend(terms.iter().flat_map(|x| x.points.iter().cloned()));
+        RistrettoPoint::vartime_multiscalar_mul(scalars, points).is_identity()
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -508,4 +638,41 @@ mod tests {
         let s = sigma.verify(&mut verifier_transcript, &sigin, &tags, &sigout);
         assert!(s.is_ok());
     }
+
+    #[test]
+    fn sealsig_batch() {
+        let acct = Account::new(&"hi".to_string());
+        let rings = (0..3u64).map(|i| {
+            let mut ring = get_test_ring(7);
+            ring[2] = acct.derive_ot(&Scalar::from(i + 5));
+            ring[4] = acct.derive_ot(&Scalar::from(3u64));
+            ring
+        }).collect::<Vec<_>>();
+        let outputs = (0..3u64).map(|i| vec![acct.derive_ot(&Scalar::from(i + 1)), acct.derive_ot(&Scalar::from(7u64))]).collect::<Vec<_>>();
+        let tags = rings.iter().map(|ring| vec![ring[2].get_tag().unwrap().clone(), ring[4].get_tag().unwrap().clone()]).collect::<Vec<_>>();
+        let mut seals = (0..3).map(|i| {
+            SealSig::sign(&mut Transcript::new(b"test example"), &rings[i].iter().collect::<Vec<_>>(), &tags[i].iter().collect::<Vec<_>>(), &[2, 4], &outputs[i].iter().collect::<Vec<_>>()).unwrap()
+        }).collect::<Vec<_>>();
+        let statements = |seals: &[SealSig]| -> Vec<Result<(), SealError>> {
+            let statements = (0..3).map(|i| SealStatement {
+                seal: &seals[i],
+                transcript: Transcript::new(b"test example"),
+                ring: rings[i].iter().collect(),
+                tags: tags[i].iter().collect(),
+                outputs: outputs[i].iter().collect(),
+            }).collect::<Vec<_>>();
+            assert_eq!(SealSig::verify_batch(&statements).is_ok(), statements.iter().all(|x| x.seal.verify(&mut x.transcript.clone(), &x.ring, &x.tags, &x.outputs).is_ok()));
+            SealSig::verify_each(&statements)
+        };
+        assert!(statements(&seals).iter().all(|x| x.is_ok()));
+
+        seals[1].t += Scalar::one();
+        let results = statements(&seals);
+        assert!(results[0].is_ok() && results[1].is_err() && results[2].is_ok());
+
+        seals[1].t -= Scalar::one();
+        seals[2].tau += Scalar::one();
+        let results = statements(&seals);
+        assert!(results[0].is_ok() && results[1].is_ok() && results[2].is_err());
+    }
 }
@@ -0,0 +1,356 @@
+//! an exact index of every tag that has been spent
+//!
+//! the bloom filter is fast but sometimes says a tag was spent when it wasn't, which would make an honest transaction
+//! impossible to spend. so anything the bloom filter finds is looked up here before the transaction is rejected.
+//!
+//! the tags are appended to [`SPENT`], which is journaled with the blocks so undoing a block removes its tags.
+//! [`SPENT_INDEX`] is a hash table (with linear probing) of positions in that file. the index is only a cache:
+//! every entry is compared with the tag it points to, so entries left behind by undone blocks are skipped
+//! and tags that were saved without being indexed (if the node crashed) are indexed when the file is opened.
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryInto;
+use std::hash::Hasher;
+use std::io;
+use std::sync::Arc;
+use ahash::AHasher;
+use rand::{Rng, thread_rng};
+use crate::bloom::BloomFile;
+use crate::storage::{Storage, SPENT, SPENT_INDEX};
+
+/// the size of a tag
+pub const TAG_SIZE: u64 = 32;
+/// the size of the index header: the number of slots, slots used and tags indexed (u64s) then the 2 hash keys (u128s)
+pub const INDEX_HEADER: u64 = 56;
+/// the number of slots a new index starts with (it doubles when it's half full)
+pub const INITIAL_SLOTS: u64 = 1 << 16;
+/// the number of slots read at once when probing the index
+const PROBE_SLOTS: u64 = 64;
+
+/// the header of the index
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Header {
+    slots: u64,
+    used: u64,
+    indexed: u64,
+    key1: u128,
+    key2: u128,
+}
+
+impl Header {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut h = Vec::with_capacity(INDEX_HEADER as usize);
+        h.extend(self.slots.to_le_bytes());
+        h.extend(self.used.to_le_bytes());
+        h.extend(self.indexed.to_le_bytes());
+        h.extend(self.key1.to_le_bytes());
+        h.extend(self.key2.to_le_bytes());
+        h
+    }
+
+    fn from_bytes(h: &[u8]) -> Header {
+        Header {
+            slots: u64::from_le_bytes(h[..8].try_into().unwrap()),
+            used: u64::from_le_bytes(h[8..16].try_into().unwrap()),
+            indexed: u64::from_le_bytes(h[16..24].try_into().unwrap()),
+            key1: u128::from_le_bytes(h[24..40].try_into().unwrap()),
+            key2: u128::from_le_bytes(h[40..56].try_into().unwrap()),
+        }
+    }
+
+    /// the slot the search for tag starts at
+    fn home(&self, tag: &[u8;32]) -> u64 {
+        let mut h = AHasher::new_with_keys(self.key1, self.key2);
+        h.write(tag);
+        h.finish() % self.slots
+    }
+}
+
+/// the spent tags and their index
+#[derive(Clone)]
+pub struct SpentTags {
+    storage: Arc<dyn Storage>,
+}
+
+impl SpentTags {
+    /// creates empty spent tag files
+    pub fn initialize(storage: &dyn Storage) -> io::Result<()> {
+        storage.write_all(SPENT, &[])?;
+        let mut rng = thread_rng();
+        let header = Header {
+            slots: INITIAL_SLOTS,
+            used: 0,
+            indexed: 0,
+            key1: rng.gen(),
+            key2: rng.gen(),
+        };
+        write_index(storage, header, &vec![0u8; (INITIAL_SLOTS*8) as usize])
+    }
+
+    /// opens the spent tags and indexes any that were saved without being indexed
+    /// nodes that don't save the history have no spent tags and never find anything
+    pub fn new(storage: Arc<dyn Storage>) -> SpentTags {
+        let spent = SpentTags { storage };
+        if spent.storage.exists(SPENT) {
+            spent.catch_up().expect("couldn't index the spent tags");
+        }
+        spent
+    }
+
+    /// the number of tags that have been spent
+    pub fn len(&self) -> u64 {
+        self.storage.len(SPENT).unwrap_or(0)/TAG_SIZE
+    }
+
+    /// tells you if no tags have been spent
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// tells you if the tag has definitely been spent (a node without spent tags has never seen one spent)
+    pub fn contains(&self, tag: &[u8;32]) -> io::Result<bool> {
+        if !self.storage.exists(SPENT) {
+            return Ok(false)
+        }
+        Ok(self.find(&self.header()?, self.len(), tag)?.0)
+    }
+
+    /// tells you which tags have been spent. the bloom filter is checked first and only its matches are looked up.
+    /// it fails if a match can't be looked up (callers should count those tags as spent so a broken index can't let a
+    /// double spend through)
+    pub fn spent_batch(&self, bloom: &BloomFile, tags: &[&[u8;32]]) -> io::Result<Vec<bool>> {
+        bloom.contains_batch(tags).into_iter().zip(tags).map(|(maybe, tag)| Ok(maybe && self.contains(tag)?)).collect()
+    }
+
+    /// saves the tags that were spent in a block (ones that are already saved are skipped)
+    /// they're appended together and the index slots they take are written in as few runs as they fit in
+    pub fn insert_batch(&self, tags: &[&[u8;32]]) -> io::Result<()> {
+        let mut header = self.catch_up()?;
+        let start = self.len();
+        let mut new = Vec::<[u8;32]>::new();
+        let mut seen = HashSet::new();
+        for tag in tags {
+            if seen.insert(*tag) && !self.find(&header, start, tag)?.0 {
+                new.push(**tag);
+            }
+        }
+        if new.is_empty() {
+            return Ok(())
+        }
+        self.storage.append(SPENT, &new.concat())?;
+        let count = start + new.len() as u64;
+        if (header.used + new.len() as u64)*2 > header.slots {
+            return self.rebuild(header.key1, header.key2, count)
+        }
+
+        let mut pending = BTreeMap::new();
+        for (pos, tag) in (start..).zip(&new) {
+            let (_, slot) = self.find_with(&header, count, tag, &pending)?;
+            pending.insert(slot, pos + 1);
+        }
+        let mut runs: Vec<(u64, Vec<u8>)> = vec![];
+        for (slot, s) in pending {
+            match runs.last_mut() {
+                Some((first, run)) if *first + run.len() as u64/8 == slot => run.extend(s.to_le_bytes()),
+                _ => runs.push((slot, s.to_le_bytes().to_vec())),
+            }
+        }
+        for (slot, run) in runs {
+            self.storage.write_at(SPENT_INDEX, INDEX_HEADER + slot*8, &run)?;
+        }
+        header.used += new.len() as u64;
+        header.indexed = count;
+        self.storage.write_at(SPENT_INDEX, 0, &header.to_bytes())
+    }
+
+    /// recreates the index from the spent tags (with twice as many slots as needed)
+    pub fn rebuild_index(&self) -> io::Result<()> {
+        let header = self.header()?;
+        self.rebuild(header.key1, header.key2, self.len())
+    }
+
+    /// reads the index header
+    fn header(&self) -> io::Result<Header> {
+        let mut h = [0u8; INDEX_HEADER as usize];
+        if self.storage.read_at(SPENT_INDEX, 0, &mut h)? != h.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the spent tag index has no header"))
+        }
+        let header = Header::from_bytes(&h);
+        if header.slots == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the spent tag index header is corrupt"))
+        }
+        Ok(header)
+    }
+
+    /// reads the tag at position pos of the spent tags
+    fn tag(&self, pos: u64) -> io::Result<[u8;32]> {
+        let mut tag = [0u8;32];
+        if self.storage.read_at(SPENT, pos*TAG_SIZE, &mut tag)? != tag.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the spent tags are too short"))
+        }
+        Ok(tag)
+    }
+
+    /// finds the slot the tag is in or the empty slot it would go in
+    /// a slot holds the position of its tag plus 1 so 0 means empty. slots pointing past count or at a different tag are skipped
+    fn find(&self, header: &Header, count: u64, tag: &[u8;32]) -> io::Result<(bool, u64)> {
+        self.find_with(header, count, tag, &BTreeMap::new())
+    }
+
+    /// find with the slots in pending filled in but not written yet
+    fn find_with(&self, header: &Header, count: u64, tag: &[u8;32], pending: &BTreeMap<u64, u64>) -> io::Result<(bool, u64)> {
+        let mut slot = header.home(tag);
+        let mut probed = 0;
+        while probed < header.slots {
+            // the slots after the home slot are read together (up to the end of the table)
+            let mut slots = vec![0u8; (PROBE_SLOTS.min(header.slots - slot)*8) as usize];
+            if self.storage.read_at(SPENT_INDEX, INDEX_HEADER + slot*8, &mut slots)? != slots.len() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the spent tag index is too short"))
+            }
+            for s in slots.chunks_exact(8) {
+                let s = pending.get(&slot).copied().unwrap_or_else(|| u64::from_le_bytes(s.try_into().unwrap()));
+                if s == 0 {
+                    return Ok((false, slot))
+                }
+                if s <= count && self.tag(s - 1)? == *tag {
+                    return Ok((true, slot))
+                }
+                slot = (slot + 1) % header.slots;
+                probed += 1;
+                if probed == header.slots {
+                    break
+                }
+            }
+        }
+        Err(io::Error::other("the spent tag index is full"))
+    }
+
+    /// puts the tag at position pos in the index, doubling the index first if it's half full
+    fn index(&self, header: Header, count: u64, pos: u64, tag: &[u8;32]) -> io::Result<Header> {
+        if (header.used + 1)*2 > header.slots {
+            self.rebuild(header.key1, header.key2, count)?;
+            return self.header()
+        }
+        let (_, slot) = self.find(&header, pos, tag)?;
+        self.storage.write_at(SPENT_INDEX, INDEX_HEADER + slot*8, &(pos + 1).to_le_bytes())?;
+        Ok(Header { used: header.used + 1, ..header })
+    }
+
+    /// indexes the tags that were saved after the index was last updated
+    fn catch_up(&self) -> io::Result<Header> {
+        let mut header = self.header()?;
+        let count = self.len();
+        header.indexed = header.indexed.min(count);
+        for pos in header.indexed..count {
+            let tag = self.tag(pos)?;
+            if !self.find(&header, count, &tag)?.0 {
+                header = self.index(header, count, pos, &tag)?;
+            }
+            header.indexed = pos + 1;
+        }
+        self.storage.write_at(SPENT_INDEX, 0, &header.to_bytes())?;
+        Ok(header)
+    }
+
+    /// writes a new index for the first count tags
+    fn rebuild(&self, key1: u128, key2: u128, count: u64) -> io::Result<()> {
+        let mut header = Header {
+            slots: INITIAL_SLOTS,
+            used: count,
+            indexed: count,
+            key1,
+            key2,
+        };
+        while header.slots < count*2 + 2 {
+            header.slots *= 2;
+        }
+        let mut table = vec![0u8; (header.slots*8) as usize];
+        let tags = self.storage.read_all(SPENT)?;
+        for (pos, tag) in tags.chunks_exact(TAG_SIZE as usize).take(count as usize).enumerate() {
+            let mut slot = header.home(tag.try_into().unwrap());
+            while table[(slot*8) as usize..(slot*8 + 8) as usize] != [0u8;8] {
+                slot = (slot + 1) % header.slots;
+            }
+            table[(slot*8) as usize..(slot*8 + 8) as usize].copy_from_slice(&(pos as u64 + 1).to_le_bytes());
+        }
+        write_index(&*self.storage, header, &table)
+    }
+}
+
+/// replaces the index with header followed by table
+fn write_index(storage: &dyn Storage, header: Header, table: &[u8]) -> io::Result<()> {
+    let mut file = header.to_bytes();
+    file.extend_from_slice(table);
+    storage.write_all(SPENT_INDEX, &file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, JournalStore};
+
+    fn tag(i: u64) -> [u8;32] {
+        let mut t = [0u8;32];
+        t[..8].copy_from_slice(&i.to_le_bytes());
+        t[31] = 1;
+        t
+    }
+
+    fn new_spent() -> (Arc<dyn Storage>, SpentTags) {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        SpentTags::initialize(&*storage).unwrap();
+        (storage.clone(), SpentTags::new(storage))
+    }
+
+    #[test]
+    fn exact() {
+        let (storage, spent) = new_spent();
+        let tags = (0..100_000).map(tag).collect::<Vec<_>>();
+        spent.insert_batch(&tags.iter().collect::<Vec<_>>()).unwrap();
+        spent.insert_batch(&[&tags[3]]).unwrap();
+        assert_eq!(spent.len(), 100_000);
+        assert!(tags.iter().all(|x| spent.contains(x).unwrap()));
+        assert!(!(100_000..200_000).any(|x| spent.contains(&tag(x)).unwrap()));
+        assert!(storage.len(SPENT_INDEX).unwrap() > INDEX_HEADER + INITIAL_SLOTS*8);
+
+        // the bloom filter says everything was spent but only the spent tags are
+        let bloom = BloomFile::from_randomness(storage.clone()).unwrap();
+        bloom.insert_batch(&[&tags[0], &tag(100_000)]);
+        assert_eq!(spent.spent_batch(&bloom, &[&tags[0], &tag(100_000), &tags[1]]).unwrap(), vec![true, false, false]);
+
+        // a broken index can't say whether the bloom filter's matches were spent
+        storage.write_all(SPENT_INDEX, &[]).unwrap();
+        assert!(spent.contains(&tags[0]).is_err() && spent.contains(&tag(100_000)).is_err());
+        assert!(spent.spent_batch(&bloom, &[&tags[0], &tag(100_000), &tags[1]]).is_err());
+        assert_eq!(spent.spent_batch(&bloom, &[&tags[1]]).unwrap(), vec![false]);
+    }
+
+    #[test]
+    fn undone_and_unindexed_tags() {
+        let (storage, spent) = new_spent();
+        spent.insert_batch(&[&tag(1), &tag(2)]).unwrap();
+
+        // undoing a block takes its tags out of the file and leaves their index entries behind
+        storage.begin_block(0).unwrap();
+        spent.insert_batch(&[&tag(3), &tag(4)]).unwrap();
+        storage.rollback(0).unwrap();
+        assert_eq!(spent.len(), 2);
+        assert!(!spent.contains(&tag(3)).unwrap());
+        spent.insert_batch(&[&tag(5), &tag(3)]).unwrap();
+        assert!(spent.contains(&tag(5)).unwrap() && spent.contains(&tag(3)).unwrap() && !spent.contains(&tag(4)).unwrap());
+
+        // a crash between saving tags and indexing them
+        storage.append(SPENT, &tag(6)).unwrap();
+        let spent = SpentTags::new(storage.clone());
+        assert!(spent.contains(&tag(6)).unwrap());
+
+        let index = storage.read_all(SPENT_INDEX).unwrap();
+        spent.rebuild_index().unwrap();
+        assert_ne!(storage.read_all(SPENT_INDEX).unwrap(), index);
+        assert!((1..=6).filter(|&x| x != 4).all(|x| spent.contains(&tag(x)).unwrap()));
+
+        // a tag spent twice in one batch is only saved once
+        spent.insert_batch(&[&tag(7), &tag(8), &tag(7)]).unwrap();
+        assert_eq!(spent.len(), 7);
+        assert!(spent.contains(&tag(7)).unwrap() && spent.contains(&tag(8)).unwrap());
+    }
+}
@@ -0,0 +1,366 @@
+//! a header on every file whose layout might change so old files can be recognized and upgraded
+//!
+//! each versioned file starts with 4 bytes saying what the file is then its version (u32). [`Versioned`] wraps a backend,
+//! adds and removes the header so nothing above it ever sees it, and upgrades old files when it's opened.
+//! files saved before there were headers are version 0.
+use std::convert::TryInto;
+use std::io;
+use std::collections::HashMap;
+use super::{Storage, BlockStore, BlockKind, JournalStore, Journal, undo_name, TAG_HEADER, FULL_BLOCKS, FULL_BLOCKS_METADATA, LIGHTNING_BLOCKS, LIGHTNING_BLOCKS_METADATA, HISTORY, BLOOM, NODE, JOURNAL};
+use crate::validation::{upgrade_full_block_v1, upgrade_lightning_block_v1};
+
+/// the size of the header at the start of every versioned file
+pub const FORMAT_HEADER: u64 = 8;
+
+/// what a versioned file looks like now
+pub struct Format {
+    /// the file
+    pub name: &'static str,
+    /// the first 4 bytes of the file
+    pub magic: [u8; 4],
+    /// the version this code reads and writes
+    pub version: u32,
+}
+
+impl Format {
+    /// the header of the file at version
+    pub fn header(&self, version: u32) -> [u8; 8] {
+        let mut h = [0u8; 8];
+        h[..4].copy_from_slice(&self.magic);
+        h[4..].copy_from_slice(&version.to_le_bytes());
+        h
+    }
+}
+
+/// every versioned file (the version of myNode is the version of the app's saved node)
+pub static FORMATS: [Format; 7] = [
+    Format { name: FULL_BLOCKS, magic: *b"KFBD", version: 2 },
+    Format { name: FULL_BLOCKS_METADATA, magic: *b"KFBM", version: 1 },
+    Format { name: LIGHTNING_BLOCKS, magic: *b"KLBD", version: 2 },
+    Format { name: LIGHTNING_BLOCKS_METADATA, magic: *b"KLBM", version: 1 },
+    Format { name: HISTORY, magic: *b"KHIS", version: 1 },
+    Format { name: BLOOM, magic: *b"KBLM", version: 1 },
+    Format { name: NODE, magic: *b"KNOD", version: 1 },
+];
+
+/// the format of the file if it's versioned
+pub fn format(name: &str) -> Option<&'static Format> {
+    FORMATS.iter().find(|x| x.name == name)
+}
+
+/// the version of a file that exists (0 if it doesn't have a header)
+pub fn version<S: Storage + ?Sized>(storage: &S, format: &Format) -> io::Result<u32> {
+    let mut h = [0u8; 8];
+    if storage.read_at(format.name, 0, &mut h)? == h.len() && h[..4] == format.magic {
+        Ok(u32::from_le_bytes(h[4..].try_into().unwrap()))
+    } else {
+        Ok(0)
+    }
+}
+
+/// upgrades a file from version from to version from + 1. upgrade is given the backend itself so it sees the headers
+pub struct Migration {
+    pub name: &'static str,
+    pub from: u32,
+    pub upgrade: fn(&dyn Storage) -> io::Result<()>,
+}
+
+/// the upgrades for files saved before there were headers. myNode isn't here because only the app knows what's in it
+pub static LEGACY_MIGRATIONS: [Migration; 6] = [
+    Migration { name: FULL_BLOCKS, from: 0, upgrade: |s| add_header(s, FULL_BLOCKS) },
+    Migration { name: FULL_BLOCKS_METADATA, from: 0, upgrade: |s| add_header(s, FULL_BLOCKS_METADATA) },
+    Migration { name: LIGHTNING_BLOCKS, from: 0, upgrade: |s| add_header(s, LIGHTNING_BLOCKS) },
+    Migration { name: LIGHTNING_BLOCKS_METADATA, from: 0, upgrade: |s| add_header(s, LIGHTNING_BLOCKS_METADATA) },
+    Migration { name: HISTORY, from: 0, upgrade: |s| add_header(s, HISTORY) },
+    Migration { name: BLOOM, from: 0, upgrade: bloom_v0 },
+];
+
+/// puts the version 1 header in front of a file that didn't have one
+fn add_header(storage: &dyn Storage, name: &str) -> io::Result<()> {
+    let mut file = format(name).unwrap().header(1).to_vec();
+    file.extend(storage.read_all(name)?);
+    storage.write_all(name, &file)
+}
+
+/// before the bloom file had a format header it was either just the bits (always 4,000,000 of them checked with 6 hashes)
+/// or the bits after a header with their size
+fn bloom_v0(storage: &dyn Storage) -> io::Result<()> {
+    let bits = storage.read_all(BLOOM)?;
+    let sized = bits.len() as u64 >= TAG_HEADER && bits.len() as u64 == TAG_HEADER + u64::from_le_bytes(bits[..8].try_into().unwrap()).div_ceil(8);
+    let mut file = format(BLOOM).unwrap().header(1).to_vec();
+    if !sized {
+        file.extend(4_000_000u64.to_le_bytes());
+        file.extend(6u32.to_le_bytes());
+        file.extend([0u8; 4]);
+    }
+    file.extend(bits);
+    storage.write_all(BLOOM, &file)
+}
+
+/// the upgrades for blocks saved before outputs had view tags
+pub static VIEW_TAG_MIGRATIONS: [Migration; 2] = [
+    Migration { name: FULL_BLOCKS, from: 1, upgrade: |s| reencode_blocks(s, BlockKind::Full, upgrade_full_block_v1) },
+    Migration { name: LIGHTNING_BLOCKS, from: 1, upgrade: |s| reencode_blocks(s, BlockKind::Lightning, upgrade_lightning_block_v1) },
+];
+
+/// the file the new contents of every file an upgrade of the data file changes are saved to before any of them are written
+fn upgrade_name(data: &str) -> String {
+    format!("{}_upgrade", data)
+}
+
+/// rewrites every block in the data file with upgrade and moves the block ends in the metadata, the journal and the undo
+/// records to match. if this is interrupted it's finished the next time the node starts
+fn reencode_blocks(storage: &dyn Storage, kind: BlockKind, upgrade: fn(&[u8]) -> io::Result<Vec<u8>>) -> io::Result<()> {
+    let intent = upgrade_name(kind.data());
+    if !storage.exists(&intent) {
+        let files = reencoded_files(storage, kind, upgrade)?;
+        storage.write_all(&intent, &bincode::serialize(&files).unwrap())?;
+        storage.sync(&intent)?;
+    }
+    let files: Vec<(String, Vec<u8>)> = bincode::deserialize(&storage.read_all(&intent)?)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("the unfinished upgrade of {} is corrupted", kind.data())))?;
+    // the data file is last because its header says if the upgrade is done
+    for (name, bytes) in files.iter() {
+        storage.write_all(name, bytes)?;
+        storage.sync(name)?;
+    }
+    storage.remove(&intent)
+}
+
+/// the new contents of the journal, the undo records, the metadata and the data file (in that order). blocks that were
+/// pruned keep their offsets so the pruning file doesn't change
+fn reencoded_files(storage: &dyn Storage, kind: BlockKind, upgrade: fn(&[u8]) -> io::Result<Vec<u8>>) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let bad = |problem: &str| io::Error::new(io::ErrorKind::InvalidData, format!("can't upgrade {}: {}", kind.data(), problem));
+    let data = storage.read_all(kind.data())?;
+    let data = &data[FORMAT_HEADER as usize..];
+    let meta = storage.read_all(kind.metadata())?;
+    let skip = if version(storage, format(kind.metadata()).unwrap())? == 0 {0} else {FORMAT_HEADER as usize};
+    let (metaheader, meta) = meta.split_at(skip);
+    let ends = meta.chunks_exact(8).map(|x| u64::from_le_bytes(x.try_into().unwrap())).collect::<Vec<_>>();
+    let end = *ends.last().ok_or_else(|| bad("its metadata is empty"))?;
+    // where the data file starts. blocks are only pruned between blocks so if one is being written pruning is finished
+    // (and the data file might have the start of a block after the end of the metadata)
+    let start = if storage.exists(JOURNAL) {
+        storage.pruned_at(kind)?.1
+    } else {
+        end.checked_sub(data.len() as u64).ok_or_else(|| bad("the data file is longer than its metadata"))?
+    };
+
+    let mut newdata = format(kind.data()).unwrap().header(2).to_vec();
+    let mut newends = Vec::with_capacity(ends.len());
+    for (i, &x) in ends.iter().enumerate() {
+        if x <= start {
+            newends.push(x);
+            continue
+        }
+        let from = ends[i - 1];
+        if from < start || x < from {
+            return Err(bad("its metadata doesn't match the data file"))
+        }
+        let last = *newends.last().unwrap();
+        if x == from {
+            newends.push(last);
+            continue
+        }
+        let block = data.get((from - start) as usize..(x - start) as usize).ok_or_else(|| bad("the data file is shorter than its metadata"))?;
+        let block = upgrade(block)?;
+        newdata.extend(&block);
+        newends.push(last + block.len() as u64);
+    }
+    let moved = ends.iter().copied().zip(newends.iter().copied()).collect::<HashMap<_, _>>();
+
+    let mut files = vec![];
+    let journals = std::iter::once(JOURNAL.to_string()).chain(storage.undo_heights()?.into_iter().map(undo_name));
+    for name in journals.filter(|x| storage.exists(x)) {
+        let mut journal: Journal = bincode::deserialize(&storage.read_all(&name)?).map_err(|_| bad("a journal is corrupted"))?;
+        for (_, len) in journal.lengths.iter_mut().filter(|(file, _)| file == kind.data()) {
+            *len = *moved.get(len).ok_or_else(|| bad("a journal doesn't end at a block"))?;
+        }
+        files.push((name, bincode::serialize(&journal).unwrap()));
+    }
+    let mut newmeta = metaheader.to_vec();
+    newends.iter().for_each(|x| newmeta.extend(x.to_le_bytes()));
+    files.push((kind.metadata().to_string(), newmeta));
+    files.push((kind.data().to_string(), newdata));
+    Ok(files)
+}
+
+/// a backend that hides the header of every versioned file
+#[derive(Debug)]
+pub struct Versioned<S> {
+    inner: S,
+}
+
+impl<S: Storage> Versioned<S> {
+    /// upgrades every versioned file to the current version then wraps the backend
+    /// migrations are the app's own upgrades (for myNode) and are used along with the built in ones
+    pub fn open(inner: S, migrations: &[Migration]) -> io::Result<Versioned<S>> {
+        for format in FORMATS.iter().filter(|x| inner.exists(x.name)) {
+            loop {
+                let v = version(&inner, format)?;
+                if v == format.version {
+                    break
+                }
+                if v > format.version {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} was saved by a newer version of khora (version {}, this reads up to {})", format.name, v, format.version)))
+                }
+                let migration = match migrations.iter().chain(LEGACY_MIGRATIONS.iter()).chain(VIEW_TAG_MIGRATIONS.iter()).find(|x| x.name == format.name && x.from == v) {
+                    Some(x) => x,
+                    None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is version {} and can't be upgraded to version {}", format.name, v, format.version))),
+                };
+                (migration.upgrade)(&inner)?;
+                if version(&inner, format)? != v + 1 {
+                    return Err(io::Error::other(format!("upgrading {} from version {} didn't work", format.name, v)))
+                }
+            }
+        }
+        Ok(Versioned { inner })
+    }
+
+    /// the backend without the headers hidden
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+/// where a byte of the file really is
+fn shift(name: &str) -> u64 {
+    if format(name).is_some() {
+        FORMAT_HEADER
+    } else {
+        0
+    }
+}
+
+impl<S: Storage> Storage for Versioned<S> {
+    fn create(&self, name: &str) -> io::Result<()> {
+        match format(name) {
+            Some(f) => self.inner.write_all(name, &f.header(f.version)),
+            None => self.inner.create(name),
+        }
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.inner.exists(name)
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        self.inner.remove(name)
+    }
+
+    fn len(&self, name: &str) -> io::Result<u64> {
+        Ok(self.inner.len(name)?.saturating_sub(shift(name)))
+    }
+
+    fn read_at(&self, name: &str, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read_at(name, offset + shift(name), buf)
+    }
+
+    fn write_at(&self, name: &str, offset: u64, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_at(name, offset + shift(name), bytes)
+    }
+
+    fn write_ranges(&self, name: &str, ranges: &[(u64, &[u8])]) -> io::Result<()> {
+        self.inner.write_ranges(name, &ranges.iter().map(|&(offset, bytes)| (offset + shift(name), bytes)).collect::<Vec<_>>())
+    }
+
+    fn append(&self, name: &str, bytes: &[u8]) -> io::Result<u64> {
+        Ok(self.inner.append(name, bytes)? - shift(name))
+    }
+
+    fn read_all(&self, name: &str) -> io::Result<Vec<u8>> {
+        let mut bytes = self.inner.read_all(name)?;
+        if let Some(f) = format(name) {
+            if bytes.len() < FORMAT_HEADER as usize || bytes[..FORMAT_HEADER as usize] != f.header(f.version) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} doesn't have the right header", name)))
+            }
+            bytes.drain(..FORMAT_HEADER as usize);
+        }
+        Ok(bytes)
+    }
+
+    fn write_all(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        match format(name) {
+            Some(f) => {
+                let mut file = f.header(f.version).to_vec();
+                file.extend_from_slice(bytes);
+                self.inner.write_all(name, &file)
+            }
+            None => self.inner.write_all(name, bytes),
+        }
+    }
+
+    fn truncate(&self, name: &str, len: u64) -> io::Result<()> {
+        self.inner.truncate(name, len + shift(name))
+    }
+
+    fn sync(&self, name: &str) -> io::Result<()> {
+        self.inner.sync(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, BlockStore, BlockKind, HistoryStore, TagStore, NodeStore};
+
+    #[test]
+    fn hides_headers() {
+        let storage = Versioned::open(MemoryStorage::new(), &[]).unwrap();
+        storage.initialize_blocks(BlockKind::Lightning).unwrap();
+        storage.save_block(BlockKind::Lightning, &[1, 2, 3]).unwrap();
+        storage.save_block(BlockKind::Lightning, &[4]).unwrap();
+        assert_eq!(storage.read_block(BlockKind::Lightning, &1), Ok(vec![4]));
+        assert_eq!(storage.len(LIGHTNING_BLOCKS).unwrap(), 4);
+        storage.truncate(LIGHTNING_BLOCKS, 3).unwrap();
+        assert_eq!(storage.inner().read_all(LIGHTNING_BLOCKS).unwrap(), [&b"KLBD"[..], &2u32.to_le_bytes(), &[1, 2, 3]].concat());
+        storage.save_node(b"node").unwrap();
+        assert_eq!(storage.load_node().unwrap(), b"node".to_vec());
+        assert_eq!(version(storage.inner(), format(NODE).unwrap()).unwrap(), 1);
+
+        // files without a format don't get a header
+        storage.write_all("other", &[5]).unwrap();
+        assert_eq!(storage.inner().read_all("other").unwrap(), vec![5]);
+    }
+
+    #[test]
+    fn upgrades_old_files() {
+        let old = MemoryStorage::new();
+        old.initialize_history().unwrap();
+        old.history_append(&[7u8; 64]).unwrap();
+        old.write_all(BLOOM, &[0b10u8; 500_000]).unwrap();
+        old.write_all(NODE, b"node").unwrap();
+
+        // only the app knows how to upgrade the saved node
+        let err = Versioned::open(old, &[]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let old = MemoryStorage::new();
+        old.initialize_history().unwrap();
+        old.history_append(&[7u8; 64]).unwrap();
+        old.write_all(BLOOM, &[0b10u8; 500_000]).unwrap();
+        old.write_all(NODE, b"node").unwrap();
+        let migrations = [Migration { name: NODE, from: 0, upgrade: |s| {
+            let mut file = format(NODE).unwrap().header(1).to_vec();
+            file.extend(s.read_all(NODE)?);
+            file.push(0);
+            s.write_all(NODE, &file)
+        } }];
+        let storage = Versioned::open(old, &migrations).unwrap();
+        assert_eq!(storage.history_get(&0).unwrap(), [7u8; 64]);
+        assert_eq!(storage.tag_params().unwrap(), (4_000_000, 6));
+        assert_eq!(storage.tag_byte(499_999).unwrap(), 0b10);
+        assert_eq!(storage.load_node().unwrap(), b"node\0".to_vec());
+
+        // opening it again doesn't change anything
+        let storage = Versioned::open(MemoryStorage::new(), &[]).unwrap();
+        storage.initialize_tags(64, 3).unwrap();
+        let before = storage.inner().read_all(BLOOM).unwrap();
+        let inner = Versioned::open(storage.inner, &[]).unwrap().inner;
+        assert_eq!(inner.read_all(BLOOM).unwrap(), before);
+
+        // or a file from the future
+        inner.write_all(HISTORY, &format(HISTORY).unwrap().header(2)).unwrap();
+        assert!(Versioned::open(inner, &[]).is_err());
+    }
+}
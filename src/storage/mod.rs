@@ -0,0 +1,1068 @@
+//! storage backends for everything a node keeps between runs
+//!
+//! the node needs the full and lightning block files (with their metadata), the history of every OTAccount,
+//! the bloom filter and exact index of spent tags and its own saved state. backends only provide a handful of raw file operations
+//! ([`Storage`]), everything else is built on top of those by the [`BlockStore`], [`HistoryStore`], [`TagStore`],
+//! [`NodeStore`] and [`JournalStore`] traits so all backends lay their data out the same way.
+//!
+//! reading a block touches every one of those files. to keep a crash from leaving them out of sync with each other,
+//! the node opens a [`Journal`] before it writes anything for a block and only closes it once the node itself is saved.
+//! on startup, [`JournalStore::recover`] undoes whatever a half written block left behind. the journals of the last few
+//! blocks are kept so the node can [`JournalStore::rollback`] to before a block if a competing block wins.
+//!
+//! the files whose layout might change start with a header saying what they are and their version. [`format::Versioned`]
+//! hides those headers from everything above it and upgrades old files when the node starts.
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+pub mod format;
+
+/// the full blocks, one after another
+pub static FULL_BLOCKS: &str = "fullblocks";
+/// the offsets where each full block ends
+pub static FULL_BLOCKS_METADATA: &str = "fullblocks_metadata";
+/// the lightning blocks, one after another
+pub static LIGHTNING_BLOCKS: &str = "lightningblocks";
+/// the offsets where each lightning block ends
+pub static LIGHTNING_BLOCKS_METADATA: &str = "lightningblocks_metadata";
+/// the first full block that hasn't been pruned and where it starts
+pub static FULL_BLOCKS_PRUNED: &str = "fullblocks_pruned";
+/// the first lightning block that hasn't been pruned and where it starts
+pub static LIGHTNING_BLOCKS_PRUNED: &str = "lightningblocks_pruned";
+/// the public key and commitment of every OTAccount that has appeared on the blockchain
+pub static HISTORY: &str = "history";
+/// the bloom filter of every tag that has been spent
+pub static BLOOM: &str = "bloomfile";
+/// every tag that has been spent, one after another
+pub static SPENT: &str = "spenttags";
+/// a hash table of where each tag is in the spent tags
+pub static SPENT_INDEX: &str = "spenttags_index";
+/// the saved node
+pub static NODE: &str = "myNode";
+/// what the files looked like before the block that's currently being written
+pub static JOURNAL: &str = "journal";
+/// the block numbers that can be rolled back to (each one's undo record is in a file named undo then the block number)
+pub static UNDO: &str = "undo";
+
+/// the size of a single entry in the history (compressed pk then compressed commitment)
+pub const HISTORY_ENTRY: u64 = 64;
+
+/// the raw operations a storage backend needs to provide. files are identified by name and the backend decides where they live
+pub trait Storage: Send + Sync {
+    /// creates the file or empties it if it already exists
+    fn create(&self, name: &str) -> io::Result<()>;
+    /// tells you if the file exists
+    fn exists(&self, name: &str) -> bool;
+    /// deletes the file
+    fn remove(&self, name: &str) -> io::Result<()>;
+    /// the length of the file in bytes
+    fn len(&self, name: &str) -> io::Result<u64>;
+    /// fills as much of buf as the file has starting at offset and returns how many bytes were read
+    fn read_at(&self, name: &str, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+    /// overwrites the file starting at offset
+    fn write_at(&self, name: &str, offset: u64, bytes: &[u8]) -> io::Result<()>;
+    /// overwrites many ranges of the file (backends should do this without reopening the file for each range)
+    fn write_ranges(&self, name: &str, ranges: &[(u64, &[u8])]) -> io::Result<()> {
+        for &(offset, bytes) in ranges {
+            self.write_at(name, offset, bytes)?;
+        }
+        Ok(())
+    }
+    /// adds bytes to the end of the file and returns the new length of the file
+    fn append(&self, name: &str, bytes: &[u8]) -> io::Result<u64>;
+    /// reads the whole file
+    fn read_all(&self, name: &str) -> io::Result<Vec<u8>>;
+    /// replaces the contents of the file (if this is interrupted the file is either completely old or completely new)
+    fn write_all(&self, name: &str, bytes: &[u8]) -> io::Result<()>;
+    /// cuts the file down to len bytes
+    fn truncate(&self, name: &str, len: u64) -> io::Result<()>;
+    /// makes sure everything written to the file so far survives a crash
+    fn sync(&self, name: &str) -> io::Result<()>;
+}
+
+/// which of the 2 block files you're talking about
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum BlockKind {
+    Full,
+    Lightning,
+}
+impl BlockKind {
+    /// the file the blocks are saved in
+    pub fn data(&self) -> &'static str {
+        match self {
+            BlockKind::Full => FULL_BLOCKS,
+            BlockKind::Lightning => LIGHTNING_BLOCKS,
+        }
+    }
+
+    /// the file the block offsets are saved in
+    pub fn metadata(&self) -> &'static str {
+        match self {
+            BlockKind::Full => FULL_BLOCKS_METADATA,
+            BlockKind::Lightning => LIGHTNING_BLOCKS_METADATA,
+        }
+    }
+
+    /// the file that says which blocks were pruned
+    pub fn pruned(&self) -> &'static str {
+        match self {
+            BlockKind::Full => FULL_BLOCKS_PRUNED,
+            BlockKind::Lightning => LIGHTNING_BLOCKS_PRUNED,
+        }
+    }
+
+    /// the kind of block saved in the file
+    pub fn from_data(name: &str) -> Option<BlockKind> {
+        if name == FULL_BLOCKS {
+            Some(BlockKind::Full)
+        } else if name == LIGHTNING_BLOCKS {
+            Some(BlockKind::Lightning)
+        } else {
+            None
+        }
+    }
+}
+
+/// saves blocks back to back in a data file. the metadata file starts with 0 and gets the end offset of every block
+/// appended to it so block n lives between entries n and n+1
+///
+/// pruning deletes the start of the data file. the metadata keeps the offsets the blocks would have if nothing was
+/// deleted so the pruned file remembers how many bytes were cut off the front and subtracts that when reading
+pub trait BlockStore {
+    /// creates the block file and its metadata
+    fn initialize_blocks(&self, kind: BlockKind) -> io::Result<()>;
+    /// saves the block and adds its end to the metadata
+    /// you must save empty blocks to metadata for this to work (save vec![])
+    fn save_block(&self, kind: BlockKind, serialized_block: &[u8]) -> io::Result<()>;
+    /// reads the specified block
+    fn read_block(&self, kind: BlockKind, bnum: &u64) -> Result<Vec<u8>, &'static str>;
+    /// the first block that hasn't been pruned and how many bytes were cut off the front of the data file
+    fn pruned_at(&self, kind: BlockKind) -> io::Result<(u64, u64)>;
+    /// the length the data file would have if nothing was pruned
+    fn blocks_len(&self, kind: BlockKind) -> io::Result<u64>;
+    /// deletes every block before bnum from the data file
+    fn prune_blocks(&self, kind: BlockKind, bnum: u64) -> io::Result<()>;
+    /// finishes pruning if the node stopped after deciding what to prune but before the data file was rewritten
+    fn finish_pruning(&self, kind: BlockKind) -> io::Result<()>;
+}
+
+impl<S: Storage + ?Sized> BlockStore for S {
+    fn initialize_blocks(&self, kind: BlockKind) -> io::Result<()> {
+        if self.exists(kind.pruned()) {
+            self.remove(kind.pruned())?;
+        }
+        self.create(kind.data())?;
+        self.write_all(kind.metadata(), &[0u8; 8])
+    }
+
+    fn save_block(&self, kind: BlockKind, serialized_block: &[u8]) -> io::Result<()> {
+        let currentlen = self.append(kind.data(), serialized_block)? + self.pruned_at(kind)?.1;
+        self.append(kind.metadata(), &currentlen.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn read_block(&self, kind: BlockKind, bnum: &u64) -> Result<Vec<u8>, &'static str> {
+        let mut datalocation = [0u8; 16];
+        let bytes_read = match self.read_at(kind.metadata(), bnum * 8, &mut datalocation) {
+            Ok(x) => x,
+            Err(_) => return Err("We don't have that block"),
+        };
+        let (loc1, loc2) = if bytes_read == 16 {
+            (u64::from_le_bytes(datalocation[..8].try_into().unwrap()), u64::from_le_bytes(datalocation[8..].try_into().unwrap()))
+        } else if bytes_read == 8 {
+            let loc1 = u64::from_le_bytes(datalocation[..8].try_into().unwrap());
+            (loc1, self.blocks_len(kind).map_err(|_| "We don't have that block")?)
+        } else {
+            return Err("We don't have that block")
+        };
+        let (pruned, cut) = self.pruned_at(kind).map_err(|_| "We don't have that block")?;
+        if *bnum < pruned {
+            return Err("We pruned that block")
+        }
+        if loc2 <= loc1 {
+            return Err("We skipped that block")
+        }
+        let mut bytes = vec![0u8; (loc2 - loc1) as usize];
+        match self.read_at(kind.data(), loc1 - cut, &mut bytes) {
+            Ok(x) if x == bytes.len() => Ok(bytes),
+            _ => Err("the block file is shorter than its metadata"),
+        }
+    }
+
+    fn pruned_at(&self, kind: BlockKind) -> io::Result<(u64, u64)> {
+        if !self.exists(kind.pruned()) {
+            return Ok((0, 0))
+        }
+        let bytes = self.read_all(kind.pruned())?;
+        if bytes.len() != 16 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the pruning file is corrupted"))
+        }
+        Ok((u64::from_le_bytes(bytes[..8].try_into().unwrap()), u64::from_le_bytes(bytes[8..].try_into().unwrap())))
+    }
+
+    fn blocks_len(&self, kind: BlockKind) -> io::Result<u64> {
+        Ok(self.len(kind.data())? + self.pruned_at(kind)?.1)
+    }
+
+    fn prune_blocks(&self, kind: BlockKind, bnum: u64) -> io::Result<()> {
+        let (pruned, _) = self.pruned_at(kind)?;
+        if bnum <= pruned {
+            return Ok(())
+        }
+        if self.exists(JOURNAL) {
+            return Err(io::Error::other("can't prune while a block is being written"))
+        }
+        let mut start = [0u8; 8];
+        if self.read_at(kind.metadata(), bnum * 8, &mut start)? != 8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "can't prune blocks you don't have"))
+        }
+        // once this is saved the blocks are as good as gone, finish_pruning does the rest even if the node stops now
+        self.write_all(kind.pruned(), &[bnum.to_le_bytes(), start].concat())?;
+        self.sync(kind.pruned())?;
+        self.finish_pruning(kind)
+    }
+
+    fn finish_pruning(&self, kind: BlockKind) -> io::Result<()> {
+        let (_, cut) = self.pruned_at(kind)?;
+        if cut == 0 {
+            return Ok(())
+        }
+        let metalen = self.len(kind.metadata())?;
+        let mut end = [0u8; 8];
+        self.read_at(kind.metadata(), metalen - 8, &mut end)?;
+        let end = u64::from_le_bytes(end);
+        let data = self.read_all(kind.data())?;
+        let physical = data.len() as u64;
+        if physical + cut == end {
+            return Ok(())
+        }
+        // otherwise the data file still starts where it did before this round of pruning
+        if end < physical || end - physical > cut {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the block file doesn't match its metadata"))
+        }
+        let oldcut = end - physical;
+        self.write_all(kind.data(), &data[(cut - oldcut) as usize..])?;
+        self.sync(kind.data())
+    }
+}
+
+/// saves the public key and commitment of every OTAccount as 64 bytes in the order they appeared
+pub trait HistoryStore {
+    /// creates the history file
+    fn initialize_history(&self) -> io::Result<()>;
+    /// reads the entry at height location
+    fn history_get(&self, location: &u64) -> io::Result<[u8; 64]>;
+    /// reads the entries at every location (in the order of the locations)
+    fn history_get_many(&self, locations: &[u64]) -> io::Result<Vec<[u8; 64]>>;
+    /// appends already serialized entries
+    fn history_append(&self, entries: &[u8]) -> io::Result<()>;
+    /// the number of entries in the history
+    fn history_len(&self) -> io::Result<u64>;
+}
+
+impl<S: Storage + ?Sized> HistoryStore for S {
+    fn initialize_history(&self) -> io::Result<()> {
+        self.create(HISTORY)
+    }
+
+    fn history_get(&self, location: &u64) -> io::Result<[u8; 64]> {
+        let mut bytes = [0u8; 64];
+        if self.read_at(HISTORY, location * HISTORY_ENTRY, &mut bytes)? != bytes.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the history doesn't go that far"))
+        }
+        Ok(bytes)
+    }
+
+    fn history_get_many(&self, locations: &[u64]) -> io::Result<Vec<[u8; 64]>> {
+        // read from the start of the file to the end and only read repeated locations once
+        let mut order = (0..locations.len()).collect::<Vec<_>>();
+        order.sort_unstable_by_key(|&i| locations[i]);
+        let mut entries = vec![[0u8; 64]; locations.len()];
+        let mut last: Option<usize> = None;
+        for i in order {
+            entries[i] = match last {
+                Some(j) if locations[j] == locations[i] => entries[j],
+                _ => self.history_get(&locations[i])?,
+            };
+            last = Some(i);
+        }
+        Ok(entries)
+    }
+
+    fn history_append(&self, entries: &[u8]) -> io::Result<()> {
+        self.append(HISTORY, entries)?;
+        Ok(())
+    }
+
+    fn history_len(&self) -> io::Result<u64> {
+        Ok(self.len(HISTORY)? / HISTORY_ENTRY)
+    }
+}
+
+/// the size of the header at the start of the tag file: the number of bits (u64), the number of hashes (u32) and 4 reserved bytes
+pub const TAG_HEADER: u64 = 16;
+
+/// the bytes of the bloom filter that remembers spent tags. byte offsets don't include the header
+pub trait TagStore {
+    /// creates the tag file with its header followed by enough 0s for num_bits
+    fn initialize_tags(&self, num_bits: u64, num_hashes: u32) -> io::Result<()>;
+    /// the number of bits and hashes the filter was created with
+    fn tag_params(&self) -> io::Result<(u64, u32)>;
+    /// reads the whole filter (without the header)
+    fn tag_bytes(&self) -> io::Result<Vec<u8>>;
+    /// reads 1 byte of the filter
+    fn tag_byte(&self, byte: u64) -> io::Result<u8>;
+    /// overwrites 1 byte of the filter
+    fn set_tag_byte(&self, byte: u64, value: u8) -> io::Result<()>;
+    /// overwrites many bytes of the filter
+    fn set_tag_bytes(&self, bytes: &[(u64, u8)]) -> io::Result<()>;
+}
+
+impl<S: Storage + ?Sized> TagStore for S {
+    fn initialize_tags(&self, num_bits: u64, num_hashes: u32) -> io::Result<()> {
+        let mut file = Vec::with_capacity((TAG_HEADER + num_bits.div_ceil(8)) as usize);
+        file.extend(num_bits.to_le_bytes());
+        file.extend(num_hashes.to_le_bytes());
+        file.extend([0u8; 4]);
+        file.resize((TAG_HEADER + num_bits.div_ceil(8)) as usize, 0);
+        self.write_all(BLOOM, &file)
+    }
+
+    fn tag_params(&self) -> io::Result<(u64, u32)> {
+        let mut h = [0u8; TAG_HEADER as usize];
+        if self.read_at(BLOOM, 0, &mut h)? != h.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the bloom file has no header"))
+        }
+        let num_bits = u64::from_le_bytes(h[..8].try_into().unwrap());
+        let num_hashes = u32::from_le_bytes(h[8..12].try_into().unwrap());
+        if num_bits == 0 || num_hashes == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the bloom file header is corrupt"))
+        }
+        Ok((num_bits, num_hashes))
+    }
+
+    fn tag_bytes(&self) -> io::Result<Vec<u8>> {
+        let (num_bits, _) = self.tag_params()?;
+        let file = self.read_all(BLOOM)?;
+        if (file.len() as u64) < TAG_HEADER + num_bits.div_ceil(8) {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the bloom file is too short"))
+        }
+        Ok(file[TAG_HEADER as usize..].to_vec())
+    }
+
+    fn tag_byte(&self, byte: u64) -> io::Result<u8> {
+        let mut b = [0u8];
+        if self.read_at(BLOOM, TAG_HEADER + byte, &mut b)? != 1 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the bloom file is too short"))
+        }
+        Ok(b[0])
+    }
+
+    fn set_tag_byte(&self, byte: u64, value: u8) -> io::Result<()> {
+        self.write_at(BLOOM, TAG_HEADER + byte, &[value])
+    }
+
+    fn set_tag_bytes(&self, bytes: &[(u64, u8)]) -> io::Result<()> {
+        let mut bytes = bytes.to_vec();
+        bytes.sort_by_key(|&(byte, _)| byte);
+        // neighbouring bytes are written together
+        let mut ranges: Vec<(u64, Vec<u8>)> = vec![];
+        for (byte, value) in bytes {
+            match ranges.last_mut() {
+                Some((start, values)) if *start + values.len() as u64 == byte => values.push(value),
+                Some((start, values)) if *start + values.len() as u64 > byte => *values.last_mut().unwrap() = value,
+                _ => ranges.push((byte, vec![value])),
+            }
+        }
+        self.write_ranges(BLOOM, &ranges.iter().map(|(start, values)| (TAG_HEADER + start, &values[..])).collect::<Vec<_>>())
+    }
+}
+
+/// the serialized node that's saved when the app is off
+pub trait NodeStore {
+    /// tells you if there's a node saved (the node only exists if you already have an account made)
+    fn has_node(&self) -> bool;
+    /// saves the serialized node
+    fn save_node(&self, bytes: &[u8]) -> io::Result<()>;
+    /// loads the serialized node
+    fn load_node(&self) -> io::Result<Vec<u8>>;
+    /// deletes the saved node (this logs you out)
+    fn remove_node(&self) -> io::Result<()>;
+}
+
+impl<S: Storage + ?Sized> NodeStore for S {
+    fn has_node(&self) -> bool {
+        self.exists(NODE)
+    }
+
+    fn save_node(&self, bytes: &[u8]) -> io::Result<()> {
+        self.write_all(NODE, bytes)?;
+        self.sync(NODE)
+    }
+
+    fn load_node(&self) -> io::Result<Vec<u8>> {
+        self.read_all(NODE)
+    }
+
+    fn remove_node(&self) -> io::Result<()> {
+        self.remove(NODE)
+    }
+}
+
+/// the files a block appends to
+pub static JOURNALED_FILES: [&str; 6] = [FULL_BLOCKS, FULL_BLOCKS_METADATA, LIGHTNING_BLOCKS, LIGHTNING_BLOCKS_METADATA, HISTORY, SPENT];
+
+/// the number of blocks that can be undone
+pub const UNDO_DEPTH: usize = 10;
+
+/// the file the undo record for the block after bnum is kept in
+fn undo_name(bnum: u64) -> String {
+    format!("{}{}", UNDO, bnum)
+}
+
+/// everything needed to put the files back the way they were when the node was last saved
+/// once the block is finished the journal is kept as the undo record of that block
+#[derive(Clone, Default, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct Journal {
+    /// the block number of the saved node
+    pub bnum: u64,
+    /// the length of each file in JOURNALED_FILES that existed
+    pub lengths: Vec<(String, u64)>,
+    /// the original value of every byte of the bloom filter that's been changed
+    pub tag_bytes: Vec<(u64, u8)>,
+    /// the saved node (everything the node knew before the block)
+    pub node: Vec<u8>,
+}
+
+/// what recovery had to do
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Recovery {
+    /// there was no unfinished block
+    Clean,
+    /// the node was saved after the block so the block is kept
+    RolledForward,
+    /// the node wasn't saved after the block so the block is removed
+    RolledBack,
+}
+
+/// puts the files back the way the journal says they were
+fn undo<S: Storage + ?Sized>(storage: &S, journal: &Journal) -> io::Result<()> {
+    for (name, len) in journal.lengths.iter() {
+        let len = match BlockKind::from_data(name) {
+            Some(kind) => len.saturating_sub(storage.pruned_at(kind)?.1),
+            None => *len,
+        };
+        if storage.len(name)? > len {
+            storage.truncate(name, len)?;
+        }
+        storage.sync(name)?;
+    }
+    storage.set_tag_bytes(&journal.tag_bytes)?;
+    if !journal.tag_bytes.is_empty() {
+        storage.sync(BLOOM)?;
+    }
+    Ok(())
+}
+
+/// makes writing a block all or nothing and remembers how to undo the last UNDO_DEPTH blocks
+///
+/// call begin_block after saving the node and before writing anything for the block, journal_tag_bytes before changing
+/// the bloom filter, and commit_block to save the node once the block is done. if the node crashes in between,
+/// recover puts every file back to the state matching the saved node. finished journals are kept as undo records so
+/// rollback can take the node back to before a block that turned out to be on the wrong fork.
+pub trait JournalStore {
+    /// remembers the length of the block files, the history and the saved node. does nothing if a journal is already open
+    /// (the node wasn't saved after the last block so the files still need to go back to where that journal says)
+    fn begin_block(&self, bnum: u64) -> io::Result<()>;
+    /// remembers the value each byte of the bloom filter had before the block (bytes already in the journal are left alone).
+    /// the values come from the caller because the filter in memory already has them
+    fn journal_tag_bytes(&self, bytes: &[(u64, u8)]) -> io::Result<()>;
+    /// flushes every journaled file, keeps the journal as an undo record, and saves the node
+    fn commit_block(&self, node: &[u8]) -> io::Result<()>;
+    /// the open journal if there is one
+    fn journal(&self) -> io::Result<Option<Journal>>;
+    /// finishes or undoes the block that was being written when the node stopped. saved_bnum is the block number of the saved node
+    fn recover(&self, saved_bnum: u64) -> io::Result<Recovery>;
+    /// the block numbers the node can be rolled back to (oldest first)
+    fn undo_heights(&self) -> io::Result<Vec<u64>>;
+    /// the undo record of the block after bnum
+    fn undo_record(&self, bnum: u64) -> io::Result<Journal>;
+    /// undoes every block after bnum (bnum must be one of the undo heights) and returns the node that was saved at bnum
+    fn rollback(&self, bnum: u64) -> io::Result<Vec<u8>>;
+}
+
+impl<S: Storage + ?Sized> JournalStore for S {
+    fn begin_block(&self, bnum: u64) -> io::Result<()> {
+        if self.exists(JOURNAL) {
+            return Ok(())
+        }
+        // block files are measured as if nothing was pruned
+        let lengths = JOURNALED_FILES.iter().filter_map(|&name| {
+            match BlockKind::from_data(name) {
+                Some(kind) => self.blocks_len(kind),
+                None => self.len(name),
+            }.ok().map(|len| (name.to_string(), len))
+        }).collect();
+        let journal = Journal {
+            bnum,
+            lengths,
+            tag_bytes: vec![],
+            node: self.load_node().unwrap_or_default(),
+        };
+        self.write_all(JOURNAL, &bincode::serialize(&journal).unwrap())?;
+        self.sync(JOURNAL)
+    }
+
+    fn journal_tag_bytes(&self, bytes: &[(u64, u8)]) -> io::Result<()> {
+        let mut journal = match self.journal()? {
+            Some(journal) => journal,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "there's no block being written")),
+        };
+        let mut journaled = journal.tag_bytes.iter().map(|&(b, _)| b).collect::<HashSet<_>>();
+        let mut changed = false;
+        for &(byte, value) in bytes {
+            if journaled.insert(byte) {
+                journal.tag_bytes.push((byte, value));
+                changed = true;
+            }
+        }
+        if changed {
+            self.write_all(JOURNAL, &bincode::serialize(&journal).unwrap())?;
+            self.sync(JOURNAL)?;
+        }
+        Ok(())
+    }
+
+    fn commit_block(&self, node: &[u8]) -> io::Result<()> {
+        if let Some(journal) = self.journal()? {
+            for (name, _) in journal.lengths.iter() {
+                self.sync(name)?;
+            }
+            if !journal.tag_bytes.is_empty() {
+                self.sync(BLOOM)?;
+            }
+
+            // keep the journal around so the block can be undone later
+            let mut heights = self.undo_heights()?;
+            self.write_all(&undo_name(journal.bnum), &bincode::serialize(&journal).unwrap())?;
+            heights.retain(|&x| x < journal.bnum);
+            heights.push(journal.bnum);
+            let forgotten = heights.len().saturating_sub(UNDO_DEPTH);
+            let forgotten = heights.drain(..forgotten).collect::<Vec<_>>();
+            self.write_all(UNDO, &bincode::serialize(&heights).unwrap())?;
+            self.sync(UNDO)?;
+            for bnum in forgotten {
+                self.remove(&undo_name(bnum))?;
+            }
+
+            self.save_node(node)?;
+            self.remove(JOURNAL)
+        } else {
+            self.save_node(node)
+        }
+    }
+
+    fn journal(&self) -> io::Result<Option<Journal>> {
+        if !self.exists(JOURNAL) {
+            return Ok(None)
+        }
+        match bincode::deserialize(&self.read_all(JOURNAL)?) {
+            Ok(journal) => Ok(Some(journal)),
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "the journal is corrupted")),
+        }
+    }
+
+    fn recover(&self, saved_bnum: u64) -> io::Result<Recovery> {
+        let journal = match self.journal()? {
+            Some(journal) => journal,
+            None => {
+                // blocks are only pruned between blocks
+                self.finish_pruning(BlockKind::Full)?;
+                self.finish_pruning(BlockKind::Lightning)?;
+                return Ok(Recovery::Clean)
+            }
+        };
+        if saved_bnum > journal.bnum {
+            self.remove(JOURNAL)?;
+            return Ok(Recovery::RolledForward)
+        }
+        undo(self, &journal)?;
+        // the node may have stopped after keeping the undo record but before it was saved
+        let mut heights = self.undo_heights()?;
+        if heights.contains(&journal.bnum) {
+            heights.retain(|&x| x != journal.bnum);
+            self.write_all(UNDO, &bincode::serialize(&heights).unwrap())?;
+            self.remove(&undo_name(journal.bnum))?;
+        }
+        self.remove(JOURNAL)?;
+        Ok(Recovery::RolledBack)
+    }
+
+    fn undo_heights(&self) -> io::Result<Vec<u64>> {
+        if !self.exists(UNDO) {
+            return Ok(vec![])
+        }
+        bincode::deserialize(&self.read_all(UNDO)?).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "the undo index is corrupted"))
+    }
+
+    fn undo_record(&self, bnum: u64) -> io::Result<Journal> {
+        if !self.exists(&undo_name(bnum)) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "there's no undo record for that block"))
+        }
+        bincode::deserialize(&self.read_all(&undo_name(bnum))?).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "the undo record is corrupted"))
+    }
+
+    fn rollback(&self, bnum: u64) -> io::Result<Vec<u8>> {
+        let mut heights = self.undo_heights()?;
+        let journal = self.journal()?;
+        if !heights.contains(&bnum) && journal.as_ref().map(|x| x.bnum) != Some(bnum) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "can't roll back that far"))
+        }
+
+        // anything written since the node was last saved goes first
+        if let Some(journal) = journal {
+            undo(self, &journal)?;
+            self.save_node(&journal.node)?;
+            self.remove(JOURNAL)?;
+            if journal.bnum == bnum {
+                return Ok(journal.node)
+            }
+        }
+        // then each block from newest to oldest, so the files always match the saved node if this is interrupted
+        loop {
+            let height = match heights.last() {
+                Some(&x) if x >= bnum => x,
+                _ => unreachable!("bnum is one of the heights"),
+            };
+            let record = self.undo_record(height)?;
+            undo(self, &record)?;
+            self.save_node(&record.node)?;
+            heights.pop();
+            self.write_all(UNDO, &bincode::serialize(&heights).unwrap())?;
+            self.remove(&undo_name(height))?;
+            if height == bnum {
+                return Ok(record.node)
+            }
+        }
+    }
+}
+
+/// keeps every file in a data directory
+#[derive(Debug)]
+pub struct FileStorage {
+    dir: PathBuf,
+    /// files that have been read are kept open so reading many small pieces doesn't reopen them each time
+    readers: Mutex<HashMap<String, File>>,
+}
+
+impl FileStorage {
+    /// uses dir as the data directory (creating it if it doesn't exist)
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<FileStorage> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(FileStorage {
+            dir: dir.as_ref().to_path_buf(),
+            readers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// the data directory
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// where the file is on disk
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// closes the file if it's open for reading then replaces or removes it (reads wait until it's done)
+    fn forget<T>(&self, name: &str, f: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        let mut readers = self.readers.lock().unwrap();
+        readers.remove(name);
+        f()
+    }
+}
+
+impl Clone for FileStorage {
+    fn clone(&self) -> FileStorage {
+        FileStorage {
+            dir: self.dir.clone(),
+            readers: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    fn create(&self, name: &str) -> io::Result<()> {
+        self.forget(name, || File::create(self.path(name)))?;
+        Ok(())
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.path(name).exists()
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        self.forget(name, || fs::remove_file(self.path(name)))
+    }
+
+    fn len(&self, name: &str) -> io::Result<u64> {
+        Ok(fs::metadata(self.path(name))?.len())
+    }
+
+    fn read_at(&self, name: &str, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut readers = self.readers.lock().unwrap();
+        if !readers.contains_key(name) {
+            readers.insert(name.to_string(), File::open(self.path(name))?);
+        }
+        let f = readers.get_mut(name).unwrap();
+        f.seek(SeekFrom::Start(offset))?;
+        let mut read = 0;
+        while read < buf.len() {
+            match f.read(&mut buf[read..])? {
+                0 => break,
+                x => read += x,
+            }
+        }
+        Ok(read)
+    }
+
+    fn write_at(&self, name: &str, offset: u64, bytes: &[u8]) -> io::Result<()> {
+        self.write_ranges(name, &[(offset, bytes)])
+    }
+
+    fn write_ranges(&self, name: &str, ranges: &[(u64, &[u8])]) -> io::Result<()> {
+        let mut f = OpenOptions::new().write(true).open(self.path(name))?;
+        for &(offset, bytes) in ranges {
+            f.seek(SeekFrom::Start(offset))?;
+            f.write_all(bytes)?;
+        }
+        Ok(())
+    }
+
+    fn append(&self, name: &str, bytes: &[u8]) -> io::Result<u64> {
+        let mut f = OpenOptions::new().append(true).open(self.path(name))?;
+        f.write_all(bytes)?;
+        f.seek(SeekFrom::End(0))
+    }
+
+    fn read_all(&self, name: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(name))
+    }
+
+    fn write_all(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        // write a copy and swap it in so a crash never leaves half a file
+        let tmp = self.path(&format!("{}.tmp", name));
+        let mut f = File::create(&tmp)?;
+        f.write_all(bytes)?;
+        f.sync_all()?;
+        self.forget(name, || fs::rename(tmp, self.path(name)))
+    }
+
+    fn truncate(&self, name: &str, len: u64) -> io::Result<()> {
+        OpenOptions::new().write(true).open(self.path(name))?.set_len(len)
+    }
+
+    fn sync(&self, name: &str) -> io::Result<()> {
+        OpenOptions::new().write(true).open(self.path(name))?.sync_all()
+    }
+}
+
+/// keeps every file in memory (for tests and for nodes that don't need anything to survive a restart)
+#[derive(Default, Debug)]
+pub struct MemoryStorage {
+    files: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} doesn't exist", name))
+}
+
+impl Storage for MemoryStorage {
+    fn create(&self, name: &str) -> io::Result<()> {
+        self.files.write().unwrap().insert(name.to_string(), vec![]);
+        Ok(())
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.files.read().unwrap().contains_key(name)
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        self.files.write().unwrap().remove(name).map(|_| ()).ok_or_else(|| not_found(name))
+    }
+
+    fn len(&self, name: &str) -> io::Result<u64> {
+        self.files.read().unwrap().get(name).map(|x| x.len() as u64).ok_or_else(|| not_found(name))
+    }
+
+    fn read_at(&self, name: &str, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let files = self.files.read().unwrap();
+        let file = files.get(name).ok_or_else(|| not_found(name))?;
+        let start = std::cmp::min(offset as usize, file.len());
+        let end = std::cmp::min(start + buf.len(), file.len());
+        buf[..end - start].copy_from_slice(&file[start..end]);
+        Ok(end - start)
+    }
+
+    fn write_at(&self, name: &str, offset: u64, bytes: &[u8]) -> io::Result<()> {
+        let mut files = self.files.write().unwrap();
+        let file = files.get_mut(name).ok_or_else(|| not_found(name))?;
+        let end = offset as usize + bytes.len();
+        if file.len() < end {
+            file.resize(end, 0);
+        }
+        file[offset as usize..end].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn append(&self, name: &str, bytes: &[u8]) -> io::Result<u64> {
+        let mut files = self.files.write().unwrap();
+        let file = files.get_mut(name).ok_or_else(|| not_found(name))?;
+        file.extend_from_slice(bytes);
+        Ok(file.len() as u64)
+    }
+
+    fn read_all(&self, name: &str) -> io::Result<Vec<u8>> {
+        self.files.read().unwrap().get(name).cloned().ok_or_else(|| not_found(name))
+    }
+
+    fn write_all(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        self.files.write().unwrap().insert(name.to_string(), bytes.to_vec());
+        Ok(())
+    }
+
+    fn truncate(&self, name: &str, len: u64) -> io::Result<()> {
+        let mut files = self.files.write().unwrap();
+        files.get_mut(name).ok_or_else(|| not_found(name))?.resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self, name: &str) -> io::Result<()> {
+        if self.exists(name) {
+            Ok(())
+        } else {
+            Err(not_found(name))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("khora-storage-{}", rand::random::<u64>()))
+    }
+
+    fn blocks_round_trip(storage: &dyn Storage) {
+        storage.initialize_blocks(BlockKind::Full).unwrap();
+        storage.save_block(BlockKind::Full, &[1, 2, 3]).unwrap();
+        storage.save_block(BlockKind::Full, &[]).unwrap();
+        storage.save_block(BlockKind::Full, &[4, 5]).unwrap();
+        assert_eq!(storage.read_block(BlockKind::Full, &0), Ok(vec![1, 2, 3]));
+        assert!(storage.read_block(BlockKind::Full, &1).is_err());
+        assert_eq!(storage.read_block(BlockKind::Full, &2), Ok(vec![4, 5]));
+        assert!(storage.read_block(BlockKind::Full, &3).is_err());
+        assert!(storage.read_block(BlockKind::Lightning, &0).is_err());
+    }
+
+    fn history_and_tags(storage: &dyn Storage) {
+        storage.initialize_history().unwrap();
+        storage.history_append(&[7u8; 128]).unwrap();
+        assert_eq!(storage.history_len().unwrap(), 2);
+        assert_eq!(storage.history_get(&1).unwrap(), [7u8; 64]);
+        storage.history_append(&[9u8; 64]).unwrap();
+        assert_eq!(storage.history_get_many(&[2, 0, 2]).unwrap(), vec![[9u8; 64], [7u8; 64], [9u8; 64]]);
+        assert!(storage.history_get_many(&[1, 3]).is_err());
+
+        // files that are kept open for reading are reopened after they're replaced
+        storage.write_all(HISTORY, &[5u8; 64]).unwrap();
+        assert_eq!(storage.history_get(&0).unwrap(), [5u8; 64]);
+        assert!(storage.history_get(&1).is_err());
+
+        storage.initialize_tags(128, 6).unwrap();
+        assert_eq!(storage.tag_params().unwrap(), (128, 6));
+        storage.set_tag_byte(3, 0b100).unwrap();
+        storage.set_tag_bytes(&[(5, 1), (15, 2)]).unwrap();
+        assert_eq!(storage.tag_byte(3).unwrap(), 0b100);
+        assert_eq!(storage.tag_byte(4).unwrap(), 0);
+        assert!(storage.tag_byte(16).is_err());
+        let mut bytes = vec![0u8; 16];
+        bytes[3] = 0b100;
+        bytes[5] = 1;
+        bytes[15] = 2;
+        assert_eq!(storage.tag_bytes().unwrap(), bytes);
+        // neighbouring bytes are written as one range and the last value of a repeated byte wins
+        storage.set_tag_bytes(&[(9, 7), (7, 5), (8, 6), (9, 8), (0, 1)]).unwrap();
+        bytes[..10].copy_from_slice(&[1, 0, 0, 0b100, 0, 1, 0, 5, 6, 8]);
+        assert_eq!(storage.tag_bytes().unwrap(), bytes);
+
+        assert!(!storage.has_node());
+        storage.save_node(b"node").unwrap();
+        assert_eq!(storage.load_node().unwrap(), b"node".to_vec());
+        storage.remove_node().unwrap();
+        assert!(!storage.has_node());
+    }
+
+    fn half_written_block(storage: &dyn Storage) -> Vec<Vec<u8>> {
+        storage.initialize_blocks(BlockKind::Lightning).unwrap();
+        storage.initialize_history().unwrap();
+        storage.initialize_tags(128, 6).unwrap();
+        storage.save_block(BlockKind::Lightning, &[1, 2, 3]).unwrap();
+        storage.set_tag_byte(2, 1).unwrap();
+        storage.save_node(&[0]).unwrap();
+        let before = vec![LIGHTNING_BLOCKS, LIGHTNING_BLOCKS_METADATA, HISTORY, BLOOM].into_iter().map(|x| storage.read_all(x).unwrap()).collect::<Vec<_>>();
+
+        storage.begin_block(1).unwrap();
+        storage.save_block(BlockKind::Lightning, &[4, 5]).unwrap();
+        storage.history_append(&[9u8; 64]).unwrap();
+        storage.journal_tag_bytes(&[(2, 1), (7, 0)]).unwrap();
+        storage.set_tag_byte(2, 3).unwrap();
+        storage.set_tag_byte(7, 8).unwrap();
+        storage.journal_tag_bytes(&[(2, 3)]).unwrap(); // the original value of byte 2 is already saved
+        before
+    }
+
+    fn journal_recovery(storage: &dyn Storage) {
+        // crashed before the node was saved
+        let before = half_written_block(storage);
+        assert_eq!(storage.journal().unwrap().unwrap().tag_bytes, vec![(2, 1), (7, 0)]);
+        assert_eq!(storage.recover(1).unwrap(), Recovery::RolledBack);
+        let after = vec![LIGHTNING_BLOCKS, LIGHTNING_BLOCKS_METADATA, HISTORY, BLOOM].into_iter().map(|x| storage.read_all(x).unwrap()).collect::<Vec<_>>();
+        assert_eq!(before, after);
+        assert!(storage.journal().unwrap().is_none());
+        assert!(storage.read_block(BlockKind::Lightning, &1).is_err());
+        assert_eq!(storage.recover(1).unwrap(), Recovery::Clean);
+
+        // crashed after the node was saved
+        half_written_block(storage);
+        assert_eq!(storage.recover(2).unwrap(), Recovery::RolledForward);
+        assert_eq!(storage.read_block(BlockKind::Lightning, &1), Ok(vec![4, 5]));
+        assert_eq!(storage.tag_byte(7).unwrap(), 8);
+
+        // finished normally
+        half_written_block(storage);
+        storage.commit_block(&[1]).unwrap();
+        assert!(storage.journal().unwrap().is_none());
+        assert_eq!(storage.load_node().unwrap(), vec![1]);
+        assert_eq!(storage.recover(2).unwrap(), Recovery::Clean);
+        assert!(storage.journal_tag_bytes(&[(0, 0)]).is_err());
+    }
+
+    fn files(storage: &dyn Storage) -> Vec<Vec<u8>> {
+        vec![LIGHTNING_BLOCKS, LIGHTNING_BLOCKS_METADATA, HISTORY, BLOOM].into_iter().map(|x| storage.read_all(x).unwrap()).collect()
+    }
+
+    fn undo_blocks(storage: &dyn Storage) {
+        let before = half_written_block(storage);
+        storage.commit_block(&[2]).unwrap();
+        let after_1 = files(storage);
+        assert_eq!(storage.undo_heights().unwrap(), vec![1]);
+        assert_eq!(storage.undo_record(1).unwrap().node, vec![0]);
+
+        storage.begin_block(2).unwrap();
+        storage.save_block(BlockKind::Lightning, &[6]).unwrap();
+        storage.journal_tag_bytes(&[(7, 8), (8, 0)]).unwrap();
+        storage.set_tag_byte(7, 9).unwrap();
+        storage.set_tag_byte(8, 1).unwrap();
+        storage.commit_block(&[3]).unwrap();
+        assert_eq!(storage.undo_heights().unwrap(), vec![1, 2]);
+
+        // this one isn't finished
+        storage.begin_block(3).unwrap();
+        storage.save_block(BlockKind::Lightning, &[7]).unwrap();
+        storage.history_append(&[1u8; 64]).unwrap();
+
+        assert!(storage.rollback(0).is_err());
+        assert_eq!(storage.rollback(2).unwrap(), vec![2]);
+        assert_eq!(files(storage), after_1);
+        assert_eq!(storage.load_node().unwrap(), vec![2]);
+        assert_eq!(storage.rollback(1).unwrap(), vec![0]);
+        assert_eq!(files(storage), before);
+        assert!(storage.undo_heights().unwrap().is_empty());
+        assert!(storage.journal().unwrap().is_none());
+
+        // only the last few blocks are remembered
+        for bnum in 1..(UNDO_DEPTH as u64 + 5) {
+            storage.begin_block(bnum).unwrap();
+            storage.save_block(BlockKind::Lightning, &[bnum as u8]).unwrap();
+            storage.commit_block(&[bnum as u8 + 1]).unwrap();
+        }
+        assert_eq!(storage.undo_heights().unwrap(), (5..(UNDO_DEPTH as u64 + 5)).collect::<Vec<_>>());
+        assert!(storage.undo_record(4).is_err());
+        assert_eq!(storage.rollback(5).unwrap(), vec![5]);
+        assert_eq!(storage.read_block(BlockKind::Lightning, &4), Ok(vec![4]));
+        assert!(storage.read_block(BlockKind::Lightning, &5).is_err());
+    }
+
+    fn pruning(storage: &dyn Storage) {
+        storage.initialize_blocks(BlockKind::Full).unwrap();
+        for block in vec![vec![1, 2, 3], vec![], vec![4, 5], vec![6]] {
+            storage.save_block(BlockKind::Full, &block).unwrap();
+        }
+        storage.prune_blocks(BlockKind::Full, 2).unwrap();
+        assert_eq!(storage.pruned_at(BlockKind::Full).unwrap(), (2, 3));
+        assert_eq!(storage.read_block(BlockKind::Full, &0), Err("We pruned that block"));
+        assert_eq!(storage.read_block(BlockKind::Full, &1), Err("We pruned that block"));
+        assert_eq!(storage.read_block(BlockKind::Full, &2), Ok(vec![4, 5]));
+        assert_eq!(storage.read_block(BlockKind::Full, &3), Ok(vec![6]));
+        assert_eq!(storage.len(FULL_BLOCKS).unwrap(), 3);
+        assert_eq!(storage.blocks_len(BlockKind::Full).unwrap(), 6);
+
+        // blocks saved after pruning and blocks undone after pruning
+        storage.save_block(BlockKind::Full, &[7]).unwrap();
+        storage.save_node(&[0]).unwrap();
+        storage.begin_block(5).unwrap();
+        assert!(storage.prune_blocks(BlockKind::Full, 3).is_err());
+        storage.save_block(BlockKind::Full, &[8, 9]).unwrap();
+        assert_eq!(storage.recover(5).unwrap(), Recovery::RolledBack);
+        assert!(storage.read_block(BlockKind::Full, &5).is_err());
+        assert_eq!(storage.read_block(BlockKind::Full, &4), Ok(vec![7]));
+
+        // the node stopped right after deciding to prune
+        storage.write_all(FULL_BLOCKS_PRUNED, &[3u64.to_le_bytes(), 5u64.to_le_bytes()].concat()).unwrap();
+        assert_eq!(storage.recover(5).unwrap(), Recovery::Clean);
+        assert_eq!(storage.read_block(BlockKind::Full, &2), Err("We pruned that block"));
+        assert_eq!(storage.read_block(BlockKind::Full, &3), Ok(vec![6]));
+        assert_eq!(storage.read_block(BlockKind::Full, &4), Ok(vec![7]));
+        assert_eq!(storage.read_all(FULL_BLOCKS).unwrap(), vec![6, 7]);
+
+        storage.initialize_blocks(BlockKind::Full).unwrap();
+        assert_eq!(storage.pruned_at(BlockKind::Full).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn memory_storage() {
+        let storage = MemoryStorage::new();
+        blocks_round_trip(&storage);
+        history_and_tags(&storage);
+        journal_recovery(&storage);
+        undo_blocks(&storage);
+        pruning(&storage);
+    }
+
+    #[test]
+    fn file_storage() {
+        let dir = temp_dir();
+        let storage = FileStorage::new(&dir).unwrap();
+        blocks_round_trip(&storage);
+        history_and_tags(&storage);
+        journal_recovery(&storage);
+        undo_blocks(&storage);
+        pruning(&storage);
+        assert!(dir.join(FULL_BLOCKS).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
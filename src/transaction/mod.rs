@@ -8,7 +8,7 @@ use serde::{Serialize, Deserialize};
 use rand::random;
 
 use crate::account::{OTAccount, Account, Tag, fee_ota};
-use crate::seal::SealSig;
+use crate::seal::{SealSig, SealStatement};
 use crate::ringmaker::*;
 use crate::commitment::Commitment;
 
@@ -22,7 +22,26 @@ use crate::validation::History;
 #[cfg_attr(feature = "std", derive(Error))]
 pub enum TransactionError{
     InvalidTransaction,
-    InvalidOffer
+    InvalidOffer,
+    MemoTooLong,
+    WatchOnly
+}
+
+/// the transcript a transaction is sealed with. the view key ciphertexts of outputs with room for memos and the view
+/// tags of outputs that have one are added to it so nobody can swap their memos or change their tags
+pub fn seal_transcript<'a, I: IntoIterator<Item = &'a OTAccount>>(outputs: I) -> Transcript {
+    let mut tr = Transcript::new(b"seal tx");
+    for (i, x) in outputs.into_iter().enumerate() {
+        if x.has_memo_room() {
+            tr.append_u64(b"memo output", i as u64);
+            tr.append_message(b"memo", &bincode::serialize(x.eck.as_ref().unwrap()).unwrap());
+        }
+        if let Some(view_tag) = x.view_tag {
+            tr.append_u64(b"tagged output", i as u64);
+            tr.append_message(b"view tag", &[view_tag]);
+        }
+    }
+    tr
 }
 
 
@@ -30,6 +49,7 @@ pub enum TransactionError{
 #[derive(Default, Clone, Serialize, Deserialize, Debug)]
 pub struct Transaction{ // this used to have pub(crate) everywhere
     pub inputs: Vec<OTAccount>,
+    #[serde(with = "crate::account::with_view_tags")]
     pub outputs: Vec<OTAccount>,
     pub tags: Vec<Tag>,
     pub seal: SealSig,
@@ -64,7 +84,7 @@ impl Transaction {
         let inputs:Vec<OTAccount> = ring.iter().map(|acct|(acct.clone())).collect();
         let sigin:Vec<&OTAccount> = ring.iter().map(|acct|acct).collect();
         let sigout:Vec<&OTAccount> = outputs.iter().map(|acct|acct).collect();
-        let mut tr = Transcript::new(b"seal tx");
+        let mut tr = seal_transcript(sigout.iter().copied());
 
         let tagelem: Vec<Tag> = poss.iter().map(|pos| ring[*pos].clone()).map(|acct| acct.get_tag().unwrap().clone()).collect();
         let tags: Vec<&Tag> = tagelem.iter().map(|t|t).collect();
@@ -81,16 +101,26 @@ impl Transaction {
     }
     
     pub fn spend_ring(inring: &Vec<OTAccount>, recipients: &Vec<(&Account, &Scalar)>) -> Transaction{
+        Transaction::spend_ring_with_memos(inring, recipients, &[]).unwrap()
+    }
+
+    /// spend_ring with a memo for each recipient (memos[i] is for recipients[i] and missing ones are None). staking outputs can't have memos
+    /// and watch-only accounts can't spend
+    pub fn spend_ring_with_memos(inring: &Vec<OTAccount>, recipients: &Vec<(&Account, &Scalar)>, memos: &[Option<&[u8]>]) -> Result<Transaction, TransactionError>{
         let (poss,inamnt): (Vec<usize>,Vec<Scalar>) = inring.iter().enumerate().filter_map(|(i,a)|if let Some(x) = a.com.amount {Some((i,x))} else {None}).unzip();
 
         // println!("in amount: {:?}",inamnt);
 
         let ring = inring.to_owned();
         let fee_amount = inamnt.into_iter().sum::<Scalar>() - recipients.iter().map(|(_,y)| y.to_owned()).sum::<Scalar>();
-        let mut outputs = recipients.into_iter().map(|(rcpt,amout)|
-            if rcpt.vpk == RISTRETTO_BASEPOINT_POINT {rcpt.derive_stk_ot(amout)}
-            else {rcpt.derive_ot(amout)}
-        ).collect::<Vec<OTAccount>>();
+        let mut outputs = recipients.iter().enumerate().map(|(i,(rcpt,amout))|
+            match (rcpt.vpk == RISTRETTO_BASEPOINT_POINT, memos.get(i).copied().flatten()) {
+                (true, None) => Ok(rcpt.derive_stk_ot(amout)),
+                (true, Some(_)) => Err(TransactionError::InvalidOffer),
+                (false, None) => Ok(rcpt.derive_ot(amout)),
+                (false, Some(memo)) => rcpt.derive_ot_with_memo(amout, memo).map_err(|_| TransactionError::MemoTooLong),
+            }
+        ).collect::<Result<Vec<OTAccount>, TransactionError>>()?;
         outputs.push(fee_ota(&fee_amount));
 
         // println!("fee: {:?}",fee_amount);
@@ -98,33 +128,33 @@ impl Transaction {
         let inputs:Vec<OTAccount> = ring.iter().map(|acct|(acct.clone())).collect();
         let sigin:Vec<&OTAccount> = ring.iter().map(|acct|acct).collect();
         let sigout:Vec<&OTAccount> = outputs.iter().map(|acct|acct).collect();
-        let mut tr = Transcript::new(b"seal tx");
+        let mut tr = seal_transcript(sigout.iter().copied());
 
-        let tagelem: Vec<Tag> = poss.iter().map(|pos| ring[*pos].clone()).map(|acct| acct.get_tag().unwrap().clone()).collect();
+        let tagelem: Vec<Tag> = poss.iter().map(|pos| ring[*pos].get_tag().map_err(|_| TransactionError::WatchOnly)).collect::<Result<_, _>>()?;
         let tags: Vec<&Tag> = tagelem.iter().map(|t|t).collect();
         let seal = SealSig::sign(&mut tr, &sigin, &tags, &poss, &sigout).expect("Not able sign tx");
         outputs.pop();
 
         outputs.iter_mut().for_each(|x| {*x = x.publish_offer();});
-        Transaction{
+        Ok(Transaction{
             inputs ,
             outputs,
             tags: tagelem,
             seal,
             fee: u64::from_le_bytes(fee_amount.as_bytes()[..8].try_into().unwrap()),
-        }
+        })
     }
 
 
     pub fn verify(&self) -> Result<(), TransactionError> {
-        let mut tr = Transcript::new(b"seal tx");
         let inputs: Vec<&OTAccount> = self.inputs.iter().map(|a| a).collect();
         let tags: Vec<&Tag> = self.tags.iter().map(|a| a).collect();
         
         let mut outputs = self.outputs.clone();
         outputs.push(fee_ota(&Scalar::from(self.fee)));
         let outputs: Vec<&OTAccount> = outputs.iter().map(|a|a).collect();
-        
+        let mut tr = seal_transcript(outputs.iter().copied());
+
         let b = self.seal.verify(&mut tr, &inputs, &tags, &outputs);
 
         match b {
@@ -164,6 +194,7 @@ pub fn get_test_ring(n: usize) -> Vec<OTAccount> {
 #[derive(Default, Clone, Eq, Serialize, Deserialize, Hash, Debug)]
 pub struct PolynomialTransaction{
     pub inputs: Vec<u8>,
+    #[serde(with = "crate::account::with_view_tags")]
     pub outputs: Vec<OTAccount>,
     pub tags: Vec<Tag>,
     pub seal: SealSig,
@@ -176,14 +207,14 @@ impl PartialEq for PolynomialTransaction {
 }
 impl PolynomialTransaction {
     pub fn verify_ram(&self,history:&Vec<OTAccount>) -> Result<(), TransactionError> {
-        let mut tr = Transcript::new(b"seal tx");
         let tags: Vec<&Tag> = self.tags.iter().map(|a| a).collect();
         if let Ok(i) = recieve_ring(&self.inputs) {
             let inputs: Vec<&OTAccount> = i.iter().map(|x| &history[*x as usize]).collect();        
             let mut outputs = self.outputs.clone();
             outputs.push(fee_ota(&Scalar::from(self.fee)));
             let outputs: Vec<&OTAccount> = outputs.iter().map(|a|a).collect();
-            
+            let mut tr = seal_transcript(outputs.iter().copied());
+
             let b = self.seal.verify(&mut tr, &inputs, &tags, &outputs);
 
             match b {
@@ -195,15 +226,18 @@ impl PolynomialTransaction {
         }
     }
 
-    pub fn verify(&self) -> Result<(), TransactionError> {
-        let mut tr = Transcript::new(b"seal tx");
+    pub fn verify(&self, history: &History) -> Result<(), TransactionError> {
         let tags: Vec<&Tag> = self.tags.iter().map(|a| a).collect();
         if let Ok(i) = recieve_ring(&self.inputs) {
-            let inputs = i.iter().map(|x| OTAccount::summon_ota(&History::get(x))).collect::<Vec<OTAccount>>();        
+            let inputs = match history.get_accounts(&i) {
+                Ok(x) => x,
+                Err(_) => return Err(TransactionError::InvalidTransaction),
+            };
             let mut outputs = self.outputs.clone();
             outputs.push(fee_ota(&Scalar::from(self.fee)));
             let outputs: Vec<&OTAccount> = outputs.iter().map(|a|a).collect();
-            
+            let mut tr = seal_transcript(outputs.iter().copied());
+
             let b = self.seal.verify(&mut tr, &inputs.iter().collect::<Vec<_>>(), &tags, &outputs);
 
             match b {
@@ -215,8 +249,29 @@ impl PolynomialTransaction {
         }
     }
 
+    /// verifies the transactions together (see SealSig::verify_batch), finding the invalid ones if any are
+    pub fn verify_batch(txs: &[&PolynomialTransaction], history: &History) -> Vec<Result<(), TransactionError>> {
+        let accounts = txs.iter().map(|x| {
+            let inputs = recieve_ring(&x.inputs).ok().and_then(|i| history.get_accounts(&i).ok())?;
+            let mut outputs = x.outputs.clone();
+            outputs.push(fee_ota(&Scalar::from(x.fee)));
+            Some((inputs, outputs))
+        }).collect::<Vec<_>>();
+        let statements = txs.iter().zip(&accounts).filter_map(|(x, a)| a.as_ref().map(|(inputs, outputs)| SealStatement {
+            seal: &x.seal,
+            transcript: seal_transcript(outputs),
+            ring: inputs.iter().collect(),
+            tags: x.tags.iter().collect(),
+            outputs: outputs.iter().collect(),
+        })).collect::<Vec<_>>();
+        let mut verified = SealSig::verify_each(&statements).into_iter();
+        accounts.iter().map(|a| match a {
+            Some(_) if verified.next() == Some(Ok(())) => Ok(()),
+            _ => Err(TransactionError::InvalidTransaction),
+        }).collect()
+    }
+
     pub fn verifystk(&self,history:&Vec<(CompressedRistretto,u64)>) -> Result<(), TransactionError> {
-        let mut tr = Transcript::new(b"seal tx");
         let tags: Vec<&Tag> = self.tags.iter().map(|a| a).collect();
         let mut i = self.inputs.clone();
         if i.pop() == Some(1) {
@@ -231,13 +286,14 @@ impl PolynomialTransaction {
                     None => return Err(TransactionError::InvalidTransaction),
                 };
                 let com = Commitment::commit(&Scalar::from(*amnt),&Scalar::zero());
-                input.push(OTAccount{pk: pk.decompress().unwrap(),com,..Default::default()});
+                input.push(OTAccount::new(pk.decompress().unwrap(), com));
             }
 
             let mut outputs = self.outputs.clone();
             outputs.push(fee_ota(&Scalar::from(self.fee)));
             let outputs: Vec<&OTAccount> = outputs.iter().map(|a|a).collect();
-            
+            let mut tr = seal_transcript(outputs.iter().copied());
+
             let b = self.seal.verify(&mut tr, &input.iter().collect::<Vec<_>>(), &tags, &outputs);
 
             match b {
@@ -257,6 +313,7 @@ mod tests {
     use std::time::Instant;
 
     use super::*;
+    use crate::account::MEMO_SIZE;
 
     #[test]
     fn create_tx() {
@@ -270,6 +327,33 @@ mod tests {
         assert!(tx.verify().is_ok());
     }
 
+    #[test]
+    fn memos() {
+        let acct = Account::new(&"memo sender".to_string());
+        let bob = Account::new(&"memo receiver".to_string());
+        let mut ring = get_test_ring(6);
+        ring[2] = acct.receive_ot(&acct.derive_ot(&Scalar::from(20u64))).unwrap();
+        let (twelve, seven) = (Scalar::from(12u64), Scalar::from(7u64));
+        let recipients = vec![(&bob,&twelve),(&acct,&seven)];
+
+        let tx = Transaction::spend_ring_with_memos(&ring, &recipients, &[Some(&b"invoice 42"[..])]).unwrap();
+        assert!(tx.verify().is_ok());
+        assert!(tx.outputs[0].has_memo_room() && tx.outputs[1].has_memo_room());
+        assert_eq!(tx.outputs[0].eck.as_ref().unwrap().e.len(), tx.outputs[1].eck.as_ref().unwrap().e.len());
+        let got = tx.try_receive(&bob);
+        assert_eq!(got[0].memo, Some(b"invoice 42".to_vec()));
+        assert_eq!(got[0].com.amount, Some(Scalar::from(12u64)));
+        assert_eq!(tx.try_receive(&acct)[0].memo, None);
+
+        // swapping in another memo breaks the seal
+        let other = Transaction::spend_ring_with_memos(&ring, &recipients, &[Some(&b"invoice 43"[..])]).unwrap();
+        let mut swapped = tx.clone();
+        swapped.outputs[0].eck = other.outputs[0].eck.clone();
+        assert!(swapped.verify().is_err());
+
+        assert_eq!(Transaction::spend_ring_with_memos(&ring, &recipients, &[Some(&[0u8; MEMO_SIZE + 1][..])]).err(), Some(TransactionError::MemoTooLong));
+    }
+
     #[test]
     fn time_testing() {
 
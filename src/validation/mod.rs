@@ -6,18 +6,18 @@ use crate::transaction::*;
 use std::convert::TryInto;
 use std::iter::FromIterator;
 use crate::bloom::BloomFile;
+use crate::spent::SpentTags;
 use rand::{thread_rng};
 use sha3::{Digest, Sha3_512};
 use ahash::AHasher;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::Read;
-use std::io::Write;
 use std::hash::Hasher;
+use std::io;
+use std::sync::{Arc, Mutex};
+use lru::LruCache;
 use serde::{Serialize, Deserialize};
 use std::collections::{HashMap, HashSet, VecDeque};
-use crate::constants::PEDERSEN_H;
-use std::io::{Seek, SeekFrom, BufReader};//, BufWriter};
+use crate::constants::PEDERSEN_H_TABLE;
+use crate::storage::{Storage, BlockStore, BlockKind, HistoryStore, JournalStore};
 
 /// the number of validators in the comittee, 128
 pub const NUMBER_OF_VALIDATORS: usize = 3;
@@ -37,6 +37,7 @@ pub const PUNISHMENT_FRACTION: u64 = 1000;
 pub struct Syncedtx{
     pub stkout: Vec<u64>,
     pub stkin: Vec<(CompressedRistretto,u64)>,
+    #[serde(with = "crate::account::with_view_tags")]
     pub txout: Vec<OTAccount>,
     pub tags: Vec<CompressedRistretto>,
     pub fees: u64,
@@ -71,7 +72,17 @@ impl Syncedtx {
 
     /// the message block creaters sign so even lightning blocks can be verified
     pub fn to_sign(txs: &Vec<PolynomialTransaction>)->Vec<u8> {
-        bincode::serialize(&Syncedtx::from(txs)).unwrap()
+        Syncedtx::from(txs).signed_bytes()
+    }
+
+    /// the bytes validators sign. if no output has a view tag they're the bytes from before outputs had view tags so
+    /// older blocks keep their signatures
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        if self.txout.iter().any(|x| x.view_tag.is_some()) {
+            bincode::serialize(self).unwrap()
+        } else {
+            bincode::serialize(&SyncedtxV1::from(self)).unwrap()
+        }
     }
 }
 
@@ -95,7 +106,7 @@ impl ValidatorSignature {
     pub fn sign(key: &Scalar, message: &mut Sha3_512, location: &u8) -> ValidatorSignature {
         let mut csprng = thread_rng();
         let a = Scalar::random(&mut csprng);
-        message.update((a*PEDERSEN_H()).compress().to_bytes());
+        message.update((&a*PEDERSEN_H_TABLE()).compress().to_bytes());
         let c = Scalar::from_hash(message.to_owned());
         ValidatorSignature{c, r: (a - c*key), pk: *location}
     }
@@ -144,7 +155,7 @@ impl Signature {
     pub fn sign(key: &Scalar, message: &mut Sha3_512, location: &u64) -> Signature { // the inputs are the hashed messages you are checking for signatures on because it's faster for many messages.
         let mut csprng = thread_rng();
         let a = Scalar::random(&mut csprng);
-        message.update((a*PEDERSEN_H()).compress().to_bytes());
+        message.update((&a*PEDERSEN_H_TABLE()).compress().to_bytes());
         let c = Scalar::from_hash(message.to_owned());
         Signature{c, r: (a - c*key), pk: *location}
     }
@@ -152,7 +163,7 @@ impl Signature {
     /// verifies a schoore signature on a message given as a Sha3_512 state
     pub fn verify(&self, message: &mut Sha3_512, stkstate: &Vec<(CompressedRistretto,u64)>) -> bool { // the inputs are the hashed messages you are checking for signatures on because it's faster for many messages.
         if self.pk as usize >= stkstate.len() {return false}
-        message.update((self.r*PEDERSEN_H() + self.c*stkstate[self.pk as usize].0.decompress().unwrap()).compress().to_bytes());
+        message.update((&self.r*PEDERSEN_H_TABLE() + self.c*stkstate[self.pk as usize].0.decompress().unwrap()).compress().to_bytes());
         self.c == Scalar::from_hash(message.to_owned())
     }
 
@@ -162,7 +173,7 @@ impl Signature {
         s.update(&message); // impliment non block check stuff for signatures
         let mut csprng = thread_rng();
         let a = Scalar::random(&mut csprng);
-        s.update((a*PEDERSEN_H()).compress().to_bytes());
+        s.update((&a*PEDERSEN_H_TABLE()).compress().to_bytes());
         let c = Scalar::from_hash(s.to_owned());
         let mut out = c.as_bytes().to_vec();
         out.par_extend((a - c*key).as_bytes());
@@ -196,7 +207,7 @@ impl Signature {
         s.update(bnum.to_le_bytes());
         let mut csprng = thread_rng();
         let a = Scalar::random(&mut csprng);
-        s.update((a*PEDERSEN_H()).compress().to_bytes());
+        s.update((&a*PEDERSEN_H_TABLE()).compress().to_bytes());
         let c = Scalar::from_hash(s.to_owned());
         let mut out = c.as_bytes().to_vec();
         out.par_extend((a - c*key).as_bytes());
@@ -248,7 +259,7 @@ impl PartialEq for NextBlock {
 }
 impl NextBlock {
     /// selects the transactions that are valid (as a member of the comittee in block generation)
-    pub fn valicreate(key: &Scalar, location: &u64, leader: &CompressedRistretto, txs: &Vec<PolynomialTransaction>, pool: &u16, bnum: &u64, last_name: &Vec<u8>, bloom: &BloomFile,/* _history: &Vec<OTAccount>,*/ stkstate: &Vec<(CompressedRistretto,u64)>) -> NextBlock {
+    pub fn valicreate(key: &Scalar, location: &u64, leader: &CompressedRistretto, txs: &Vec<PolynomialTransaction>, pool: &u16, bnum: &u64, last_name: &Vec<u8>, bloom: &BloomFile, spent: &SpentTags, history: &History, stkstate: &Vec<(CompressedRistretto,u64)>) -> NextBlock {
         let stks = txs.par_iter().filter_map(|x| 
             if x.inputs.last() == Some(&1) {if x.verifystk(&stkstate).is_ok() {Some(x.to_owned())} else {None}} else {None}
         ).collect::<Vec<PolynomialTransaction>>(); /* i would use drain_filter but its unstable */
@@ -261,22 +272,26 @@ impl NextBlock {
         let txs = txs.into_par_iter().filter_map(|x| 
             if x.inputs.last() == Some(&0) {Some(x.to_owned())} else {None}
         ).collect::<Vec<PolynomialTransaction>>();
+        let tags = txs.iter().flat_map(|x| x.tags.iter().map(|y| y.as_bytes())).collect::<Vec<_>>();
+        // if the spent tags can't be read every transaction counts as spent so a double spend can't get in
+        let spent = spent.spent_batch(bloom, &tags).unwrap_or_else(|_| vec![true; tags.len()]);
+        let starts = txs.iter().scan(0, |s, x| {*s += x.tags.len(); Some(*s - x.tags.len())}).collect::<Vec<_>>();
         
-        let mut txs =
+        let txs =
             txs.par_iter().enumerate().filter_map(|(i,x)| {
                 if 
                 x.tags.par_iter().all(|&x| !txs[..i].iter().flat_map(|x| x.tags.clone()).collect::<HashSet<CompressedRistretto>>().contains(&x))
                 &&
-                x.tags.iter().all(|y| {!bloom.contains(&y.to_bytes())})
+                !spent[starts[i]..starts[i] + x.tags.len()].contains(&true)
                 &&
                 x.tags.len() == x.tags.iter().collect::<HashSet<_>>().len()
-                &&
-                x.verify().is_ok()
                 {
-                    Some(x.to_owned())
+                    Some(x)
                 }
                 else {None}
-        }).collect::<Vec<PolynomialTransaction>>();
+        }).collect::<Vec<&PolynomialTransaction>>();
+        let verified = PolynomialTransaction::verify_batch(&txs, history);
+        let mut txs = txs.into_iter().zip(verified).filter_map(|(x,v)| if v.is_ok() {Some(x.to_owned())} else {None}).collect::<Vec<PolynomialTransaction>>();
         txs.append(&mut stks);
 
 
@@ -295,7 +310,7 @@ impl NextBlock {
 
     /// creates a full block from a collection of signatures in the comittee
     pub fn finish(key: &Scalar, location: &u64, sigs: &Vec<NextBlock>, validator_pool: &Vec<u64>, pool: &u16, bnum: &u64, last_name: &Vec<u8>, stkstate: &Vec<(CompressedRistretto,u64)>) -> Result<NextBlock,&'static str> { // <----do i need to reference previous block explicitly?
-        let leader = (key*PEDERSEN_H()).compress().as_bytes().to_vec();
+        let leader = (key*PEDERSEN_H_TABLE()).compress().as_bytes().to_vec();
         let mut sigs = sigs.into_par_iter().filter(|x| !validator_pool.into_par_iter().all(|y| x.leader.pk != *y)).map(|x| x.to_owned()).collect::<Vec<NextBlock>>();
         let mut sigfinale: Vec<NextBlock>;
         for _ in 0..=(sigs.len() - SIGNING_CUTOFF) {
@@ -387,7 +402,7 @@ impl NextBlock {
             }
         }
         
-        let leader = (key*PEDERSEN_H()).compress().as_bytes().to_vec();
+        let leader = (key*PEDERSEN_H_TABLE()).compress().as_bytes().to_vec();
         let m = vec![leader.clone(),bincode::serialize(&blk.shards).unwrap(),Syncedtx::to_sign(&blk.txs),bnum.to_le_bytes().to_vec(),last_name.clone()].into_par_iter().flatten().collect::<Vec<u8>>();
         let mut s = Sha3_512::new();
         s.update(&m);
@@ -461,54 +476,19 @@ impl NextBlock {
     }
 
     /// reads the specified block from the file
-    pub fn read(bnum: &u64) -> Result<Vec<u8>,&'static str> {
-        let mut r = BufReader::new(File::open("fullblocks_metadata").unwrap());
-        let mut datalocation = [0u8;16];
-        r.seek(SeekFrom::Start(bnum*8)).expect("Seek failed");
-        let bytes_read = r.read(&mut datalocation).expect("should work");
-        if bytes_read == 16 {
-            let loc1 = u64::from_le_bytes(datalocation[..8].try_into().unwrap());
-            let loc2 = u64::from_le_bytes(datalocation[8..].try_into().unwrap());
-            let mut bytes = (loc1..loc2).map(|_| 0u8).collect::<Vec<_>>();
-            let mut r = BufReader::new(File::open("fullblocks").unwrap());
-            r.seek(SeekFrom::Start(loc1)).expect("Seek failed");
-            r.read(&mut bytes).unwrap();
-            if bytes.is_empty() {
-                Err("We skipped that block")
-            } else {
-                Ok(bytes)
-            }
-        } else if bytes_read == 8 {
-            let loc1 = u64::from_le_bytes(datalocation[..8].try_into().unwrap());
-            let mut bytes = vec![];
-            let mut r = BufReader::new(File::open("fullblocks").unwrap());
-            r.seek(SeekFrom::Start(loc1)).expect("Seek failed");
-            r.read_to_end(&mut bytes).unwrap();
-            if bytes.is_empty() {
-                Err("We skipped this block")
-            } else {
-                Ok(bytes)
-            }
-        } else {
-            Err("We don't have that block")
-        }
+    pub fn read(storage: &dyn Storage, bnum: &u64) -> Result<Vec<u8>,&'static str> {
+        storage.read_block(BlockKind::Full, bnum)
     }
 
     /// saves the block to the full block file and adds the metadata to the metadata file
     /// you must save empty blocks to metadata for this to work (save vec![])
-    pub fn save(serialized_block: &Vec<u8>) {
-        let mut f = OpenOptions::new().append(true).open("fullblocks").unwrap();
-        f.write(&serialized_block).expect("should work");
-        let currentlen = f.seek(SeekFrom::End(0)).expect("Seek failed");
-        let mut f = OpenOptions::new().append(true).open("fullblocks_metadata").unwrap();
-        f.write(&currentlen.to_le_bytes()).expect("should work");
+    pub fn save(storage: &dyn Storage, serialized_block: &Vec<u8>) {
+        storage.save_block(BlockKind::Full, serialized_block).expect("should work");
     }
 
     /// created the full block file and metadata to the metadata file
-    pub fn initialize_saving() {
-        File::create("fullblocks").expect("should work");
-        let mut f = File::create("fullblocks_metadata").expect("should work");
-        f.write(&[0u8;8]).expect("should work");
+    pub fn initialize_saving(storage: &dyn Storage) {
+        storage.initialize_blocks(BlockKind::Full).expect("should work");
     }
 
     /// converts a full block into lightning block
@@ -546,7 +526,7 @@ impl LightningSyncBlock {
         if !self.leader.verify(&mut h, &stkstate) {
             return Err("leader is fake")
         }
-        let m = vec![stkstate[self.leader.pk as usize].0.as_bytes().to_vec().clone(), bincode::serialize(&self.shards).unwrap(), bincode::serialize(&self.info).unwrap(), self.bnum.to_le_bytes().to_vec(), self.last_name.clone()].into_par_iter().flatten().collect::<Vec<u8>>();
+        let m = vec![stkstate[self.leader.pk as usize].0.as_bytes().to_vec().clone(), bincode::serialize(&self.shards).unwrap(), self.info.signed_bytes(), self.bnum.to_le_bytes().to_vec(), self.last_name.clone()].into_par_iter().flatten().collect::<Vec<u8>>();
         let mut h = Sha3_512::new();
         h.update(&m);
         if !self.validators.par_iter().all(|x| x.verify(&mut h.clone(), &stkstate)) {
@@ -576,7 +556,7 @@ impl LightningSyncBlock {
         if !self.leader.verify(&mut h, &stkstate) {
             return Err("leader is fake")
         }
-        let m = vec![stkstate[self.leader.pk as usize].0.as_bytes().to_vec().clone(), bincode::serialize(&self.shards).unwrap(), bincode::serialize(&self.info).unwrap(), self.bnum.to_le_bytes().to_vec(), self.last_name.clone()].into_par_iter().flatten().collect::<Vec<u8>>();
+        let m = vec![stkstate[self.leader.pk as usize].0.as_bytes().to_vec().clone(), bincode::serialize(&self.shards).unwrap(), self.info.signed_bytes(), self.bnum.to_le_bytes().to_vec(), self.last_name.clone()].into_par_iter().flatten().collect::<Vec<u8>>();
         let mut h = Sha3_512::new();
         h.update(&m);
         if !self.validators.iter().all(|x| x.verify(&mut h.clone(), &stkstate)) {
@@ -596,8 +576,8 @@ impl LightningSyncBlock {
     }
 
     /// updates the staker state by dulling out punishments and gifting rewards. also updates the queue, exitqueue, and comittee if stakers left
-    pub fn scan_as_noone(&self, valinfo: &mut Vec<(CompressedRistretto,u64)>, queue: &mut Vec<VecDeque<usize>>, exitqueue: &mut Vec<VecDeque<usize>>, comittee: &mut Vec<Vec<usize>>, reward: f64, save_history: bool) {
-        if save_history {History::append(&self.info.txout)};
+    pub fn scan_as_noone(&self, valinfo: &mut Vec<(CompressedRistretto,u64)>, queue: &mut Vec<VecDeque<usize>>, exitqueue: &mut Vec<VecDeque<usize>>, comittee: &mut Vec<Vec<usize>>, reward: f64, save_history: bool, history: &History) {
+        if save_history {history.append(&self.info.txout)};
 
         let winners: Vec<usize>;
         let masochists: Vec<usize>;
@@ -724,15 +704,27 @@ impl LightningSyncBlock {
 
     /// scans the block for money sent to you. additionally updates your understanding of the height
     pub fn scan(&self, me: &Account, mine: &mut HashMap<u64,OTAccount>, height: &mut u64, alltagsever: &mut Vec<CompressedRistretto>) -> bool {
+        self.scan_with(|_, x| me.receive_ot(x).ok(), mine, height, alltagsever)
+    }
+
+    /// scans the block for money sent to any of your subaddresses. indexes says which subaddress received each of your outputs
+    pub fn scan_subaddresses(&self, subs: &Subaddresses, mine: &mut HashMap<u64,OTAccount>, height: &mut u64, alltagsever: &mut Vec<CompressedRistretto>, indexes: &mut HashMap<u64,u32>) -> bool {
+        let imtrue = self.scan_with(|i, x| subs.receive_ot(x).ok().map(|(n, y)| {indexes.insert(i, n); y}), mine, height, alltagsever);
+        indexes.retain(|i, _| mine.contains_key(i));
+        imtrue
+    }
+
+    /// scans the block with receive telling it which outputs (and where they are) are yours
+    fn scan_with<F: FnMut(u64, &OTAccount) -> Option<OTAccount>>(&self, mut receive: F, mine: &mut HashMap<u64,OTAccount>, height: &mut u64, alltagsever: &mut Vec<CompressedRistretto>) -> bool {
         let mut imtrue = true;
-        let newmine = self.info.txout.iter().enumerate().filter_map(|(i,x)| if let Ok(y) = me.receive_ot(x) {imtrue = false; Some((i as u64+*height,y))} else {None}).collect::<Vec<(u64,OTAccount)>>();
-        let newtags = newmine.iter().map(|x|x.1.tag.unwrap()).collect::<Vec<CompressedRistretto>>();
+        let newmine = self.info.txout.iter().enumerate().filter_map(|(i,x)| if let Some(y) = receive(i as u64+*height, x) {imtrue = false; Some((i as u64+*height,y))} else {None}).collect::<Vec<(u64,OTAccount)>>();
+        let newtags = newmine.iter().filter_map(|x|x.1.tag).collect::<Vec<CompressedRistretto>>(); // watch-only accounts don't know their tags
         if !newtags.par_iter().all(|x| alltagsever.par_iter().all(|y|y!=x)) {
             println!("you got burnt (someone sent you faerie gold!)"); // i want this in a seperate function
         }
         alltagsever.extend(&newtags);
 
-        *mine = mine.into_iter().filter_map(|(j,a)| if self.info.tags.par_iter().all(|x| x != &a.tag.unwrap()) {imtrue = false; Some((*j,a.clone()))} else {None} ).collect::<HashMap<u64,OTAccount>>();
+        *mine = mine.into_iter().filter_map(|(j,a)| if a.tag.is_none_or(|t| self.info.tags.par_iter().all(|x| x != &t)) {imtrue = false; Some((*j,a.clone()))} else {None} ).collect::<HashMap<u64,OTAccount>>();
         *height += self.info.txout.len() as u64;
         mine.extend(newmine);
 
@@ -814,62 +806,44 @@ impl LightningSyncBlock {
     }
 
     /// reads the specified block from the file
-    pub fn read(bnum: &u64) -> Result<Vec<u8>,&'static str> {
-        let mut r = BufReader::new(File::open("lightningblocks_metadata").unwrap());
-        let mut datalocation = [0u8;16];
-        r.seek(SeekFrom::Start(bnum*8)).expect("Seek failed");
-        let bytes_read = r.read(&mut datalocation).expect("should work");
-        if bytes_read == 16 {
-            let loc1 = u64::from_le_bytes(datalocation[..8].try_into().unwrap());
-            let loc2 = u64::from_le_bytes(datalocation[8..].try_into().unwrap());
-            let mut bytes = (loc1..loc2).map(|_| 0u8).collect::<Vec<_>>();
-            let mut r = BufReader::new(File::open("lightningblocks").unwrap());
-            r.seek(SeekFrom::Start(loc1)).expect("Seek failed");
-            r.read(&mut bytes).unwrap();
-            if bytes.is_empty() {
-                Err("We skipped that block")
-            } else {
-                Ok(bytes)
-            }
-        } else if bytes_read == 8 {
-            let loc1 = u64::from_le_bytes(datalocation[..8].try_into().unwrap());
-            let mut bytes = vec![];
-            let mut r = BufReader::new(File::open("lightningblocks").unwrap());
-            r.seek(SeekFrom::Start(loc1)).expect("Seek failed");
-            r.read_to_end(&mut bytes).unwrap();
-            if bytes.is_empty() {
-                Err("We skipped this block")
-            } else {
-                Ok(bytes)
-            }
-        } else {
-            Err("We don't have that block")
-        }
+    pub fn read(storage: &dyn Storage, bnum: &u64) -> Result<Vec<u8>,&'static str> {
+        storage.read_block(BlockKind::Lightning, bnum)
     }
 
-    /// saves the block to the full block file and adds the metadata to the metadata file
+    /// saves the block to the lightning block file and adds the metadata to the metadata file
     /// you must save empty blocks to metadata for this to work (save vec![])
-    pub fn save(serialized_block: &Vec<u8>) {
-        let mut f = OpenOptions::new().append(true).open("lightningblocks").unwrap();
-        f.write(&serialized_block).expect("should work");
-        let currentlen = f.seek(SeekFrom::End(0)).expect("Seek failed");
-        let mut f = OpenOptions::new().append(true).open("lightningblocks_metadata").unwrap();
-        f.write(&currentlen.to_le_bytes()).expect("should work");
+    pub fn save(storage: &dyn Storage, serialized_block: &Vec<u8>) {
+        storage.save_block(BlockKind::Lightning, serialized_block).expect("should work");
     }
 
-    /// created the full block file and metadata to the metadata file
-    pub fn initialize_saving() {
-        File::create("lightningblocks").expect("should work");
-        let mut f = File::create("lightningblocks_metadata").expect("should work");
-        f.write(&[0u8;8]).expect("should work");
+    /// created the lightning block file and metadata to the metadata file
+    pub fn initialize_saving(storage: &dyn Storage) {
+        storage.initialize_blocks(BlockKind::Lightning).expect("should work");
     }
+
     /// adds all tags to the bloom filter so validators can check for double spends
-    pub fn update_bloom(&self,bloom:&BloomFile,parallel:&bool) {
-        if *parallel {
-            self.info.tags.par_iter().for_each(|x| bloom.insert(&x.as_bytes()));
+    /// the bytes that change are saved to the journal first so the block can be undone if the node crashes
+    pub fn update_bloom(&self,storage:&dyn Storage,bloom:&BloomFile) {
+        let tags = self.info.tags.iter().map(|x| x.as_bytes()).collect::<Vec<_>>();
+        storage.journal_tag_bytes(&bloom.bytes(&tags)).expect("couldn't write the journal");
+        bloom.insert_batch(&tags);
+    }
+
+    /// saves all tags to the exact index of spent tags (which is journaled like the history)
+    pub fn save_tags(&self, spent: &SpentTags) {
+        spent.insert_batch(&self.info.tags.iter().map(|x| x.as_bytes()).collect::<Vec<_>>()).expect("couldn't save the spent tags");
+    }
+
+    /// the name the next block has to reference as its last_name. like the signatures, it's the hash of the bytes from
+    /// before outputs had view tags if none of its outputs have one
+    pub fn name(&self) -> Vec<u8> {
+        let mut hasher = Sha3_512::new();
+        if self.info.txout.iter().any(|x| x.view_tag.is_some()) {
+            hasher.update(bincode::serialize(self).unwrap());
         } else {
-            self.info.tags.iter().for_each(|x| bloom.insert(&x.as_bytes()));
+            hasher.update(bincode::serialize(&LightningSyncBlockV1::from(self)).unwrap());
         }
+        Scalar::from_hash(hasher).as_bytes().to_vec()
     }
 
 
@@ -929,33 +903,163 @@ pub fn select_stakers(block: &Vec<u8>, bnum: &u64, shard: &u128, queue: &mut Vec
 
 
 
-pub struct History {}
+#[derive(Serialize, Deserialize)]
+/// Syncedtx as it was saved before outputs had view tags
+struct SyncedtxV1 {
+    stkout: Vec<u64>,
+    stkin: Vec<(CompressedRistretto,u64)>,
+    txout: Vec<OTAccount>,
+    tags: Vec<CompressedRistretto>,
+    fees: u64,
+}
+
+impl From<&Syncedtx> for SyncedtxV1 {
+    fn from(x: &Syncedtx) -> SyncedtxV1 {
+        SyncedtxV1 { stkout: x.stkout.clone(), stkin: x.stkin.clone(), txout: x.txout.clone(), tags: x.tags.clone(), fees: x.fees }
+    }
+}
 
-static FILE_NAME: &str = "history";
+impl From<SyncedtxV1> for Syncedtx {
+    fn from(x: SyncedtxV1) -> Syncedtx {
+        Syncedtx { stkout: x.stkout, stkin: x.stkin, txout: x.txout, tags: x.tags, fees: x.fees }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+/// LightningSyncBlock as it was saved before outputs had view tags
+struct LightningSyncBlockV1 {
+    validators: Vec<Signature>,
+    leader: Signature,
+    info: SyncedtxV1,
+    shards: Vec<u16>,
+    bnum: u64,
+    last_name: Vec<u8>,
+}
+
+impl From<&LightningSyncBlock> for LightningSyncBlockV1 {
+    fn from(x: &LightningSyncBlock) -> LightningSyncBlockV1 {
+        LightningSyncBlockV1 { validators: x.validators.clone(), leader: x.leader.clone(), info: SyncedtxV1::from(&x.info), shards: x.shards.clone(), bnum: x.bnum, last_name: x.last_name.clone() }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+/// PolynomialTransaction as it was saved before outputs had view tags
+struct PolynomialTransactionV1 {
+    inputs: Vec<u8>,
+    outputs: Vec<OTAccount>,
+    tags: Vec<Tag>,
+    seal: crate::seal::SealSig,
+    fee: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+/// NextBlock as it was saved before outputs had view tags
+struct NextBlockV1 {
+    validators: Vec<Signature>,
+    leader: Signature,
+    txs: Vec<PolynomialTransactionV1>,
+    last_name: Vec<u8>,
+    shards: Vec<u16>,
+    bnum: u64,
+}
+
+/// rewrites a lightning block saved before outputs had view tags (its outputs don't have one so its name and signatures don't change)
+pub fn upgrade_lightning_block_v1(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let x: LightningSyncBlockV1 = bincode::deserialize(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let block = LightningSyncBlock { validators: x.validators, leader: x.leader, info: x.info.into(), shards: x.shards, bnum: x.bnum, last_name: x.last_name };
+    Ok(bincode::serialize(&block).unwrap())
+}
+
+/// rewrites a full block saved before outputs had view tags
+pub fn upgrade_full_block_v1(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let x: NextBlockV1 = bincode::deserialize(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let txs = x.txs.into_iter().map(|t| PolynomialTransaction { inputs: t.inputs, outputs: t.outputs, tags: t.tags, seal: t.seal, fee: t.fee }).collect();
+    let block = NextBlock { validators: x.validators, leader: x.leader, txs, last_name: x.last_name, shards: x.shards, bnum: x.bnum };
+    Ok(bincode::serialize(&block).unwrap())
+}
+
+/// the number of decompressed OTAccounts the node keeps around for verifying rings
+pub const HISTORY_CACHE: usize = 100_000;
+
+#[derive(Clone)]
+/// this represents the saved public keys and commitments of all the OTAccounts that have appeared on the block chain (it is used to verify transactions and generate rings)
+pub struct History {
+    storage: Arc<dyn Storage>,
+    cache: Option<Arc<Mutex<LruCache<u64, OTAccount>>>>,
+}
 
-/// this represents a file that saves the public keys and commitments of all the OTAccounts that have appeared on the block chain (it is used to verify transactions and generate rings)
 impl History {
+    /// the history kept in storage
+    pub fn new(storage: Arc<dyn Storage>) -> History {
+        History {
+            storage,
+            cache: None,
+        }
+    }
+
+    /// the history kept in storage with the last capacity OTAccounts that were looked up kept in memory
+    /// clones share the cache
+    pub fn with_cache(storage: Arc<dyn Storage>, capacity: usize) -> History {
+        History {
+            storage,
+            cache: Some(Arc::new(Mutex::new(LruCache::new(capacity)))),
+        }
+    }
+
+    /// forgets every cached OTAccount (do this when the history is cut short)
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().clear();
+        }
+    }
+
     /// Create the file
-    pub fn initialize() {
-        File::create(FILE_NAME).unwrap();
+    pub fn initialize(&self) {
+        self.storage.initialize_history().unwrap();
     }
 
     /// Get the information on the OTAccount at height location as compressed Ristrettos
-    pub fn get(location: &u64) -> [CompressedRistretto;2] {
-        let mut byte = [0u8;64];
-        let mut r = BufReader::new(File::open(FILE_NAME).unwrap());
-        r.seek(SeekFrom::Start(location*64)).expect("Seek failed");
-        r.read(&mut byte).unwrap();
+    pub fn get(&self, location: &u64) -> [CompressedRistretto;2] {
+        let byte = self.get_raw(location);
         [CompressedRistretto::from_slice(&byte[..32]),CompressedRistretto::from_slice(&byte[32..])] // OTAccount::summon_ota() from there
     }
 
     /// Get the information on the OTAccount at height location as raw bytes
-    pub fn get_raw(location: &u64) -> [u8; 64] {
-        let mut bytes = [0u8;64];
-        let mut r = BufReader::new(File::open(FILE_NAME).unwrap());
-        r.seek(SeekFrom::Start(location*64)).expect("Seek failed");
-        r.read(&mut bytes).unwrap();
-        bytes
+    pub fn get_raw(&self, location: &u64) -> [u8; 64] {
+        self.storage.history_get(location).unwrap()
+    }
+
+    /// Get the information on the OTAccounts at every location as compressed Ristrettos
+    pub fn get_many(&self, locations: &[u64]) -> io::Result<Vec<[CompressedRistretto;2]>> {
+        Ok(self.storage.history_get_many(locations)?.iter().map(|byte|
+            [CompressedRistretto::from_slice(&byte[..32]),CompressedRistretto::from_slice(&byte[32..])]
+        ).collect())
+    }
+
+    /// Get the OTAccounts at every location, using the cache if there is one
+    pub fn get_accounts(&self, locations: &[u64]) -> io::Result<Vec<OTAccount>> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(self.get_many(locations)?.iter().map(OTAccount::summon_ota).collect()),
+        };
+        let mut accounts = {
+            let mut cache = cache.lock().unwrap();
+            locations.iter().map(|x| cache.get(x).cloned()).collect::<Vec<_>>()
+        };
+        let missing = locations.iter().zip(accounts.iter()).filter(|(_, a)| a.is_none()).map(|(&x, _)| x).collect::<Vec<_>>();
+        if !missing.is_empty() {
+            // decompressing is slow so it's done without holding the lock
+            let mut found = self.get_many(&missing)?.iter().map(OTAccount::summon_ota).collect::<Vec<_>>().into_iter();
+            let mut cache = cache.lock().unwrap();
+            for (location, account) in locations.iter().zip(accounts.iter_mut()) {
+                if account.is_none() {
+                    let a = found.next().unwrap();
+                    cache.put(*location, a.clone());
+                    *account = Some(a);
+                }
+            }
+        }
+        Ok(accounts.into_iter().map(|x| x.unwrap()).collect())
     }
 
     /// Generate a OTAccount from the raw bytes
@@ -964,11 +1068,9 @@ impl History {
     }
 
     /// Appends new OTAccounts to the file
-    pub fn append(accs: &Vec<OTAccount>) {
+    pub fn append(&self, accs: &Vec<OTAccount>) {
         let buf = accs.into_iter().map(|x| [x.pk.compress().as_bytes().to_owned(),x.com.com.compress().as_bytes().to_owned()].to_owned()).flatten().flatten().collect::<Vec<u8>>();
-        let mut f = OpenOptions::new().append(true).open(FILE_NAME).unwrap();
-        f.write_all(&buf.iter().map(|x|*x).collect::<Vec<u8>>()).unwrap();
-
+        self.storage.history_append(&buf).unwrap();
     }
 }
 
@@ -982,7 +1084,7 @@ impl History {
 mod tests {
     use std::time::Instant;
 
-    use crate::constants::PEDERSEN_H;
+    use crate::constants::PEDERSEN_H_TABLE;
 
 
 
@@ -993,7 +1095,7 @@ mod tests {
 
         let message = "hi!!!".as_bytes().to_vec();
         let sk = Scalar::from(rand::random::<u64>());
-        let pk = (sk*PEDERSEN_H()).compress();
+        let pk = (&sk*PEDERSEN_H_TABLE()).compress();
         let stkstate = vec![(pk,9012309183u64)];
         let mut m = Signature::sign_message(&sk,&message,&0u64);
         assert!(0 == Signature::recieve_signed_message(&mut m,&stkstate).unwrap());
@@ -1007,7 +1109,7 @@ mod tests {
 
         let message = "hi!!!".as_bytes().to_vec();
         let sk = Scalar::from(rand::random::<u64>());
-        let pk = (sk*PEDERSEN_H()).compress();
+        let pk = (&sk*PEDERSEN_H_TABLE()).compress();
         let stkstate = vec![(pk,9012309183u64)];
         let mut m = Signature::sign_message_nonced(&sk,&message,&0u64,&80u64);
         assert!(0 == Signature::recieve_signed_message_nonced(&mut m,&stkstate,&80u64).unwrap());
@@ -1027,4 +1129,78 @@ mod tests {
         }
         println!("runtime: {}ms",runtime.elapsed().as_millis());
     }
+
+    #[test]
+    fn history_cache() {
+        use curve25519_dalek::scalar::Scalar;
+        use std::sync::Arc;
+        use crate::account::Account;
+        use crate::storage::{MemoryStorage, Storage, HISTORY};
+        use crate::validation::History;
+
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let history = History::with_cache(storage.clone(), 2);
+        history.initialize();
+        let acc = Account::new(&"history");
+        let otas = (0..3u8).map(|x| acc.derive_ot(&Scalar::from(x))).collect::<Vec<_>>();
+        history.append(&otas);
+
+        let found = history.get_accounts(&[2, 0, 2]).unwrap();
+        assert_eq!(found.iter().map(|x| x.pk).collect::<Vec<_>>(), vec![otas[2].pk, otas[0].pk, otas[2].pk]);
+        assert_eq!(History::new(storage.clone()).get_accounts(&[1]).unwrap()[0].com.com, otas[1].com.com);
+        assert!(history.get_accounts(&[3]).is_err());
+
+        // cached accounts outlive the history being cut short until the cache is cleared
+        storage.truncate(HISTORY, 64).unwrap();
+        assert!(history.clone().get_accounts(&[2]).is_ok());
+        history.clear_cache();
+        assert!(history.get_accounts(&[2]).is_err());
+        assert!(history.get_accounts(&[0]).is_ok());
+    }
+
+    #[test]
+    fn blocks_without_view_tags_upgrade() {
+        use curve25519_dalek::scalar::Scalar;
+        use sha3::{Digest, Sha3_512};
+        use crate::account::Account;
+        use crate::storage::{MemoryStorage, Storage, BlockStore, BlockKind, JournalStore, Journal, LIGHTNING_BLOCKS, LIGHTNING_BLOCKS_METADATA, JOURNAL};
+        use crate::storage::format::{Versioned, format};
+        use crate::validation::{LightningSyncBlock, LightningSyncBlockV1, Syncedtx, SyncedtxV1};
+
+        let acc = Account::new(&"view tags");
+        let old = |bnum: u64| {
+            let mut out = acc.derive_ot(&Scalar::from(bnum));
+            out.view_tag = None;
+            let block = LightningSyncBlockV1::from(&LightningSyncBlock { bnum, info: Syncedtx { txout: vec![out], ..Default::default() }, ..Default::default() });
+            bincode::serialize(&block).unwrap()
+        };
+        let (b0, b2) = (old(0), old(2));
+        let ends = [0, b0.len() as u64, b0.len() as u64, (b0.len() + b2.len()) as u64];
+        let raw = MemoryStorage::new();
+        raw.write_all(LIGHTNING_BLOCKS, &[&format(LIGHTNING_BLOCKS).unwrap().header(1)[..], &b0, &b2].concat()).unwrap();
+        raw.write_all(LIGHTNING_BLOCKS_METADATA, &[format(LIGHTNING_BLOCKS_METADATA).unwrap().header(1).to_vec(), ends.iter().flat_map(|x| x.to_le_bytes()).collect()].concat()).unwrap();
+        let journal = Journal { bnum: 3, lengths: vec![(LIGHTNING_BLOCKS.to_string(), ends[2])], ..Default::default() };
+        raw.write_all(JOURNAL, &bincode::serialize(&journal).unwrap()).unwrap();
+
+        let storage = Versioned::open(raw, &[]).unwrap();
+        let block = storage.read_block(BlockKind::Lightning, &0).unwrap();
+        let read = bincode::deserialize::<LightningSyncBlock>(&block).unwrap();
+        assert!(read.info.txout[0].view_tag.is_none());
+        assert!(acc.receive_ot(&read.info.txout[0]).is_ok());
+        let mut hasher = Sha3_512::new();
+        hasher.update(&b0);
+        assert_eq!(read.name(), Scalar::from_hash(hasher).as_bytes().to_vec());
+        let info = bincode::deserialize::<LightningSyncBlockV1>(&b0).unwrap().info;
+        assert_eq!(read.info.signed_bytes(), bincode::serialize(&info).unwrap());
+        assert_eq!(storage.read_block(BlockKind::Lightning, &1), Err("We skipped that block"));
+        assert_eq!(bincode::deserialize::<LightningSyncBlock>(&storage.read_block(BlockKind::Lightning, &2).unwrap()).unwrap().bnum, 2);
+        assert_eq!(storage.journal().unwrap().unwrap().lengths, vec![(LIGHTNING_BLOCKS.to_string(), block.len() as u64)]);
+
+        // new outputs keep their view tags and they're part of the name
+        let tagged = Syncedtx { txout: vec![acc.derive_ot(&Scalar::one())], ..Default::default() };
+        let read = bincode::deserialize::<Syncedtx>(&bincode::serialize(&tagged).unwrap()).unwrap();
+        assert_eq!(read.txout[0].view_tag, tagged.txout[0].view_tag);
+        assert!(read.txout[0].view_tag.is_some());
+        assert_ne!(read.signed_bytes(), bincode::serialize(&SyncedtxV1::from(&read)).unwrap());
+    }
 }
\ No newline at end of file
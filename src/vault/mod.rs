@@ -0,0 +1,224 @@
+//! encrypts files that hold secret keys (like the saved node) with a key derived from a password
+//!
+//! every vault has its own random key that files are encrypted with. that key is saved in each sealed file encrypted
+//! with a key derived from the password, so changing the password doesn't change how the files themselves are encrypted
+//! and older copies (like the saved nodes kept to undo blocks) still open once the vault is unlocked.
+use serde::{Serialize, Deserialize};
+use std::convert::TryInto;
+use rand::{thread_rng, Rng};
+use sha3::Sha3_256;
+use hmac::Hmac;
+use aes_gcm_siv::Aes256GcmSiv;
+use aes_gcm_siv::aead::{Aead, NewAead};
+use zeroize::Zeroizing;
+use crate::login::DEFAULT_LOGIN_KDF;
+
+/// the first 4 bytes of a sealed file
+pub const VAULT_MAGIC: [u8; 4] = *b"KVLT";
+/// the version of sealed files this writes
+pub const VAULT_VERSION: u32 = 1;
+
+/// how the key that locks the vault's key is derived from the password
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum Kdf {
+    /// PBKDF2 with HMAC-SHA3-256
+    Pbkdf2 { rounds: u32 },
+    /// Argon2id using memory KiB (memory hard so guessing passwords on many computers at once costs a lot)
+    Argon2 { memory: u32, iterations: u32, lanes: u32 },
+}
+
+impl Kdf {
+    /// derives a key from the password (argon2's parameters can be out of range)
+    pub fn derive(&self, password: &[u8], salt: &[u8; 32]) -> Result<Zeroizing<[u8; 32]>, &'static str> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        match self {
+            Kdf::Pbkdf2 { rounds } => pbkdf2::pbkdf2::<Hmac<Sha3_256>>(password, salt, *rounds, &mut *key),
+            Kdf::Argon2 { memory, iterations, lanes } => {
+                let params = argon2::Params::new(*memory, *iterations, *lanes, Some(32)).map_err(|_| "the kdf's parameters are out of range")?;
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(password, salt, &mut *key).map_err(|_| "the kdf's parameters are out of range")?;
+            }
+        }
+        Ok(key)
+    }
+}
+
+/// what's saved after the header of a sealed file
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+struct Sealed {
+    kdf: Kdf,
+    salt: [u8; 32],
+    key_nonce: [u8; 12],
+    locked_key: Vec<u8>,
+    nonce: [u8; 12],
+    e: Vec<u8>,
+}
+
+/// an unlocked vault that can seal and open files. its key is wiped when it's dropped
+#[derive(Clone)]
+pub struct Vault {
+    kdf: Kdf,
+    salt: [u8; 32],
+    key_nonce: [u8; 12],
+    locked_key: Vec<u8>,
+    key: Zeroizing<[u8; 32]>,
+}
+
+/// encrypts bytes with a key
+fn encrypt(key: &[u8; 32], bytes: &[u8]) -> ([u8; 12], Vec<u8>) {
+    let nonce = thread_rng().gen::<[u8; 12]>();
+    let e = Aes256GcmSiv::new(&(*key).into()).encrypt(&nonce.into(), bytes).expect("encryption failure!");
+    (nonce, e)
+}
+
+/// decrypts bytes encrypted with encrypt
+fn decrypt(key: &[u8; 32], nonce: &[u8; 12], e: &[u8]) -> Result<Vec<u8>, &'static str> {
+    Aes256GcmSiv::new(&(*key).into()).decrypt(&(*nonce).into(), e).map_err(|_| "the file can't be decrypted with that key")
+}
+
+/// reads the sealed file after its header
+fn read_sealed(bytes: &[u8]) -> Result<Sealed, &'static str> {
+    if !Vault::is_sealed(bytes) {
+        return Err("the file isn't sealed")
+    }
+    if u32::from_le_bytes(bytes[4..8].try_into().unwrap()) != VAULT_VERSION {
+        return Err("the file was sealed by a different version")
+    }
+    bincode::deserialize(&bytes[8..]).map_err(|_| "the sealed file is corrupted")
+}
+
+impl Vault {
+    /// makes a new vault locked with password (with the kdf logins use)
+    pub fn create(password: &[u8]) -> Vault {
+        Vault::with_kdf(password, DEFAULT_LOGIN_KDF)
+    }
+
+    /// makes a new vault locked with password using kdf (its parameters have to be in range)
+    pub fn with_kdf(password: &[u8], kdf: Kdf) -> Vault {
+        let mut vault = Vault {
+            kdf,
+            salt: [0u8; 32],
+            key_nonce: [0u8; 12],
+            locked_key: vec![],
+            key: Zeroizing::new(thread_rng().gen()),
+        };
+        vault.change_password(password);
+        vault
+    }
+
+    /// tells you if the bytes are a sealed file
+    pub fn is_sealed(bytes: &[u8]) -> bool {
+        bytes.len() >= 8 && bytes[..4] == VAULT_MAGIC
+    }
+
+    /// unlocks the vault the file was sealed with and opens the file
+    pub fn unlock(password: &[u8], sealed: &[u8]) -> Result<(Vault, Vec<u8>), &'static str> {
+        let s = read_sealed(sealed)?;
+        let key = Zeroizing::new(decrypt(&*s.kdf.derive(password, &s.salt)?, &s.key_nonce, &s.locked_key).map_err(|_| "wrong password")?);
+        if key.len() != 32 {
+            return Err("the sealed file is corrupted")
+        }
+        let mut vault = Vault {
+            kdf: s.kdf,
+            salt: s.salt,
+            key_nonce: s.key_nonce,
+            locked_key: s.locked_key,
+            key: Zeroizing::new([0u8; 32]),
+        };
+        vault.key.copy_from_slice(&key);
+        let bytes = vault.open(sealed)?;
+        Ok((vault, bytes))
+    }
+
+    /// tells you if password unlocks the vault the file was sealed with (without opening the file)
+    pub fn check_password(password: &[u8], sealed: &[u8]) -> bool {
+        read_sealed(sealed).and_then(|s| decrypt(&*s.kdf.derive(password, &s.salt)?, &s.key_nonce, &s.locked_key)).map(Zeroizing::new).is_ok()
+    }
+
+    /// encrypts the file
+    pub fn seal(&self, bytes: &[u8]) -> Vec<u8> {
+        let (nonce, e) = encrypt(&self.key, bytes);
+        let s = Sealed {
+            kdf: self.kdf,
+            salt: self.salt,
+            key_nonce: self.key_nonce,
+            locked_key: self.locked_key.clone(),
+            nonce,
+            e,
+        };
+        let mut file = VAULT_MAGIC.to_vec();
+        file.extend(VAULT_VERSION.to_le_bytes());
+        file.extend(bincode::serialize(&s).unwrap());
+        file
+    }
+
+    /// decrypts a file sealed by this vault (with this or any earlier password)
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, &'static str> {
+        let s = read_sealed(sealed)?;
+        decrypt(&self.key, &s.nonce, &s.e)
+    }
+
+    /// locks the vault with a new password. files sealed after this need the new password to unlock the vault
+    pub fn change_password(&mut self, password: &[u8]) {
+        self.salt = thread_rng().gen();
+        let (key_nonce, locked_key) = encrypt(&self.kdf.derive(password, &self.salt).expect("the vault's kdf parameters are out of range"), &*self.key);
+        self.key_nonce = key_nonce;
+        self.locked_key = locked_key;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: Kdf = Kdf::Pbkdf2 { rounds: 10 };
+
+    #[test]
+    fn seal_and_unlock() {
+        let vault = Vault::with_kdf(b"hunter22", FAST);
+        let sealed = vault.seal(b"secret keys");
+        assert!(Vault::is_sealed(&sealed));
+        assert!(!sealed.windows(11).any(|x| x == b"secret keys"));
+        assert_eq!(vault.open(&sealed), Ok(b"secret keys".to_vec()));
+
+        let (unlocked, bytes) = Vault::unlock(b"hunter22", &sealed).unwrap();
+        assert_eq!(bytes, b"secret keys".to_vec());
+        assert_eq!(unlocked.open(&vault.seal(b"more")), Ok(b"more".to_vec()));
+        assert_eq!(Vault::unlock(b"hunter23", &sealed).err(), Some("wrong password"));
+        assert!(Vault::check_password(b"hunter22", &sealed) && !Vault::check_password(b"hunter23", &sealed));
+        assert!(!Vault::check_password(b"", &sealed) && !Vault::check_password(b"hunter22", b"plain"));
+        assert!(Vault::with_kdf(b"hunter22", FAST).open(&sealed).is_err());
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(Vault::unlock(b"hunter22", &tampered).is_err());
+        assert!(Vault::unlock(b"hunter22", b"plain").is_err());
+    }
+
+    #[test]
+    fn change_password() {
+        let mut vault = Vault::with_kdf(b"old password", FAST);
+        let old = vault.seal(b"before");
+        vault.change_password(b"new password");
+        let new = vault.seal(b"after");
+
+        assert!(Vault::unlock(b"old password", &new).is_err());
+        let (unlocked, bytes) = Vault::unlock(b"new password", &new).unwrap();
+        assert_eq!(bytes, b"after".to_vec());
+        // files sealed before the password changed still open
+        assert_eq!(unlocked.open(&old), Ok(b"before".to_vec()));
+        assert!(Vault::unlock(b"new password", &old).is_err());
+    }
+
+    #[test]
+    fn argon2() {
+        let kdf = Kdf::Argon2 { memory: 64, iterations: 1, lanes: 1 };
+        assert_eq!(kdf.derive(b"password", &[1; 32]), kdf.derive(b"password", &[1; 32]));
+        assert!(kdf.derive(b"password", &[1; 32]) != kdf.derive(b"password", &[2; 32]));
+        assert!(Kdf::Argon2 { memory: 1, iterations: 1, lanes: 1 }.derive(b"password", &[1; 32]).is_err());
+
+        let sealed = Vault::with_kdf(b"hunter22", kdf).seal(b"secret keys");
+        assert_eq!(Vault::unlock(b"hunter22", &sealed).unwrap().1, b"secret keys".to_vec());
+        assert!(Vault::unlock(b"hunter23", &sealed).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+//! watch-only wallets that find and total an account's money with only its public, tracking and view keys
+//!
+//! a watch-only wallet can tell which outputs are sent to the account and read their amounts and memos, but it can't
+//! work out their tags (that needs the spend key) so it can't see them being spent on its own. the owner exports a
+//! [`KeyImage`] for each output (its tag with a proof that the tag belongs to the output) and once those are imported
+//! the wallet notices when the outputs are spent. a watch-only account can never build transactions.
+//!
+//! the wallet remembers what the last [`UNDO_DEPTH`] blocks changed so it can [`WatchWallet::rollback`] with the node
+//! when a competing block wins.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::ristretto::CompressedRistretto;
+use merlin::Transcript;
+use serde::{Serialize, Deserialize};
+
+use crate::account::{Account, AccountError, OTAccount, Tag, TagProof};
+use crate::external::transcript::TranscriptProtocol;
+use crate::storage::UNDO_DEPTH;
+use crate::validation::{LightningSyncBlock, Syncedtx};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WatchError{
+    NotOurOutput,
+    InvalidKeyImage,
+    TooFarBack,
+}
+
+/// the tag of an output at location with a proof it's the output's tag (made by the owner)
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct KeyImage {
+    pub location: u64,
+    pub tag: Tag,
+    proof: TagProof,
+}
+
+/// what the key image proof is bound to
+fn key_image_transcript(location: u64, output: &OTAccount, tag: &Tag) -> Transcript {
+    let mut transcript = Transcript::new(b"key image");
+    transcript.append_u64(b"location", location);
+    transcript.append_point(b"pk", &output.pk.compress());
+    transcript.append_point(b"tag", tag);
+    transcript
+}
+
+impl KeyImage {
+    /// exports the key image of an output you received (and can spend) at location
+    pub fn export(location: u64, owned: &OTAccount) -> Result<KeyImage, AccountError> {
+        let sk = owned.get_sk()?;
+        let tag = owned.get_tag()?;
+        let proof = TagProof::prove(&mut key_image_transcript(location, owned, &tag), &sk, &tag.decompress().unwrap());
+        Ok(KeyImage { location, tag, proof })
+    }
+
+    /// checks that the tag belongs to the output
+    pub fn verify(&self, output: &OTAccount) -> bool {
+        match self.tag.decompress() {
+            Some(tag) => self.proof.verify(&mut key_image_transcript(self.location, output, &self.tag), &output.pk, &tag),
+            None => false,
+        }
+    }
+}
+
+/// what scanning a block changed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Scanned {
+    bnum: u64,
+    /// the height before the block (every output received in it is at or after this)
+    height: u64,
+    /// the outputs the block spent
+    spent: Vec<u64>,
+}
+
+/// the outputs of an account found with its watch-only keys
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WatchWallet {
+    account: Account,
+    height: u64,
+    outputs: HashMap<u64, OTAccount>,
+    images: HashMap<CompressedRistretto, u64>,
+    spent: HashSet<u64>,
+    scanned: VecDeque<Scanned>,
+}
+
+impl WatchWallet {
+    /// a wallet for the account with public key pk that starts scanning at the first output
+    pub fn new(pk: &CompressedRistretto, ask: &Scalar, vsk: &Scalar) -> WatchWallet {
+        WatchWallet {
+            account: Account::watch_only(pk, ask, vsk),
+            height: 0,
+            outputs: HashMap::new(),
+            images: HashMap::new(),
+            spent: HashSet::new(),
+            scanned: VecDeque::new(),
+        }
+    }
+
+    /// the watch-only account
+    pub fn account(&self) -> &Account {
+        &self.account
+    }
+
+    /// the location of the next output to be scanned
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// scans the next block for outputs sent to the account and for the imported tags being spent. returns the locations it received
+    pub fn scan(&mut self, block: &LightningSyncBlock) -> Vec<u64> {
+        self.scan_info(block.bnum, &block.info)
+    }
+
+    fn scan_info(&mut self, bnum: u64, info: &Syncedtx) -> Vec<u64> {
+        let received = info.txout.iter().enumerate().filter_map(|(i, x)| {
+            self.account.receive_ot(x).ok().map(|y| (self.height + i as u64, y))
+        }).collect::<Vec<_>>();
+        let spent = info.tags.iter().filter_map(|tag| self.images.get(tag)).filter(|x| !self.spent.contains(x)).copied().collect::<Vec<_>>();
+        self.scanned.push_back(Scanned { bnum, height: self.height, spent: spent.clone() });
+        if self.scanned.len() > UNDO_DEPTH {
+            self.scanned.pop_front();
+        }
+        self.height += info.txout.len() as u64;
+        self.spent.extend(spent);
+        let locations = received.iter().map(|x| x.0).collect();
+        self.outputs.extend(received);
+        locations
+    }
+
+    /// undoes block bnum and every block scanned after it. spends found when importing key images aren't undone
+    pub fn rollback(&mut self, bnum: u64) -> Result<(), WatchError> {
+        if self.scanned.front().is_none_or(|x| x.bnum > bnum) {
+            return Err(WatchError::TooFarBack)
+        }
+        while let Some(block) = self.scanned.pop_back() {
+            if block.bnum < bnum {
+                self.scanned.push_back(block);
+                break
+            }
+            block.spent.iter().for_each(|x| {self.spent.remove(x);});
+            self.height = block.height;
+        }
+        let height = self.height;
+        self.outputs.retain(|&x, _| x < height);
+        self.images.retain(|_, &mut x| x < height);
+        self.spent.retain(|&x| x < height);
+        Ok(())
+    }
+
+    /// imports key images the owner exported. spent says if a tag was spent before it was imported (like SpentTags::contains)
+    /// because the wallet doesn't keep every tag it scans
+    pub fn import<F: Fn(&Tag) -> bool>(&mut self, images: &[KeyImage], spent: F) -> Result<(), WatchError> {
+        for image in images {
+            let output = self.outputs.get(&image.location).ok_or(WatchError::NotOurOutput)?;
+            if !image.verify(output) {
+                return Err(WatchError::InvalidKeyImage)
+            }
+        }
+        for image in images {
+            self.images.insert(image.tag, image.location);
+            if spent(&image.tag) {
+                self.spent.insert(image.location);
+            }
+        }
+        Ok(())
+    }
+
+    /// every output received with its location (including spent ones)
+    pub fn outputs(&self) -> impl Iterator<Item = (&u64, &OTAccount)> {
+        self.outputs.iter()
+    }
+
+    /// tells you if the output at location is known to be spent
+    pub fn is_spent(&self, location: &u64) -> bool {
+        self.spent.contains(location)
+    }
+
+    /// the locations of received outputs without an imported key image (they might have been spent)
+    pub fn missing_images(&self) -> Vec<u64> {
+        let imported = self.images.values().collect::<HashSet<_>>();
+        self.outputs.keys().filter(|x| !imported.contains(x)).copied().collect()
+    }
+
+    /// the total of every output received (None if it doesn't fit in a u64)
+    pub fn received(&self) -> Option<u64> {
+        self.total(|_| true)
+    }
+
+    /// the total of the outputs not known to be spent (None if it doesn't fit in a u64)
+    pub fn balance(&self) -> Option<u64> {
+        self.total(|x| !self.spent.contains(x))
+    }
+
+    fn total<F: Fn(&u64) -> bool>(&self, include: F) -> Option<u64> {
+        self.outputs.iter().filter(|x| include(x.0)).try_fold(0u64, |total, x| {
+            let amount = x.1.com.amount.unwrap();
+            if amount.as_bytes()[8..].iter().any(|&b| b != 0) {
+                return None
+            }
+            total.checked_add(u64::from_le_bytes(amount.as_bytes()[..8].try_into().unwrap()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Transaction, TransactionError};
+
+    #[test]
+    fn watch_only() {
+        let owner = Account::new(&"watched");
+        let mut wallet = WatchWallet::new(&owner.pk.compress(), &owner.ask, &owner.vsk);
+        assert!(wallet.account().is_watch_only() && !owner.is_watch_only());
+
+        let outputs = vec![owner.derive_ot(&Scalar::from(5u64)), Account::new(&"other").derive_ot(&Scalar::from(9u64)), owner.derive_ot_with_memo(&Scalar::from(7u64), b"rent").unwrap()];
+        let published = outputs.iter().map(|x| x.publish_offer()).collect::<Vec<_>>();
+        let tags = outputs.iter().filter_map(|x| owner.receive_ot(x).ok()).map(|x| x.tag.unwrap()).collect::<Vec<_>>();
+        let info = Syncedtx { stkout: vec![], stkin: vec![], txout: published.clone(), tags: vec![], fees: 0 };
+        assert_eq!(wallet.scan_info(0, &info), vec![0, 2]);
+        assert_eq!((wallet.received(), wallet.balance()), (Some(12), Some(12)));
+        assert_eq!(wallet.outputs.get(&2).unwrap().memo, Some(b"rent".to_vec()));
+        assert!(wallet.outputs().all(|x| x.1.tag.is_none()));
+
+        // it can't spend what it sees
+        let ring = published.iter().map(|x| wallet.account().receive_ot(x).unwrap_or_else(|_| x.clone())).collect::<Vec<_>>();
+        assert_eq!(Transaction::spend_ring_with_memos(&ring, &vec![], &[]).err(), Some(TransactionError::WatchOnly));
+
+        // the first output is spent before its key image is imported and the second after
+        let spend = Syncedtx { stkout: vec![], stkin: vec![], txout: vec![], tags: vec![tags[0]], fees: 0 };
+        wallet.scan_info(1, &spend);
+        assert_eq!(wallet.balance(), Some(12));
+        let images = [0, 2].iter().map(|&l| KeyImage::export(l, &owner.receive_ot(&published[l as usize]).unwrap()).unwrap()).collect::<Vec<_>>();
+        let forged = KeyImage { location: 0, ..images[1].clone() };
+        assert_eq!(wallet.import(&[forged], |_| false), Err(WatchError::InvalidKeyImage));
+        assert_eq!(wallet.missing_images().len(), 2);
+        wallet.import(&images, |x| *x == tags[0]).unwrap();
+        assert!(wallet.is_spent(&0) && wallet.missing_images().is_empty());
+        assert_eq!(wallet.balance(), Some(7));
+
+        let spend = Syncedtx { stkout: vec![], stkin: vec![], txout: vec![], tags: vec![tags[1]], fees: 0 };
+        wallet.scan_info(2, &spend);
+        assert_eq!((wallet.received(), wallet.balance()), (Some(12), Some(0)));
+        assert!(KeyImage::export(0, wallet.outputs.get(&0).unwrap()).is_err());
+
+        // a competing block 2 receives instead of spending then block 0 loses too
+        wallet.rollback(2).unwrap();
+        assert_eq!((wallet.height(), wallet.balance()), (3, Some(7)));
+        assert_eq!(wallet.scan_info(2, &Syncedtx { txout: vec![owner.derive_ot(&Scalar::from(1u64))], ..Default::default() }), vec![3]);
+        assert_eq!(wallet.balance(), Some(8));
+        wallet.rollback(0).unwrap();
+        assert_eq!((wallet.height(), wallet.received(), wallet.outputs().count()), (0, Some(0), 0));
+        assert_eq!(wallet.rollback(0), Err(WatchError::TooFarBack));
+
+        // amounts that add up to more than a u64 have no total
+        wallet.scan_info(0, &Syncedtx { txout: vec![owner.derive_ot(&Scalar::from(u64::MAX)), owner.derive_ot(&Scalar::from(1u64))], ..Default::default() });
+        assert_eq!(wallet.balance(), None);
+    }
+}